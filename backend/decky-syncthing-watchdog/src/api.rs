@@ -1,67 +1,193 @@
+use crate::backend::ProbeResult;
 use crate::checks::run_check;
-use crate::service::{get_state, init_service, start_service, stop_service};
-use crate::settings::SettingsProvider;
+use crate::crash_report;
+use crate::credentials::scrub;
+use crate::logging;
+use crate::service::{
+    ServiceError, SyncthingState, get_state, init_service, start_service, stop_service,
+};
+use crate::settings::{
+    Autostart, DEFAULT_INSTANCE, Instance, Mode, SettingsError, SettingsProvider, redact,
+};
+use crate::settings_validation::{ValidationReport, validate_json};
+use crate::shutdown::request_shutdown;
+use crate::supervisor::{SubsystemHealth, SubsystemStatus, health};
+use crate::version::version_info;
+use hyper::body::HttpBody;
+use hyper::header::HeaderValue;
 use hyper::{Body, Method, Request, Response, StatusCode};
-use log::debug;
-use std::collections::HashMap;
+use log::{LevelFilter, debug, info, warn};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::fmt::Display;
 use std::net::IpAddr;
+use std::str::FromStr;
+use tokio::fs::read;
 
-const STATE_ROUTE: &str = "/__decky-watchdog/state";
-const RELOAD_CONFIG_ROUTE: &str = "/__decky-watchdog/reload-config";
-const START_ROUTE: &str = "/__decky-watchdog/start";
-const STOP_ROUTE: &str = "/__decky-watchdog/stop";
-const CHECK_ROUTE: &str = "/__decky-watchdog/check";
+pub const BIND_ADDR: &str = "127.0.0.1:58384";
+
+pub const API_PREFIX: &str = "/__decky-watchdog";
+
+pub const STATE_ROUTE: &str = "/__decky-watchdog/state";
+/// How the Syncthing GUI is reached, see [`crate::backend`]. Probes it if the last result
+/// expired.
+pub const BACKEND_ROUTE: &str = "/__decky-watchdog/state/backend";
+pub const RELOAD_CONFIG_ROUTE: &str = "/__decky-watchdog/reload-config";
+pub const START_ROUTE: &str = "/__decky-watchdog/start";
+pub const STOP_ROUTE: &str = "/__decky-watchdog/stop";
+pub const CHECK_ROUTE: &str = "/__decky-watchdog/check";
+pub const HEALTH_ROUTE: &str = "/__decky-watchdog/health";
+pub const VERSION_ROUTE: &str = "/__decky-watchdog/version";
+pub const SHUTDOWN_ROUTE: &str = "/__decky-watchdog/shutdown";
+pub const LOG_LEVEL_ROUTE: &str = "/__decky-watchdog/log-level";
+pub const LOGS_ROUTE: &str = "/__decky-watchdog/logs";
+pub const CRASHES_ROUTE: &str = "/__decky-watchdog/crashes";
+pub const SETTINGS_ROUTE: &str = "/__decky-watchdog/settings";
+pub const SETTINGS_VALIDATE_ROUTE: &str = "/__decky-watchdog/settings/validate";
+pub const SETTINGS_EFFECTIVE_ROUTE: &str = "/__decky-watchdog/settings/effective";
+/// Lists the instances. `<INSTANCES_ROUTE>/<name><route>` calls one of the [`INSTANCE_ROUTES`]
+/// for the instance `<name>`, the routes without this prefix are for the default instance.
+pub const INSTANCES_ROUTE: &str = "/__decky-watchdog/instances";
+const INSTANCE_ROUTES: &[&str] = &[STATE_ROUTE, START_ROUTE, STOP_ROUTE];
+/// Header of state responses with a problem with the protection of the credentials (see
+/// [`SettingsProvider::warnings`]), once per problem.
+pub const WARNING_HEADER: &str = "x-decky-watchdog-warning";
+
+/// Number of log entries returned by the logs route if not specified.
+const DEFAULT_LOG_LINES: usize = 100;
+/// Max. size of request bodies.
+const MAX_BODY_SIZE: usize = 64 * 1024;
 
 pub async fn handle_api(
     client_ip: &IpAddr,
-    req: &Request<Body>,
+    req: &mut Request<Body>,
     settings: &SettingsProvider,
 ) -> Option<Result<Response<Body>, Infallible>> {
     if !client_ip.is_loopback() {
         return None;
     }
+    let (instance_name, path) = match scoped_route(req.uri().path()) {
+        Some((name, path)) => {
+            if !INSTANCE_ROUTES.iter().any(|route| path.starts_with(route)) {
+                return Some(make_json_error_response(
+                    "Route is not instance specific.",
+                    StatusCode::NOT_FOUND,
+                ));
+            }
+            if settings.settings().await.instance(name).is_none() {
+                return Some(make_json_error_response(
+                    "Unknown instance.",
+                    StatusCode::NOT_FOUND,
+                ));
+            }
+            (name.to_string(), path)
+        }
+        None => (DEFAULT_INSTANCE.to_string(), req.uri().path().to_string()),
+    };
     match *req.method() {
         Method::GET => {
-            if req.uri().path().starts_with(STATE_ROUTE) {
-                match get_state(&*settings.settings().await).await {
-                    Ok(state) => Some(Ok(Response::builder()
-                        .body(Body::from(state.as_static_str()))
-                        .unwrap())),
+            if path.starts_with(BACKEND_ROUTE) {
+                match settings.backend(&instance_name).await {
+                    Ok(result) => Some(make_json_response(&result, StatusCode::OK)),
+                    Err(err) => Some(make_json_error_response(
+                        &err.to_string(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )),
+                }
+            } else if path.starts_with(STATE_ROUTE) {
+                match with_instance(settings, &instance_name, get_state).await {
+                    Ok(state) => Some(make_state_response(state, settings).await),
                     Err(err) => Some(make_error_response(&err)),
                 }
+            } else if path.starts_with(HEALTH_ROUTE) {
+                Some(make_health_response(settings).await)
+            } else if path.starts_with(VERSION_ROUTE) {
+                Some(make_json_response(&version_info(), StatusCode::OK))
+            } else if path.starts_with(LOG_LEVEL_ROUTE) {
+                Some(make_log_level_response())
+            } else if path.starts_with(LOGS_ROUTE) {
+                Some(make_logs_response(req).await)
+            } else if path.starts_with(CRASHES_ROUTE) {
+                Some(make_crashes_response(
+                    path.trim_start_matches(CRASHES_ROUTE),
+                ))
+            } else if path == SETTINGS_ROUTE {
+                Some(make_settings_response(settings).await)
+            } else if path == SETTINGS_EFFECTIVE_ROUTE {
+                match settings.effective().await {
+                    Ok(effective) => Some(make_json_response(&effective, StatusCode::OK)),
+                    Err(err) => Some(make_json_error_response(
+                        &err.to_string(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )),
+                }
+            } else if path == INSTANCES_ROUTE {
+                Some(make_instances_response(settings).await)
             } else {
                 None
             }
         }
         Method::POST => {
-            if req.uri().path().starts_with(RELOAD_CONFIG_ROUTE) {
+            if path.starts_with(RELOAD_CONFIG_ROUTE) {
                 debug!("Reload config request");
                 let response = match settings.reload().await {
                     Ok(()) => {
                         debug!("Reloaded config. Re-init service.");
-                        match init_service(&*settings.settings().await).await {
+                        // Not holding the settings while initializing the service.
+                        let settings = settings.settings().await.clone();
+                        if let Err(err) = logging::apply_settings(&settings) {
+                            warn!("failed to set log level: {err:?}");
+                        }
+                        match init_service(&settings).await {
                             Ok(()) => Some(make_empty_response()),
                             Err(err) => Some(make_error_response(&err)),
                         }
                     }
+                    Err(SettingsError::Invalid(issues)) => Some(make_json_response(
+                        &ValidationReport::from(issues),
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                    )),
                     Err(err) => Some(make_error_response(&err)),
                 };
                 debug!("Reload config done: {:?}", response);
                 response
-            } else if req.uri().path().starts_with(START_ROUTE) {
-                match start_service(&*settings.settings().await).await {
+            } else if path.starts_with(SETTINGS_VALIDATE_ROUTE) {
+                Some(make_validate_response(req, settings).await)
+            } else if path.starts_with(SHUTDOWN_ROUTE) {
+                info!("Shutdown requested via API");
+                request_shutdown();
+                Some(make_empty_response())
+            } else if path.starts_with(LOG_LEVEL_ROUTE) {
+                let level = match query_param(req, "level").map(LevelFilter::from_str) {
+                    Some(Ok(level)) => level,
+                    _ => {
+                        return Some(make_json_error_response(
+                            "Missing or invalid log level.",
+                            StatusCode::BAD_REQUEST,
+                        ));
+                    }
+                };
+                match logging::set_level(level) {
+                    Ok(()) => Some(make_log_level_response()),
+                    Err(err) => Some(make_json_error_response(
+                        &err.to_string(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )),
+                }
+            } else if path.starts_with(START_ROUTE) {
+                match with_instance(settings, &instance_name, start_service).await {
                     Ok(()) => Some(make_empty_response()),
                     Err(err) => Some(make_error_response(&err)),
                 }
-            } else if req.uri().path().starts_with(STOP_ROUTE) {
-                match stop_service(&*settings.settings().await).await {
+            } else if path.starts_with(STOP_ROUTE) {
+                match with_instance(settings, &instance_name, stop_service).await {
                     Ok(()) => Some(make_empty_response()),
                     Err(err) => Some(make_error_response(&err)),
                 }
-            } else if req.uri().path().starts_with(CHECK_ROUTE) {
-                match run_check(settings, req.uri().path().trim_start_matches(CHECK_ROUTE)).await {
+            } else if path.starts_with(CHECK_ROUTE) {
+                match run_check(settings, path.trim_start_matches(CHECK_ROUTE)).await {
                     Ok(Some(res)) => Some(Ok(res)),
                     Ok(None) => Some(make_json_error_response(
                         "Unknown check.",
@@ -76,10 +202,235 @@ pub async fn handle_api(
                 None
             }
         }
+        Method::PATCH => {
+            if path == SETTINGS_ROUTE {
+                Some(make_settings_update_response(req, settings).await)
+            } else {
+                None
+            }
+        }
         _ => None,
     }
 }
 
+async fn make_state_response(
+    state: SyncthingState,
+    settings: &SettingsProvider,
+) -> Result<Response<Body>, Infallible> {
+    let mut response = Response::builder();
+    for warning in settings.warnings().await {
+        if let Ok(value) = HeaderValue::from_str(&warning) {
+            response = response.header(WARNING_HEADER, value);
+        }
+    }
+    Ok(response.body(Body::from(state.as_static_str())).unwrap())
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    healthy: bool,
+    subsystems: BTreeMap<&'static str, SubsystemHealth>,
+    /// Problems with the protection of the credentials.
+    warnings: Vec<String>,
+}
+
+async fn make_health_response(settings: &SettingsProvider) -> Result<Response<Body>, Infallible> {
+    let subsystems = health().await;
+    let healthy = subsystems
+        .values()
+        .all(|h| h.status == SubsystemStatus::Running);
+    let status = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    make_json_response(
+        &HealthResponse {
+            healthy,
+            subsystems,
+            warnings: settings.warnings().await,
+        },
+        status,
+    )
+}
+
+/// Splits an instance scoped path (`<INSTANCES_ROUTE>/<name>/<route>`) into the name of the
+/// instance and the path of the route without the prefix.
+fn scoped_route(path: &str) -> Option<(&str, String)> {
+    let rest = path.strip_prefix(INSTANCES_ROUTE)?.strip_prefix('/')?;
+    let (name, route) = rest.split_once('/')?;
+    Some((name, format!("{API_PREFIX}/{route}")))
+}
+
+/// Calls `f` with the instance `name`. The instance must exist.
+async fn with_instance<T, F>(
+    settings: &SettingsProvider,
+    name: &str,
+    f: F,
+) -> Result<T, ServiceError>
+where
+    F: AsyncFnOnce(&Instance<'_>) -> Result<T, ServiceError>,
+{
+    let settings = settings.settings().await;
+    let instance = settings
+        .instance(name)
+        .ok_or_else(|| SettingsError::UnknownInstance(name.to_string()))?;
+    f(&instance).await
+}
+
+#[derive(Debug, Serialize)]
+struct InstanceInfo<'a> {
+    name: &'a str,
+    mode: Mode,
+    port: u32,
+    autostart: Autostart,
+    /// `None` if the state can not be determined.
+    state: Option<&'static str>,
+    /// Last probe of the Syncthing GUI, `None` if not probed yet.
+    backend: Option<ProbeResult>,
+}
+
+async fn make_instances_response(
+    settings: &SettingsProvider,
+) -> Result<Response<Body>, Infallible> {
+    let current = settings.settings().await;
+    let mut instances = Vec::new();
+    for instance in current.instances() {
+        instances.push(InstanceInfo {
+            name: instance.name,
+            mode: instance.mode,
+            port: instance.port,
+            autostart: instance.autostart,
+            state: get_state(&instance)
+                .await
+                .ok()
+                .map(SyncthingState::as_static_str),
+            backend: settings.last_backend(instance.name).await,
+        });
+    }
+    make_json_response(&instances, StatusCode::OK)
+}
+
+/// Returns the value of the query parameter `name`, if set.
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+fn make_log_level_response() -> Result<Response<Body>, Infallible> {
+    let mut resp = HashMap::with_capacity(1);
+    resp.insert("level", logging::level().as_str().to_ascii_lowercase());
+    make_json_response(&resp, StatusCode::OK)
+}
+
+async fn make_logs_response(req: &Request<Body>) -> Result<Response<Body>, Infallible> {
+    let lines = match query_param(req, "lines").map(usize::from_str) {
+        None => DEFAULT_LOG_LINES,
+        Some(Ok(lines)) => lines,
+        Some(Err(_)) => {
+            return make_json_error_response("Invalid number of lines.", StatusCode::BAD_REQUEST);
+        }
+    };
+    let level = match query_param(req, "level").map(LevelFilter::from_str) {
+        None => LevelFilter::Trace,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return make_json_error_response("Invalid log level.", StatusCode::BAD_REQUEST);
+        }
+    };
+    match logging::tail(lines, level).await {
+        Ok(entries) => make_json_response(&entries, StatusCode::OK),
+        Err(err) => make_json_error_response(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Reads the request body, up to [`MAX_BODY_SIZE`].
+async fn read_body(req: &mut Request<Body>) -> Result<Vec<u8>, String> {
+    let body = req.body_mut();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        if content.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err("Request body too large.".to_string());
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+/// Validates the settings JSON in the request body, or the settings file if the body is empty.
+async fn make_validate_response(
+    req: &mut Request<Body>,
+    settings: &SettingsProvider,
+) -> Result<Response<Body>, Infallible> {
+    let content = match read_body(req).await {
+        Ok(content) if content.is_empty() => match read(settings.path()).await {
+            Ok(content) => content,
+            Err(err) => return make_error_response(&err),
+        },
+        Ok(content) => content,
+        Err(err) => return make_json_error_response(&err, StatusCode::BAD_REQUEST),
+    };
+    make_json_response(
+        &ValidationReport::from(validate_json(&content)),
+        StatusCode::OK,
+    )
+}
+
+/// Returns the current settings, without credentials.
+async fn make_settings_response(settings: &SettingsProvider) -> Result<Response<Body>, Infallible> {
+    match serde_json::to_value(&*settings.settings().await) {
+        Ok(mut value) => {
+            redact(&mut value);
+            make_json_response(&value, StatusCode::OK)
+        }
+        Err(err) => make_json_error_response(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Merges the JSON object in the request body into the settings, saves and applies them.
+async fn make_settings_update_response(
+    req: &mut Request<Body>,
+    settings: &SettingsProvider,
+) -> Result<Response<Body>, Infallible> {
+    let patch = match read_body(req).await.and_then(|content| {
+        serde_json::from_slice::<Map<String, Value>>(&content).map_err(|e| e.to_string())
+    }) {
+        Ok(patch) => patch,
+        Err(err) => return make_json_error_response(&err, StatusCode::BAD_REQUEST),
+    };
+    debug!(keys:? = patch.keys().collect::<Vec<_>>(); "Settings update request");
+    match settings.update(patch).await {
+        Ok(()) => {
+            if let Err(err) = logging::apply_settings(&*settings.settings().await) {
+                warn!("failed to set log level: {err:?}");
+            }
+            make_settings_response(settings).await
+        }
+        Err(SettingsError::Invalid(issues)) => make_json_response(
+            &ValidationReport::from(issues),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        Err(err) => make_json_error_response(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Lists the crash reports, or returns the one with the ID in `path` (`/<id>`).
+fn make_crashes_response(path: &str) -> Result<Response<Body>, Infallible> {
+    let result = match path.trim_start_matches('/') {
+        "" => crash_report::list().map(|list| make_json_response(&list, StatusCode::OK)),
+        id => crash_report::get(id).map(|report| match report {
+            Some(report) => make_json_response(&report, StatusCode::OK),
+            None => make_json_error_response("Unknown crash report.", StatusCode::NOT_FOUND),
+        }),
+    };
+    result.unwrap_or_else(|err| {
+        make_json_error_response(&err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
 pub fn make_empty_response() -> Result<Response<Body>, Infallible> {
     Ok(Response::builder()
         .status(StatusCode::NO_CONTENT)
@@ -91,7 +442,8 @@ pub fn make_error_response(err: &dyn Display) -> Result<Response<Body>, Infallib
     Ok(Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
         .body(Body::from(format!(
-            "<html><body><h1>Internal Server Error</h1><p><pre>{err}</pre></p></body></html>"
+            "<html><body><h1>Internal Server Error</h1><p><pre>{}</pre></p></body></html>",
+            scrub(&err.to_string())
         )))
         .unwrap())
 }
@@ -100,10 +452,22 @@ pub fn make_json_error_response(
     err: &str,
     status: StatusCode,
 ) -> Result<Response<Body>, Infallible> {
+    let err = scrub(err);
     let mut resp = HashMap::with_capacity(1);
-    resp.insert("error", err);
+    resp.insert("error", &*err);
     Ok(Response::builder()
         .status(status)
         .body(Body::from(serde_json::to_string(&resp).unwrap()))
         .unwrap())
 }
+
+pub fn make_json_response(
+    content: &impl Serialize,
+    status: StatusCode,
+) -> Result<Response<Body>, Infallible> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(content).unwrap()))
+        .unwrap())
+}
@@ -0,0 +1,153 @@
+//! Token authenticating clients of the control API (`/__decky-watchdog/...`) on the HTTP
+//! server, so that other local processes and web pages can not control Syncthing.
+//! A new random token is generated on every start and written to a file in the runtime
+//! directory (next to the PID file) that only the user can read. Clients send it in the
+//! [`TOKEN_HEADER`] header or as bearer token. The control socket does not need it, only the
+//! user can connect to it.
+
+use crate::credentials::PRIVATE_MODE;
+use hyper::HeaderMap;
+use hyper::header::AUTHORIZATION;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fs::{OpenOptions, read_to_string, remove_file, rename};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const TOKEN_FILE_NAME: &str = "watchdog.token";
+pub const TOKEN_HEADER: &str = "x-decky-watchdog-token";
+/// Random bytes per token.
+const TOKEN_BYTES: usize = 32;
+
+pub struct AuthToken(String);
+
+impl AuthToken {
+    pub fn generate() -> io::Result<Self> {
+        let mut bytes = [0; TOKEN_BYTES];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| io::Error::other("no random numbers available"))?;
+        Ok(Self(bytes.iter().map(|b| format!("{b:02x}")).collect()))
+    }
+
+    pub fn file_path(runtime_dir: &Path) -> PathBuf {
+        runtime_dir.join(TOKEN_FILE_NAME)
+    }
+
+    pub fn read(path: &Path) -> io::Result<String> {
+        Ok(read_to_string(path)?.trim().to_string())
+    }
+
+    /// Writes the token via a temporary file, which is only accessible by the user from the
+    /// start.
+    pub fn write(&self, runtime_dir: &Path) -> io::Result<()> {
+        let path = Self::file_path(runtime_dir);
+        let tmp = path.with_extension("token.tmp");
+        remove_file(&tmp).ok();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(PRIVATE_MODE)
+            .open(&tmp)?;
+        file.write_all(self.0.as_bytes())?;
+        rename(&tmp, &path)
+    }
+
+    pub fn remove(runtime_dir: &Path) -> io::Result<()> {
+        remove_file(Self::file_path(runtime_dir))
+    }
+
+    /// Whether the request carries the token, in [`TOKEN_HEADER`] or as bearer token.
+    pub fn verify(&self, headers: &HeaderMap) -> bool {
+        let bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token);
+        headers
+            .get(TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .or(bearer)
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.0.as_bytes()))
+    }
+}
+
+/// Compares in a time that only depends on the length, so the token can not be guessed
+/// byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::test_support::TempDir;
+    use hyper::header::HeaderValue;
+    use std::fs::metadata;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn generates_random_tokens() {
+        let token = AuthToken::generate().unwrap();
+        assert_eq!(token.0.len(), 2 * TOKEN_BYTES);
+        assert!(token.0.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(token.0, AuthToken::generate().unwrap().0);
+    }
+
+    #[test]
+    fn accepts_token_in_header() {
+        let token = AuthToken::generate().unwrap();
+        assert!(token.verify(&headers(TOKEN_HEADER, &token.0)));
+    }
+
+    #[test]
+    fn accepts_bearer_token() {
+        let token = AuthToken::generate().unwrap();
+        let authorization = AUTHORIZATION.as_str();
+        assert!(token.verify(&headers(authorization, &format!("Bearer {}", token.0))));
+        assert!(token.verify(&headers(authorization, &format!("bearer {}", token.0))));
+        assert!(!token.verify(&headers(authorization, &format!("Basic {}", token.0))));
+        assert!(!token.verify(&headers(authorization, &token.0)));
+    }
+
+    #[test]
+    fn rejects_wrong_tokens() {
+        let token = AuthToken::generate().unwrap();
+        assert!(!token.verify(&HeaderMap::new()));
+        let other = AuthToken::generate().unwrap();
+        assert!(!token.verify(&headers(TOKEN_HEADER, &other.0)));
+        // Wrong length.
+        assert!(!token.verify(&headers(TOKEN_HEADER, &token.0[1..])));
+        assert!(!token.verify(&headers(TOKEN_HEADER, &format!("{}0", token.0))));
+        assert!(!token.verify(&headers(TOKEN_HEADER, "")));
+        // The header is checked first, a bearer token does not help with a wrong one.
+        let mut both = headers(TOKEN_HEADER, &other.0);
+        both.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token.0)).unwrap(),
+        );
+        assert!(!token.verify(&both));
+    }
+
+    #[test]
+    fn writes_private_token_file() {
+        let dir = TempDir::new("token");
+        let token = AuthToken::generate().unwrap();
+        token.write(dir.path()).unwrap();
+        let path = AuthToken::file_path(dir.path());
+        assert_eq!(AuthToken::read(&path).unwrap(), token.0);
+        let mode = metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, PRIVATE_MODE);
+
+        AuthToken::remove(dir.path()).unwrap();
+        assert!(!path.exists());
+    }
+}
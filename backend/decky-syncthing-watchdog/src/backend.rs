@@ -0,0 +1,297 @@
+//! Detection of how the Syncthing GUI of an instance is reached (HTTPS or HTTP).
+//! The scheme is taken from the `tls` attribute of the GUI in Syncthing's `config.xml` if that
+//! can be found, otherwise from the `tls` setting, otherwise HTTPS and then HTTP are probed.
+//! The host is taken from the settings, or from `config.xml` if not set there.
+//! Results are cached per instance and checked again periodically, after proxy errors (see
+//! [`BackendCache::invalidate`]) and, with a backoff, while Syncthing can not be reached.
+
+use crate::gui_address::{GuiAddress, connect_host};
+use crate::settings::{Instance, Mode};
+use crate::syncthing_config::SyncthingConfig;
+use crate::tls::{TlsVerification, make_https_client};
+use hyper::http::uri::Scheme;
+use hyper::{Body, Method, Request, Uri};
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio::time::timeout;
+
+/// How long a successful probe is used before probing again.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// Wait after the first failed probe, doubled with every further failure up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(16);
+/// Max. time a probe request may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendScheme {
+    Https,
+    Http,
+}
+
+impl BackendScheme {
+    /// HTTPS unless `uri` is for HTTP.
+    fn of(uri: &Uri) -> Self {
+        match uri.scheme() == Some(&Scheme::HTTP) {
+            true => Self::Http,
+            false => Self::Https,
+        }
+    }
+}
+
+impl From<BackendScheme> for Scheme {
+    fn from(scheme: BackendScheme) -> Self {
+        match scheme {
+            BackendScheme::Https => Scheme::HTTPS,
+            BackendScheme::Http => Scheme::HTTP,
+        }
+    }
+}
+
+/// Where the scheme comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemeSource {
+    /// The `tls` attribute in Syncthing's `config.xml`.
+    Config,
+    /// The `tls` setting.
+    Settings,
+    /// Trying HTTPS and HTTP.
+    Probe,
+    /// The `remote_url` setting of a remote instance.
+    Remote,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    /// `None` if Syncthing could not be reached.
+    pub scheme: Option<BackendScheme>,
+    /// URI of the Syncthing GUI, `None` if Syncthing could not be reached.
+    pub uri: Option<String>,
+    pub source: SchemeSource,
+    /// Unix timestamp of the probe.
+    pub probed_at: u64,
+    /// Why Syncthing could not be reached.
+    pub error: Option<String>,
+    /// Number of failed probes in a row.
+    pub failures: u32,
+    /// How the certificate of the GUI is verified, if it uses HTTPS.
+    pub verification: TlsVerification,
+    /// When to probe again.
+    #[serde(skip)]
+    expires: Instant,
+}
+
+/// What to probe, taken from the settings of an instance.
+pub struct ProbeTarget {
+    name: String,
+    /// URIs to try, in this order.
+    candidates: Vec<(BackendScheme, Uri)>,
+    verification: TlsVerification,
+    source: SchemeSource,
+}
+
+impl ProbeTarget {
+    /// Reads Syncthing's configuration of the instance. It is ignored if it is for another port,
+    /// then it probably belongs to another Syncthing. Remote instances are only tried at their
+    /// `remote_url`.
+    pub fn new(instance: &Instance<'_>) -> Self {
+        if instance.mode == Mode::Remote {
+            let uri = instance.remote_uri();
+            let host = uri.as_ref().and_then(Uri::host).unwrap_or_default();
+            return Self {
+                name: instance.name.to_string(),
+                verification: TlsVerification::for_host(host, instance.tls_pin()),
+                candidates: uri
+                    .map(|uri| (BackendScheme::of(&uri), uri))
+                    .into_iter()
+                    .collect(),
+                source: SchemeSource::Remote,
+            };
+        }
+        let mut address = instance.gui_address();
+        let mut source = match address.tls {
+            Some(_) => SchemeSource::Settings,
+            None => SchemeSource::Probe,
+        };
+        if let Some(config) = SyncthingConfig::find(instance, None)
+            .and_then(|config| config.gui_address())
+            .filter(|config| config.port == instance.port)
+        {
+            if instance.host.is_none() {
+                address.host = config.host;
+            }
+            if config.tls.is_some() {
+                address.tls = config.tls;
+                source = SchemeSource::Config;
+            }
+        }
+        Self {
+            name: instance.name.to_string(),
+            candidates: candidates(&address),
+            verification: TlsVerification::for_host(
+                &connect_host(&address.host),
+                instance.tls_pin(),
+            ),
+            source,
+        }
+    }
+}
+
+/// Probe results per instance name.
+#[derive(Default)]
+pub struct BackendCache(RwLock<HashMap<String, ProbeResult>>);
+
+impl BackendCache {
+    /// Returns the result for the instance `name` if it does not need to be probed again.
+    pub async fn fresh(&self, name: &str) -> Option<ProbeResult> {
+        self.0
+            .read()
+            .await
+            .get(name)
+            .filter(|result| result.expires > Instant::now())
+            .cloned()
+    }
+
+    /// Returns the last result for the instance `name`, even if it expired.
+    pub async fn last(&self, name: &str) -> Option<ProbeResult> {
+        self.0.read().await.get(name).cloned()
+    }
+
+    /// Probes the instance, unless that was just done by someone else.
+    pub async fn probe(&self, target: ProbeTarget) -> ProbeResult {
+        let mut results = self.0.write().await;
+        let previous = results.get(&target.name);
+        if let Some(previous) = previous.filter(|result| result.expires > Instant::now()) {
+            return previous.clone();
+        }
+        let failures = previous.map_or(0, |result| result.failures);
+        let previous_uri = previous.and_then(|result| result.uri.clone());
+        let result = probe(&target, failures).await;
+        match &result.uri {
+            Some(uri) if result.uri != previous_uri => {
+                info!(
+                    "Syncthing GUI of {} is at {uri} (from {:?}).",
+                    target.name, result.source
+                );
+            }
+            Some(_) => {}
+            None => debug!(
+                "Syncthing GUI of {} not reachable ({} failures): {}",
+                target.name,
+                result.failures,
+                result.error.as_deref().unwrap_or_default()
+            ),
+        }
+        results.insert(target.name, result.clone());
+        result
+    }
+
+    /// Makes the next request for the instance `name` probe again, e.g. after the proxy failed
+    /// to connect. Does not shorten the backoff after failed probes.
+    pub async fn invalidate(&self, name: &str) {
+        if let Some(result) = self.0.write().await.get_mut(name)
+            && result.uri.is_some()
+        {
+            result.expires = Instant::now();
+        }
+    }
+
+    pub async fn clear(&self) {
+        self.0.write().await.clear();
+    }
+}
+
+/// URIs (with path `/`) of the GUI at `address`: HTTPS and then HTTP if `address.tls` is not
+/// known.
+fn candidates(address: &GuiAddress) -> Vec<(BackendScheme, Uri)> {
+    let schemes: &[BackendScheme] = match address.tls {
+        Some(true) => &[BackendScheme::Https],
+        Some(false) => &[BackendScheme::Http],
+        None => &[BackendScheme::Https, BackendScheme::Http],
+    };
+    schemes
+        .iter()
+        .filter_map(|scheme| {
+            let uri = Uri::builder()
+                .scheme(Scheme::from(*scheme))
+                .authority(address.authority())
+                .path_and_query("/")
+                .build()
+                .ok()?;
+            Some((*scheme, uri))
+        })
+        .collect()
+}
+
+/// Returns the scheme and URI (with path `/`) the GUI at `address` answers on, see
+/// [`candidates`].
+pub async fn probe_address(
+    address: &GuiAddress,
+    verification: TlsVerification,
+) -> Result<(BackendScheme, Uri), String> {
+    probe_candidates(&candidates(address), verification).await
+}
+
+/// Returns the first of the `candidates` that answers.
+async fn probe_candidates(
+    candidates: &[(BackendScheme, Uri)],
+    verification: TlsVerification,
+) -> Result<(BackendScheme, Uri), String> {
+    let client = make_https_client::<Body>(verification);
+    let mut error = "no valid address".to_string();
+    for (scheme, uri) in candidates {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(uri.clone())
+            .body(Body::empty())
+            .unwrap();
+        match timeout(PROBE_TIMEOUT, client.request(req)).await {
+            Ok(Ok(_)) => return Ok((*scheme, uri.clone())),
+            Ok(Err(err)) => error = err.to_string(),
+            Err(_) => error = "timed out".to_string(),
+        }
+    }
+    Err(error)
+}
+
+async fn probe(target: &ProbeTarget, failures: u32) -> ProbeResult {
+    let source = target.source;
+    let probed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let verification = target.verification;
+    let error = match probe_candidates(&target.candidates, verification).await {
+        Ok((scheme, uri)) => {
+            return ProbeResult {
+                scheme: Some(scheme),
+                uri: Some(uri.to_string()),
+                source,
+                probed_at,
+                error: None,
+                failures: 0,
+                verification,
+                expires: Instant::now() + REPROBE_INTERVAL,
+            };
+        }
+        Err(error) => error,
+    };
+    let failures = failures.saturating_add(1);
+    let backoff = MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures - 1))
+        .min(MAX_BACKOFF);
+    ProbeResult {
+        scheme: None,
+        uri: None,
+        source,
+        probed_at,
+        error: Some(error),
+        failures,
+        verification,
+        expires: Instant::now() + backoff,
+    }
+}
@@ -1,19 +1,17 @@
-use crate::proxy::handle_proxy;
+use crate::backend::{BackendScheme, probe_address};
+use crate::gui_address::{DEFAULT_HOST, GuiAddress, connect_host};
 use crate::service::{SyncthingState, get_state, init_service, start_service, stop_service};
-use crate::settings::{IsSetup, Mode, Settings, SettingsProvider};
-use crate::util::make_unsafe_https_client;
+use crate::settings::{DEFAULT_INSTANCE, IsSetup, Mode, Settings, SettingsProvider};
+use crate::syncthing_config::SyncthingConfig;
+use crate::tls::{TlsVerification, make_https_client};
 use anyhow::anyhow;
-use homedir::my_home;
-use hyper::http::uri::Scheme;
-use hyper::{Body, Method, Request, Response, StatusCode, Uri, body};
+use base64::Engine;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response, StatusCode, body};
 use log::{debug, warn};
 use serde::Serialize;
-use std::env;
-use std::fs::read_to_string;
-use std::net::Ipv4Addr;
-use std::path::{Path, PathBuf};
+use serde_json::{Map, Value};
 use std::time::Duration;
-use sxd_xpath::evaluate_xpath;
 use tokio::time::sleep;
 
 const TESTING_AUTO_FAIL_SCANS: bool = false;
@@ -65,8 +63,11 @@ async fn start_check(settings: &Settings) -> Result<StartResponse, anyhow::Error
     let mut settings = settings.clone();
     settings.is_setup = IsSetup::Bool(true);
 
-    stop_service(&settings).await.ok();
-    sleep(Duration::from_secs(1)).await;
+    // Stopping a remote Syncthing would shut it down for good.
+    if settings.mode != Mode::Remote {
+        stop_service(&settings.default_instance()).await.ok();
+        sleep(Duration::from_secs(1)).await;
+    }
 
     if let Err(err) = init_service(&settings).await {
         warn!("Error during start check (init): {err:?}");
@@ -87,14 +88,14 @@ async fn start_check(settings: &Settings) -> Result<StartResponse, anyhow::Error
                 )),
                 error_details: Some(err.to_string()),
             }),
-            Mode::Flatpak => {
-                // In Flatpak mode, this shouldn't fail.
+            Mode::Flatpak | Mode::Remote => {
+                // In Flatpak and remote mode, this shouldn't fail.
                 Err(err)
             }
         };
     }
 
-    if let Err(err) = start_service(&settings).await {
+    if let Err(err) = start_service(&settings.default_instance()).await {
         warn!("Error during start check (start): {err:?}");
         return Ok(StartResponse {
             success: false,
@@ -105,7 +106,10 @@ async fn start_check(settings: &Settings) -> Result<StartResponse, anyhow::Error
 
     sleep(Duration::from_secs(3)).await;
     for _ in 0..7 {
-        if matches!(get_state(&settings).await, Ok(SyncthingState::Running)) {
+        if matches!(
+            get_state(&settings.default_instance()).await,
+            Ok(SyncthingState::Running)
+        ) {
             return Ok(StartResponse {
                 success: true,
                 error: None,
@@ -122,6 +126,10 @@ async fn start_check(settings: &Settings) -> Result<StartResponse, anyhow::Error
             "Failed to start Syncthing. Please check the Flatpak '{}' is installed.",
             settings.flatpak_name
         ),
+        Mode::Remote => format!(
+            "Failed to reach Syncthing at '{}'. Please check the URL, the API key and the certificate.",
+            settings.remote_url.as_deref().unwrap_or_default()
+        ),
     };
 
     warn!("Error during start check (unknown)");
@@ -132,9 +140,13 @@ async fn start_check(settings: &Settings) -> Result<StartResponse, anyhow::Error
     })
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 struct ScanPortResponse {
     port: Option<u32>,
+    /// Host of the GUI, if it was found in Syncthing's configuration.
+    host: Option<String>,
+    /// Whether the GUI uses HTTPS, if the port was found.
+    tls: Option<bool>,
 }
 
 async fn scan_port(
@@ -143,60 +155,85 @@ async fn scan_port(
     static TRY_PORTS: &[u32] = &[8384, 8080];
 
     if TESTING_AUTO_FAIL_SCANS {
-        return Ok(ScanPortResponse { port: None });
+        return Ok(ScanPortResponse::default());
     }
 
     // First just try to check the config.
-    if let Some(port) = get_config(&*settings_provider.settings().await)
-        .await
-        .and_then(|config| {
-            let doc = config.as_document();
-            let port = evaluate_xpath(&doc, "/configuration/gui/address")
-                .ok()?
-                .string();
-            let parts = port.split(':');
-            let port = parts.into_iter().nth(1);
-            port.and_then(|port_str| port_str.parse::<u32>().ok())
-        })
+    if let Some(address) =
+        get_config(&*settings_provider.settings().await).and_then(|config| config.gui_address())
     {
-        debug!("Found port {port} in config. Trying it.");
+        debug!(
+            "Found address {} in config. Trying it.",
+            address.authority()
+        );
         // Try it out:
-        Ok(ScanPortResponse {
-            port: try_port(port, settings_provider).await.ok(),
+        Ok(match try_address(&address, settings_provider).await {
+            Ok(found) => ScanPortResponse {
+                port: Some(found.port),
+                host: Some(found.host),
+                tls: found.tls,
+            },
+            Err(_) => ScanPortResponse::default(),
         })
     } else {
         // Fall back to trying.
         for port in TRY_PORTS {
-            if let Ok(port) = try_port(*port, settings_provider).await {
-                return Ok(ScanPortResponse { port: Some(port) });
+            let address = GuiAddress {
+                host: DEFAULT_HOST.to_string(),
+                port: *port,
+                tls: None,
+            };
+            if let Ok(found) = try_address(&address, settings_provider).await {
+                return Ok(ScanPortResponse {
+                    port: Some(found.port),
+                    host: None,
+                    tls: found.tls,
+                });
             }
         }
-        Ok(ScanPortResponse { port: None })
+        Ok(ScanPortResponse::default())
     }
 }
 
-async fn try_port(port: u32, settings_provider: &SettingsProvider) -> Result<u32, anyhow::Error> {
-    debug!("Trying port {port}.");
-    let body = Request::builder()
-        .uri(
-            Uri::builder()
-                .scheme(Scheme::HTTP)
-                .authority(format!("127.0.0.1:{port}"))
-                .path_and_query("/")
-                .build()
-                .unwrap(),
-        )
-        .header("User-Agent", "watchdog")
-        .body(Body::empty())
-        .unwrap();
-    let proxy_reponse =
-        handle_proxy(Ipv4Addr::new(127, 0, 0, 1).into(), body, settings_provider).await?;
-    if proxy_reponse.status() == StatusCode::OK {
-        let (_, rbody) = proxy_reponse.into_parts();
+/// Checks that Syncthing's GUI answers at `address`. Returns the address with `tls` set.
+async fn try_address(
+    address: &GuiAddress,
+    settings_provider: &SettingsProvider,
+) -> Result<GuiAddress, anyhow::Error> {
+    debug!("Trying {}.", address.authority());
+    let (verification, auth_header) = {
+        let settings = settings_provider.settings().await;
+        let instance = settings.default_instance();
+        let verification =
+            TlsVerification::for_host(&connect_host(&address.host), instance.tls_pin());
+        let auth_header = (!instance.basic_auth_user.is_empty()).then(|| {
+            let credentials = format!("{}:{}", instance.basic_auth_user, instance.basic_auth_pass);
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        });
+        (verification, auth_header)
+    };
+    let (scheme, uri) = probe_address(address, verification)
+        .await
+        .map_err(|err| anyhow!(err))?;
+    let mut req = Request::builder().uri(uri).header("User-Agent", "watchdog");
+    if let Some(auth_header) = auth_header {
+        req = req.header(AUTHORIZATION, auth_header);
+    }
+    let response = make_https_client::<Body>(verification)
+        .request(req.body(Body::empty())?)
+        .await?;
+    if response.status() == StatusCode::OK {
+        let (_, rbody) = response.into_parts();
         let content = String::from_utf8(body::to_bytes(rbody).await?.to_vec())?;
         if content.contains("syncthing/app.js") {
             debug!("Port check request OK!");
-            Ok(port)
+            Ok(GuiAddress {
+                tls: Some(scheme == BackendScheme::Https),
+                ..address.clone()
+            })
         } else {
             debug!("Port check request succeeded but doesn't seem to be Syncthing.");
             Err(anyhow!("Port not found"))
@@ -207,38 +244,49 @@ async fn try_port(port: u32, settings_provider: &SettingsProvider) -> Result<u32
     }
 }
 
+/// Whether an API key was found in Syncthing's config and works. A working key is saved in the
+/// settings by the watchdog itself, the frontend does not get to see it.
 #[derive(Debug, Serialize)]
 struct ScanApiKeyResponse {
-    api_key: Option<String>,
+    found: bool,
+    valid: bool,
 }
 
 async fn scan_api_key(settings: &SettingsProvider) -> Result<ScanApiKeyResponse, anyhow::Error> {
     if TESTING_AUTO_FAIL_SCANS {
-        return Ok(ScanApiKeyResponse { api_key: None });
+        return Ok(ScanApiKeyResponse {
+            found: false,
+            valid: false,
+        });
     }
 
-    let api_key = get_config(&*settings.settings().await)
-        .await
-        .and_then(|config| {
-            let doc = config.as_document();
-            let api_key = evaluate_xpath(&doc, "/configuration/gui/apikey").ok()?;
-            Some(api_key.string())
+    let api_key = get_config(&*settings.settings().await).and_then(|config| config.api_key());
+    let Some(api_key) = api_key else {
+        return Ok(ScanApiKeyResponse {
+            found: false,
+            valid: false,
+        });
+    };
+    if !test_api_key(settings, &api_key).await {
+        return Ok(ScanApiKeyResponse {
+            found: true,
+            valid: false,
         });
-
-    if let Some(api_key) = api_key.as_ref() {
-        if !test_api_key(settings, api_key).await {
-            return Ok(ScanApiKeyResponse { api_key: None });
-        }
     }
 
-    Ok(ScanApiKeyResponse { api_key })
+    let patch = Map::from_iter([("api_key".to_string(), Value::String(api_key))]);
+    settings.update(patch).await?;
+    Ok(ScanApiKeyResponse {
+        found: true,
+        valid: true,
+    })
 }
 
 /// Check if an API key is actually usable
 async fn test_api_key(settings: &SettingsProvider, key: &str) -> bool {
-    let client = make_unsafe_https_client::<Body>();
-    match settings.backend_uri().await {
-        Ok((_, backend_uri)) => {
+    match settings.backend_uri(DEFAULT_INSTANCE).await {
+        Ok((backend_uri, verification)) => {
+            let client = make_https_client::<Body>(verification);
             let uri = format!("{backend_uri}rest/system/status");
             debug!("Testing API key against {}", uri);
             let req = hyper::Request::builder()
@@ -290,77 +338,17 @@ async fn basic_auth(settings: &Settings) -> Result<BasicAuthResponse, anyhow::Er
     // it's configured.
 
     Ok(BasicAuthResponse {
-        basic_auth_user: get_config(settings).await.and_then(|config| {
-            let doc = config.as_document();
-            let maybe_user = evaluate_xpath(&doc, "/configuration/gui/user")
-                .ok()?
-                .string();
-            if maybe_user.trim().is_empty() {
-                None
-            } else {
-                Some(maybe_user)
-            }
-        }),
+        basic_auth_user: get_config(settings).and_then(|config| config.gui_user()),
     })
 }
 
-async fn get_config(settings: &Settings) -> Option<sxd_document::Package> {
-    let home = match my_home() {
-        Ok(Some(home)) => home,
-        _ => {
-            return None;
-        }
-    };
-
-    let mut possible_paths = Vec::with_capacity(6);
-    if settings.mode == Mode::Flatpak || settings._wizard_force_flatpak_config_for.is_some() {
-        let flatpak_name = settings
-            ._wizard_force_flatpak_config_for
-            .as_deref()
-            .unwrap_or(&settings.flatpak_name);
-        possible_paths.push(
-            home.join(".var")
-                .join("app")
-                .join(flatpak_name)
-                .join(".local")
-                .join("state")
-                .join("syncthing")
-                .join("config.xml"),
-        );
-        possible_paths.push(
-            home.join(".var")
-                .join("app")
-                .join(flatpak_name)
-                .join("config")
-                .join("syncthing")
-                .join("config.xml"),
-        );
-    }
-
-    if let Ok(var) = env::var("XDG_STATE_HOME") {
-        possible_paths.push(PathBuf::from(var).join("syncthing").join("config.xml"));
-    }
-    if let Ok(var) = env::var("XDG_CONFIG_HOME") {
-        possible_paths.push(PathBuf::from(var).join("syncthing").join("config.xml"));
-    }
-    possible_paths.push(
-        home.join(".local")
-            .join("state")
-            .join("syncthing")
-            .join("config.xml"),
+fn get_config(settings: &Settings) -> Option<SyncthingConfig> {
+    let config = SyncthingConfig::find(
+        &settings.default_instance(),
+        settings._wizard_force_flatpak_config_for.as_deref(),
     );
-    possible_paths.push(home.join(".config").join("syncthing").join("config.xml"));
-
-    for path in possible_paths {
-        if let Some(config) = try_read_config(&path) {
-            debug!("detected config path: {}", path.display());
-            return Some(config);
-        }
+    if config.is_none() {
+        warn!("did not find syncthing config file");
     }
-    warn!("did not find syncthing config file");
-    None
-}
-
-fn try_read_config(path: &Path) -> Option<sxd_document::Package> {
-    sxd_document::parser::parse(&read_to_string(path).ok()?).ok()
+    config
 }
@@ -0,0 +1,346 @@
+//! Command line interface of the watchdog.
+//! `run` starts the watchdog itself, `credentials` reads the credentials store, all other
+//! subcommands are small clients that talk to an already running watchdog over its HTTP API.
+
+use crate::api::{
+    API_PREFIX, BIND_ADDR, CHECK_ROUTE, CRASHES_ROUTE, HEALTH_ROUTE, INSTANCES_ROUTE,
+    LOG_LEVEL_ROUTE, LOGS_ROUTE, RELOAD_CONFIG_ROUTE, SHUTDOWN_ROUTE, START_ROUTE, STATE_ROUTE,
+    STOP_ROUTE, VERSION_ROUTE,
+};
+use crate::auth_token::{AuthToken, TOKEN_FILE_NAME, TOKEN_HEADER};
+use crate::credentials::CredentialsStore;
+use crate::endpoint::Endpoint;
+use crate::logging::LogFormat;
+use crate::settings_overrides::Overrides;
+use clap::{Args, Parser, Subcommand};
+use homedir::my_home;
+use hyper::client::conn;
+use hyper::header::HOST;
+use hyper::{Body, Client, Method, Request, Response, body};
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio::net::UnixStream;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Watchdog and proxy for the Decky Syncthing plugin.")]
+pub struct Cli {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the watchdog.
+    Run(RunArgs),
+    /// Print the state of the Syncthing service.
+    Status {
+        /// Name of the Syncthing instance, if not the default one.
+        #[arg(long)]
+        instance: Option<String>,
+    },
+    /// Print the health of the watchdog's subsystems.
+    Health,
+    /// Start the Syncthing service.
+    Start {
+        /// Name of the Syncthing instance, if not the default one.
+        #[arg(long)]
+        instance: Option<String>,
+    },
+    /// Stop the Syncthing service.
+    Stop {
+        /// Name of the Syncthing instance, if not the default one.
+        #[arg(long)]
+        instance: Option<String>,
+    },
+    /// List the Syncthing instances with their state.
+    Instances,
+    /// Run a setup check (e.g. `start`, `scan_port`, `scan_api_key`, `scan_basic_auth`).
+    Check {
+        /// Name of the check.
+        name: String,
+    },
+    /// Make the watchdog reload its settings.
+    Reload,
+    /// Print version and build information of the running watchdog.
+    Version,
+    /// Ask the running watchdog to shut down.
+    Shutdown,
+    /// Print the log level of the watchdog, or change it until the settings are reloaded.
+    LogLevel {
+        /// New log level (`error`, `warn`, `info`, `debug`, `trace` or `off`).
+        level: Option<String>,
+    },
+    /// Print the most recent log entries of the watchdog as JSON.
+    Logs {
+        /// Number of entries to print.
+        #[arg(long, default_value_t = 100)]
+        lines: usize,
+        /// Only print entries with at least this level.
+        #[arg(long)]
+        level: Option<String>,
+    },
+    /// List the crash reports of the watchdog, or print the one with the given ID.
+    Crashes {
+        /// ID of the crash report.
+        id: Option<String>,
+    },
+    /// Print the credentials kept in the configured credentials store, per instance as JSON.
+    /// Reads the store directly, the watchdog does not have to run.
+    Credentials {
+        /// Path to the plugin settings JSON.
+        #[arg(long)]
+        settings: PathBuf,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Path to the plugin settings JSON.
+    #[arg(long)]
+    pub settings: PathBuf,
+    /// Path to the PID file of the watchdog. The endpoint file is written next to it.
+    #[arg(long)]
+    pub pid_file: PathBuf,
+    /// Directory to write logs into.
+    #[arg(long)]
+    pub log_dir: PathBuf,
+    /// Format of the log files. Overrides the `log_format` setting.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Address to listen on. Overrides the `listen_address` setting.
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// Unix domain socket to serve the control API on. Overrides the `control_socket` setting.
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+    /// Override a setting, e.g. `--set port=8385`. Takes precedence over the environment
+    /// variables (`DECKY_ST_<SETTING>`, e.g. `DECKY_ST_PORT=8385`) and the settings file.
+    #[arg(long = "set", value_name = "SETTING=VALUE", value_parser = parse_setting)]
+    pub set: Vec<(String, String)>,
+}
+
+fn parse_setting(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(setting, value)| (setting.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected <SETTING>=<VALUE>, got `{arg}`"))
+}
+
+/// Runtime directory the plugin runs the watchdog in, relative to the home directory, if
+/// `DECKY_PLUGIN_RUNTIME_DIR` is not set.
+const DEFAULT_RUNTIME_DIR: &str = "homebrew/data/decky-syncthing";
+
+/// Options for the client subcommands, to find the running watchdog. If none are given, the
+/// endpoint and token files in the plugin's runtime directory are used, see
+/// [`ClientArgs::or_default`].
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    /// Address of the watchdog.
+    #[arg(long, global = true, conflicts_with = "socket")]
+    pub addr: Option<SocketAddr>,
+    /// Unix domain socket of the watchdog control API.
+    #[arg(long, global = true)]
+    pub socket: Option<PathBuf>,
+    /// Endpoint file written by the watchdog (`watchdog.endpoint.json` in the runtime
+    /// directory).
+    #[arg(long, global = true, conflicts_with_all = ["addr", "socket"])]
+    pub endpoint_file: Option<PathBuf>,
+    /// File with the token for the control API (`watchdog.token` in the runtime directory).
+    /// Defaults to the one next to the endpoint file. Not needed for the control socket.
+    #[arg(long, global = true)]
+    pub token_file: Option<PathBuf>,
+}
+
+enum Target {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ClientArgs {
+    /// Finds the watchdog via the endpoint file in the runtime directory, if it exists.
+    pub fn for_runtime_dir(runtime_dir: &Path) -> Self {
+        let endpoint_file = Endpoint::file_path(runtime_dir);
+        let token_file = AuthToken::file_path(runtime_dir);
+        Self {
+            addr: None,
+            socket: None,
+            endpoint_file: endpoint_file.exists().then_some(endpoint_file),
+            token_file: token_file.exists().then_some(token_file),
+        }
+    }
+
+    /// These options, or if none are given, the ones for the plugin's runtime directory
+    /// (`DECKY_PLUGIN_RUNTIME_DIR`, or [`DEFAULT_RUNTIME_DIR`] in the home directory). Without
+    /// an endpoint file there, the watchdog is expected on the default address.
+    fn or_default(self) -> Self {
+        if self.addr.is_some()
+            || self.socket.is_some()
+            || self.endpoint_file.is_some()
+            || self.token_file.is_some()
+        {
+            return self;
+        }
+        let runtime_dir = env::var_os("DECKY_PLUGIN_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .or_else(|| {
+                my_home()
+                    .ok()
+                    .flatten()
+                    .map(|home| home.join(DEFAULT_RUNTIME_DIR))
+            });
+        match runtime_dir {
+            Some(runtime_dir) => Self::for_runtime_dir(&runtime_dir),
+            None => self,
+        }
+    }
+
+    /// The token for the control API on the HTTP server, if a token file is given or next to
+    /// the endpoint file.
+    fn token(&self) -> Result<Option<String>, anyhow::Error> {
+        if let Some(path) = &self.token_file {
+            return Ok(Some(AuthToken::read(path)?));
+        }
+        let path = self
+            .endpoint_file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(|dir| dir.join(TOKEN_FILE_NAME));
+        Ok(path.and_then(|path| AuthToken::read(&path).ok()))
+    }
+
+    fn target(&self) -> Result<Target, anyhow::Error> {
+        if let Some(socket) = &self.socket {
+            return Ok(Target::Unix(socket.clone()));
+        }
+        if let Some(addr) = self.addr {
+            return Ok(Target::Tcp(addr));
+        }
+        if let Some(path) = &self.endpoint_file {
+            let endpoint = Endpoint::read(path)?;
+            return Ok(match endpoint.control_socket {
+                Some(socket) => Target::Unix(socket),
+                None => Target::Tcp(endpoint.http),
+            });
+        }
+        Ok(Target::Tcp(BIND_ADDR.parse()?))
+    }
+}
+
+/// Runs one of the client subcommands against a running watchdog. Panics if called with
+/// [`Command::Run`] or [`Command::Credentials`].
+pub async fn run_client_command(client: ClientArgs, command: Command) -> ExitCode {
+    let (method, route) = match &command {
+        Command::Run(_) | Command::Credentials { .. } => unreachable!("not a client command"),
+        Command::Status { instance } => (Method::GET, instance_route(instance, STATE_ROUTE)),
+        Command::Health => (Method::GET, HEALTH_ROUTE.to_string()),
+        Command::Start { instance } => (Method::POST, instance_route(instance, START_ROUTE)),
+        Command::Stop { instance } => (Method::POST, instance_route(instance, STOP_ROUTE)),
+        Command::Instances => (Method::GET, INSTANCES_ROUTE.to_string()),
+        Command::Check { name } => (Method::POST, format!("{CHECK_ROUTE}/{name}")),
+        Command::Reload => (Method::POST, RELOAD_CONFIG_ROUTE.to_string()),
+        Command::Version => (Method::GET, VERSION_ROUTE.to_string()),
+        Command::Shutdown => (Method::POST, SHUTDOWN_ROUTE.to_string()),
+        Command::LogLevel { level: None } => (Method::GET, LOG_LEVEL_ROUTE.to_string()),
+        Command::LogLevel { level: Some(level) } => {
+            (Method::POST, format!("{LOG_LEVEL_ROUTE}?level={level}"))
+        }
+        Command::Logs { lines, level } => {
+            let mut route = format!("{LOGS_ROUTE}?lines={lines}");
+            if let Some(level) = level {
+                route.push_str(&format!("&level={level}"));
+            }
+            (Method::GET, route)
+        }
+        Command::Crashes { id: None } => (Method::GET, CRASHES_ROUTE.to_string()),
+        Command::Crashes { id: Some(id) } => (Method::GET, format!("{CRASHES_ROUTE}/{id}")),
+    };
+    match request(&client.or_default(), method, &route).await {
+        Ok((true, body)) => {
+            if !body.is_empty() {
+                println!("{body}");
+            }
+            ExitCode::SUCCESS
+        }
+        Ok((false, body)) => {
+            eprintln!("{body}");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("failed to reach the watchdog: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the credentials of the store configured in the settings file (and the environment).
+pub async fn print_credentials(settings_path: &Path) -> ExitCode {
+    let result = async {
+        let settings = serde_json::from_str(&tokio::fs::read_to_string(settings_path).await?)?;
+        let store = CredentialsStore::of(&Overrides::new(env::vars_os(), &[])?.apply(settings));
+        let credentials = store.load(settings_path).await?;
+        Ok::<_, anyhow::Error>(serde_json::to_string(&credentials)?)
+    };
+    match result.await {
+        Ok(credentials) => {
+            println!("{credentials}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("failed to read the credentials: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Scopes `route` to the given instance.
+fn instance_route(instance: &Option<String>, route: &str) -> String {
+    match instance {
+        Some(name) => format!(
+            "{INSTANCES_ROUTE}/{name}{}",
+            route.trim_start_matches(API_PREFIX)
+        ),
+        None => route.to_string(),
+    }
+}
+
+/// Sends a request to the watchdog and returns whether it was successful and the response body.
+pub async fn request(
+    client: &ClientArgs,
+    method: Method,
+    route: &str,
+) -> Result<(bool, String), anyhow::Error> {
+    let response = match client.target()? {
+        Target::Tcp(addr) => {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{route}"));
+            if let Some(token) = client.token()? {
+                req = req.header(TOKEN_HEADER, token);
+            }
+            Client::new().request(req.body(Body::empty())?).await?
+        }
+        Target::Unix(path) => request_unix(&path, method, route).await?,
+    };
+    let success = response.status().is_success();
+    let content = String::from_utf8(body::to_bytes(response.into_body()).await?.to_vec())?;
+    Ok((success, content.trim().to_string()))
+}
+
+async fn request_unix(
+    path: &Path,
+    method: Method,
+    route: &str,
+) -> Result<Response<Body>, anyhow::Error> {
+    let stream = UnixStream::connect(path).await?;
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(connection);
+    let req = Request::builder()
+        .method(method)
+        .uri(route)
+        .header(HOST, "localhost")
+        .body(Body::empty())?;
+    Ok(sender.send_request(req).await?)
+}
@@ -0,0 +1,271 @@
+//! Cross-origin requests (CORS) to the watchdog, e.g. from the plugin's frontend.
+//! Only origins on an allowlist (setting `cors_origins`, by default the Steam client and the
+//! Decky loader) may make cross-origin requests. Requests from other origins are denied, as the
+//! proxy adds the Syncthing credentials to every request and the control API can stop Syncthing,
+//! so every request is one with credentials. Requests without `Origin` (not from a browser) and
+//! same-origin requests (the Syncthing GUI via the proxy) are not affected.
+
+use crate::api::{WARNING_HEADER, make_json_error_response};
+use crate::settings::SettingsProvider;
+use hyper::header::{
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, HOST,
+    HeaderValue, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::warn;
+
+/// Origins allowed if `cors_origins` is not set: The Steam client (which runs the plugin's
+/// frontend) and the Decky loader.
+pub const DEFAULT_ORIGINS: &[&str] = &[
+    "https://steamloopback.host",
+    "http://127.0.0.1:1337",
+    "http://localhost:1337",
+];
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+const ALLOWED_HEADERS: &str = "accept, authorization, content-type, x-api-key, x-decky-syncthing-instance, x-decky-watchdog-token";
+/// How long browsers may cache the answer to a preflight request, in seconds.
+const MAX_AGE: &str = "600";
+
+pub enum Cors {
+    /// No cross-origin request.
+    SameOrigin,
+    /// A cross-origin request from an allowed origin, the response needs the CORS headers for
+    /// it, see [`add_headers`].
+    Allowed(HeaderValue),
+    /// To be answered with this response instead: A preflight request or a denied request.
+    Respond(Response<Body>),
+}
+
+/// Checks the origin of `req` and answers preflight requests.
+pub async fn check(req: &Request<Body>, settings: &SettingsProvider) -> Cors {
+    let Some(origin) = req.headers().get(ORIGIN) else {
+        return Cors::SameOrigin;
+    };
+    let origin_str = origin.to_str().unwrap_or_default().trim_end_matches('/');
+    let is_preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if !is_preflight && is_same_origin(origin_str, req) {
+        return Cors::SameOrigin;
+    }
+    let allowed = match &settings.settings().await.cors_origins {
+        Some(origins) => origins.iter().any(|o| is_origin(o, origin_str)),
+        None => DEFAULT_ORIGINS.iter().any(|o| is_origin(o, origin_str)),
+    } || is_same_origin(origin_str, req);
+    if !allowed {
+        warn!(
+            "Denied cross-origin request from {origin_str} to {} {}.",
+            req.method(),
+            req.uri().path()
+        );
+        return Cors::Respond(
+            make_json_error_response(
+                "Cross-origin requests from this origin are not allowed.",
+                StatusCode::FORBIDDEN,
+            )
+            .unwrap(),
+        );
+    }
+    if !is_preflight {
+        return Cors::Allowed(origin.clone());
+    }
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS)
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS)
+        .header(ACCESS_CONTROL_MAX_AGE, MAX_AGE)
+        .body(Body::empty())
+        .unwrap();
+    add_headers(&mut response, origin.clone());
+    Cors::Respond(response)
+}
+
+/// Adds the CORS headers for a request from the allowed `origin`.
+pub fn add_headers(response: &mut Response<Body>, origin: HeaderValue) {
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(WARNING_HEADER),
+    );
+    headers.append(VARY, HeaderValue::from_static("origin"));
+}
+
+/// Whether `origin` is the origin of the watchdog itself, as requested.
+fn is_same_origin(origin: &str, req: &Request<Body>) -> bool {
+    let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+    host.zip(origin.strip_prefix("http://"))
+        .is_some_and(|(host, origin)| origin.eq_ignore_ascii_case(host))
+}
+
+fn is_origin(allowed: &str, origin: &str) -> bool {
+    allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+}
+
+/// Whether `origin` can be used in `cors_origins`: `<scheme>://<host>[:<port>]`.
+pub fn is_valid_origin(origin: &str) -> bool {
+    origin.parse::<hyper::Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https"))
+            && uri.host().is_some()
+            && uri.path_and_query().is_none_or(|p| p == "/")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::test_support::{TempDir, settings_v2};
+    use crate::settings_overrides::Overrides;
+    use serde_json::{Value, json};
+    use std::fs::write;
+    use std::sync::Arc;
+
+    /// Host the watchdog is requested with.
+    const WATCHDOG_HOST: &str = "127.0.0.1:58384";
+
+    async fn settings(dir: &TempDir, fields: Value) -> Arc<SettingsProvider> {
+        let path = dir.path().join("settings.json");
+        write(&path, settings_v2(fields).to_string()).unwrap();
+        SettingsProvider::new(path, Overrides::default())
+            .await
+            .unwrap()
+    }
+
+    fn request(method: Method, origin: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri("/rest/system/status")
+            .header(HOST, WATCHDOG_HOST);
+        if let Some(origin) = origin {
+            req = req.header(ORIGIN, origin);
+        }
+        if method == Method::OPTIONS {
+            req = req.header(ACCESS_CONTROL_REQUEST_METHOD, "GET");
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn assert_allowed(cors: Cors, origin: &str) {
+        match cors {
+            Cors::Allowed(allowed) => assert_eq!(allowed, origin),
+            _ => panic!("{origin} is not allowed"),
+        }
+    }
+
+    fn assert_denied(cors: Cors) {
+        match cors {
+            Cors::Respond(response) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            _ => panic!("not denied"),
+        }
+    }
+
+    #[tokio::test]
+    async fn same_origin_requests_are_not_affected() {
+        let dir = TempDir::new("cors-same-origin");
+        let settings = settings(&dir, json!({})).await;
+        let no_origin = check(&request(Method::POST, None), &settings).await;
+        assert!(matches!(no_origin, Cors::SameOrigin));
+        let same_origin = request(Method::POST, Some("http://127.0.0.1:58384"));
+        assert!(matches!(
+            check(&same_origin, &settings).await,
+            Cors::SameOrigin
+        ));
+        // Another name of the same host is another origin.
+        assert_denied(
+            check(
+                &request(Method::POST, Some("http://localhost:58384")),
+                &settings,
+            )
+            .await,
+        );
+        assert_denied(
+            check(
+                &request(Method::POST, Some("https://127.0.0.1:58384")),
+                &settings,
+            )
+            .await,
+        );
+    }
+
+    #[tokio::test]
+    async fn allows_default_origins() {
+        let dir = TempDir::new("cors-default");
+        let settings = settings(&dir, json!({})).await;
+        for origin in DEFAULT_ORIGINS {
+            assert_allowed(
+                check(&request(Method::GET, Some(origin)), &settings).await,
+                origin,
+            );
+        }
+        let foreign = request(Method::GET, Some("https://attacker.example"));
+        assert_denied(check(&foreign, &settings).await);
+        let null = request(Method::POST, Some("null"));
+        assert_denied(check(&null, &settings).await);
+    }
+
+    #[tokio::test]
+    async fn allows_configured_origins() {
+        let dir = TempDir::new("cors-configured");
+        let settings = settings(&dir, json!({"cors_origins": ["https://example.org/"]})).await;
+        let configured = request(Method::DELETE, Some("https://example.org"));
+        assert_allowed(check(&configured, &settings).await, "https://example.org");
+        let default = request(Method::GET, Some("https://steamloopback.host"));
+        assert_denied(check(&default, &settings).await);
+    }
+
+    #[tokio::test]
+    async fn answers_preflight_requests() {
+        let dir = TempDir::new("cors-preflight");
+        let settings = settings(&dir, json!({})).await;
+        let origin = "https://steamloopback.host";
+        let Cors::Respond(response) =
+            check(&request(Method::OPTIONS, Some(origin)), &settings).await
+        else {
+            panic!("preflight request not answered");
+        };
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], ALLOWED_METHODS);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], ALLOWED_HEADERS);
+        assert_eq!(headers[VARY], "origin");
+
+        // Also for the watchdog's own origin, but not for others.
+        let same_origin = request(Method::OPTIONS, Some("http://127.0.0.1:58384"));
+        assert!(matches!(
+            check(&same_origin, &settings).await,
+            Cors::Respond(response) if response.status() == StatusCode::NO_CONTENT
+        ));
+        let foreign = request(Method::OPTIONS, Some("https://attacker.example"));
+        assert_denied(check(&foreign, &settings).await);
+
+        // A plain `OPTIONS` request is no preflight request.
+        let mut options = request(Method::OPTIONS, Some(origin));
+        options.headers_mut().remove(ACCESS_CONTROL_REQUEST_METHOD);
+        assert_allowed(check(&options, &settings).await, origin);
+    }
+
+    #[test]
+    fn adds_headers() {
+        let mut response = Response::new(Body::empty());
+        add_headers(
+            &mut response,
+            HeaderValue::from_static("https://steamloopback.host"),
+        );
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://steamloopback.host"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], WARNING_HEADER);
+    }
+
+    #[test]
+    fn validates_origins() {
+        assert!(is_valid_origin("https://steamloopback.host"));
+        assert!(is_valid_origin("http://127.0.0.1:1337/"));
+        assert!(!is_valid_origin("steamloopback.host"));
+        assert!(!is_valid_origin("ftp://steamloopback.host"));
+        assert!(!is_valid_origin("https://steamloopback.host/path"));
+    }
+}
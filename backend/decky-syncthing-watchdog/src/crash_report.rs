@@ -0,0 +1,176 @@
+//! Crash reports written by the panic hook.
+//! Each panic is saved as a JSON report in the `crashes` directory inside the log directory.
+//! Only the most recent reports are kept. They can be listed and fetched via the API, so that
+//! the plugin can offer to send them.
+
+use crate::credentials::scrub;
+use crate::logging;
+use crate::settings::redact;
+use crate::version::uptime_secs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CRASHES_DIR: &str = "crashes";
+/// Number of crash reports to keep.
+const MAX_REPORTS: usize = 10;
+/// Number of log lines included in a report.
+const LOG_LINES: usize = 200;
+
+struct Paths {
+    crashes_dir: PathBuf,
+    settings: PathBuf,
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub id: String,
+    /// Unix timestamp of the crash.
+    pub timestamp: u64,
+    pub version: String,
+    pub git_revision: String,
+    pub uptime_secs: u64,
+    pub message: String,
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: String,
+    /// The settings file at the time of the crash, without credentials.
+    pub settings: Value,
+    pub log: Vec<String>,
+}
+
+/// Short form of a [`CrashReport`], for listing.
+#[derive(Debug, Serialize)]
+pub struct CrashSummary {
+    pub id: String,
+    pub timestamp: u64,
+    pub version: String,
+    pub message: String,
+}
+
+/// Sets the directories crash reports are written to and the settings are read from.
+pub fn init(log_dir: &Path, settings_path: &Path) {
+    PATHS
+        .set(Paths {
+            crashes_dir: log_dir.join(CRASHES_DIR),
+            settings: settings_path.to_path_buf(),
+        })
+        .ok();
+}
+
+/// Writes a crash report and removes the oldest ones. Blocking, called from the panic hook.
+pub fn write_report(
+    message: String,
+    location: Option<String>,
+    thread: Option<String>,
+    backtrace: String,
+) -> Result<PathBuf, anyhow::Error> {
+    let paths = PATHS
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("crash reports not initialized"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let report = CrashReport {
+        // Milliseconds, so that reports of several panics in a row don't overwrite each other.
+        id: now.as_millis().to_string(),
+        timestamp: now.as_secs(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_revision: env!("DECKY_WATCHDOG_GIT_REVISION").to_string(),
+        uptime_secs: uptime_secs(),
+        message: scrub(&message).into_owned(),
+        location,
+        thread,
+        backtrace,
+        settings: redacted_settings(&paths.settings),
+        log: logging::last_lines(LOG_LINES),
+    };
+    create_dir_all(&paths.crashes_dir)?;
+    let path = report_path(&paths.crashes_dir, &report.id);
+    write(&path, serde_json::to_string_pretty(&report)?)?;
+    rotate(&paths.crashes_dir)?;
+    Ok(path)
+}
+
+/// Reads the settings file and blanks out the credentials. Returns the error as a string
+/// if the settings can not be read, the report is written anyway.
+fn redacted_settings(path: &Path) -> Value {
+    let mut settings = match read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(serde_json::from_str::<Value>(&s)?))
+    {
+        Ok(settings) => settings,
+        Err(err) => return Value::String(format!("failed to read settings: {err}")),
+    };
+    redact(&mut settings);
+    settings
+}
+
+fn report_path(crashes_dir: &Path, id: &str) -> PathBuf {
+    crashes_dir.join(format!("crash-{id}.json"))
+}
+
+/// Returns the IDs of all reports in the directory, oldest first.
+fn report_ids(crashes_dir: &Path) -> io::Result<Vec<String>> {
+    let mut ids = match read_dir(crashes_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                let id = name.strip_prefix("crash-")?.strip_suffix(".json")?;
+                id.parse::<u128>().ok()?;
+                Some(id.to_string())
+            })
+            .collect::<Vec<_>>(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    ids.sort_by_key(|id| id.parse::<u128>().unwrap_or_default());
+    Ok(ids)
+}
+
+fn rotate(crashes_dir: &Path) -> io::Result<()> {
+    let ids = report_ids(crashes_dir)?;
+    for id in &ids[..ids.len().saturating_sub(MAX_REPORTS)] {
+        remove_file(report_path(crashes_dir, id))?;
+    }
+    Ok(())
+}
+
+/// Lists the crash reports, newest first.
+pub fn list() -> Result<Vec<CrashSummary>, anyhow::Error> {
+    let Some(paths) = PATHS.get() else {
+        return Ok(Vec::new());
+    };
+    let mut summaries = Vec::new();
+    for id in report_ids(&paths.crashes_dir)?.into_iter().rev() {
+        if let Some(report) = get(&id)? {
+            summaries.push(CrashSummary {
+                id: report.id,
+                timestamp: report.timestamp,
+                version: report.version,
+                message: report.message,
+            });
+        }
+    }
+    Ok(summaries)
+}
+
+/// Returns the crash report with the given ID, if it exists.
+pub fn get(id: &str) -> Result<Option<CrashReport>, anyhow::Error> {
+    let Some(paths) = PATHS.get() else {
+        return Ok(None);
+    };
+    // IDs are timestamps, anything else could escape the crash directory.
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    match read_to_string(report_path(&paths.crashes_dir, id)) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
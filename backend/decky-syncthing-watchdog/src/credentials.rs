@@ -0,0 +1,455 @@
+//! Protection of the Syncthing credentials at rest and in output.
+//! The settings file is restricted to the user, as it may hold the credentials. Optionally the
+//! credentials are kept out of it, in a credentials file next to it or in the freedesktop Secret
+//! Service (see [`CredentialsStore`]). The settings file then keeps empty values and credentials
+//! written to it (e.g. by the plugin) are moved into the store when the settings are loaded.
+//! Known secrets are replaced in log lines and error responses, see [`scrub`].
+
+use crate::settings::{DEFAULT_INSTANCE, REDACTED, write_atomically};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::Permissions;
+use std::io;
+use std::mem::take;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs::{metadata, read_to_string, set_permissions};
+use tokio::time::timeout;
+
+/// Settings moved into the credentials store, of the default instance and of each of the
+/// `instances`.
+pub const CREDENTIAL_SETTINGS: &[&str] = &["api_key", "basic_auth_user", "basic_auth_pass"];
+/// Mode of files holding credentials.
+pub const PRIVATE_MODE: u32 = 0o600;
+/// Secrets shorter than this are not scrubbed from output, they would match all over the place.
+const MIN_SCRUB_LEN: usize = 4;
+/// Max. time to wait for the Secret Service, which may not be running at all.
+const SECRET_SERVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static KNOWN_SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+#[derive(Debug, Error)]
+pub enum CredentialsError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Error reading credentials JSON: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Secret Service error: {0}")]
+    SecretService(#[from] zbus::Error),
+    #[error("Secret Service did not answer in time.")]
+    Timeout,
+    #[error("Secret Service has no default collection.")]
+    NoCollection,
+    #[error("Secret Service collection is locked and can only be unlocked interactively.")]
+    Locked,
+}
+
+/// Where the credentials are kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsStore {
+    /// In the settings file.
+    #[default]
+    Settings,
+    /// In `<settings name>.credentials.json` next to the settings file.
+    File,
+    /// In the default collection of the freedesktop Secret Service (e.g. KWallet or GNOME
+    /// Keyring).
+    SecretService,
+}
+
+impl Display for CredentialsStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CredentialsStore::Settings => "settings file",
+            CredentialsStore::File => "credentials file",
+            CredentialsStore::SecretService => "Secret Service",
+        })
+    }
+}
+
+impl CredentialsStore {
+    /// The store configured in `settings`. Invalid values are reported by the validation.
+    pub fn of(settings: &Value) -> Self {
+        settings
+            .get("credentials_store")
+            .and_then(|value| Self::deserialize(value).ok())
+            .unwrap_or_default()
+    }
+
+    /// Loads the stored credentials. The settings file store has none of its own, but still
+    /// returns the ones left in the credentials file, so that switching back to it keeps them.
+    pub async fn load(self, settings_path: &Path) -> Result<Credentials, CredentialsError> {
+        match self {
+            CredentialsStore::Settings | CredentialsStore::File => {
+                match read_to_string(credentials_file(settings_path)).await {
+                    Ok(content) => Ok(serde_json::from_str(&content)?),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Credentials::default()),
+                    Err(err) => Err(err.into()),
+                }
+            }
+            CredentialsStore::SecretService => {
+                timeout(SECRET_SERVICE_TIMEOUT, secret_service::load(settings_path))
+                    .await
+                    .map_err(|_| CredentialsError::Timeout)?
+            }
+        }
+    }
+
+    /// Replaces the stored credentials. Does nothing for the settings file store.
+    pub async fn save(
+        self,
+        settings_path: &Path,
+        credentials: &Credentials,
+    ) -> Result<(), CredentialsError> {
+        match self {
+            CredentialsStore::Settings => Ok(()),
+            CredentialsStore::File => Ok(write_atomically(
+                &credentials_file(settings_path),
+                serde_json::to_string(credentials)?.as_bytes(),
+            )
+            .await?),
+            CredentialsStore::SecretService => timeout(
+                SECRET_SERVICE_TIMEOUT,
+                secret_service::save(settings_path, credentials),
+            )
+            .await
+            .map_err(|_| CredentialsError::Timeout)?,
+        }
+    }
+}
+
+/// Path of the credentials file of the `file` store.
+fn credentials_file(settings_path: &Path) -> PathBuf {
+    settings_path.with_extension("credentials.json")
+}
+
+/// The credentials that are set, as map of setting to value per instance name.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials(BTreeMap<String, BTreeMap<String, String>>);
+
+impl Credentials {
+    /// Takes the credentials that are set out of `settings` (as in the settings file), leaving
+    /// empty values.
+    pub fn take_from(settings: &mut Value) -> Self {
+        let mut slf = Self::default();
+        let Some(settings) = settings.as_object_mut() else {
+            return slf;
+        };
+        slf.take_instance(DEFAULT_INSTANCE, settings);
+        if let Some(Value::Array(instances)) = settings.get_mut("instances") {
+            for instance in instances.iter_mut().filter_map(Value::as_object_mut) {
+                if let Some(name) = instance.get("name").and_then(Value::as_str) {
+                    let name = name.to_string();
+                    slf.take_instance(&name, instance);
+                }
+            }
+        }
+        slf
+    }
+
+    fn take_instance(&mut self, name: &str, settings: &mut Map<String, Value>) {
+        for key in CREDENTIAL_SETTINGS {
+            if let Some(Value::String(value)) = settings.get_mut(*key)
+                && !value.is_empty()
+            {
+                self.0
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(key.to_string(), take(value));
+            }
+        }
+    }
+
+    /// Fills in the credentials that are empty or missing in `settings`.
+    pub fn fill_in(&self, settings: &mut Value) {
+        let Some(settings) = settings.as_object_mut() else {
+            return;
+        };
+        self.fill_in_instance(DEFAULT_INSTANCE, settings);
+        if let Some(Value::Array(instances)) = settings.get_mut("instances") {
+            for instance in instances.iter_mut().filter_map(Value::as_object_mut) {
+                if let Some(name) = instance.get("name").and_then(Value::as_str) {
+                    let name = name.to_string();
+                    self.fill_in_instance(&name, instance);
+                }
+            }
+        }
+    }
+
+    fn fill_in_instance(&self, name: &str, settings: &mut Map<String, Value>) {
+        for (key, value) in self.0.get(name).into_iter().flatten() {
+            if settings.get(key).is_none_or(|current| current == "") {
+                settings.insert(key.clone(), Value::from(value.as_str()));
+            }
+        }
+    }
+
+    /// The credentials explicitly set to empty values in `settings`, e.g. in an update, with
+    /// empty values.
+    pub fn cleared_in(settings: &Value) -> Self {
+        let mut slf = Self::default();
+        let Some(settings) = settings.as_object() else {
+            return slf;
+        };
+        slf.cleared_in_instance(DEFAULT_INSTANCE, settings);
+        if let Some(Value::Array(instances)) = settings.get("instances") {
+            for instance in instances.iter().filter_map(Value::as_object) {
+                if let Some(name) = instance.get("name").and_then(Value::as_str) {
+                    slf.cleared_in_instance(name, instance);
+                }
+            }
+        }
+        slf
+    }
+
+    fn cleared_in_instance(&mut self, name: &str, settings: &Map<String, Value>) {
+        for key in CREDENTIAL_SETTINGS {
+            if settings.get(*key).is_some_and(|value| value == "") {
+                self.0
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(key.to_string(), String::new());
+            }
+        }
+    }
+
+    /// Removes the credentials that are in `other`.
+    pub fn remove(&mut self, other: &Self) {
+        for (name, credentials) in &other.0 {
+            if let Some(current) = self.0.get_mut(name) {
+                current.retain(|key, _| !credentials.contains_key(key));
+                if current.is_empty() {
+                    self.0.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Adds the credentials of `other`, replacing the ones of the same instance and setting.
+    pub fn extend(&mut self, other: Self) {
+        for (name, credentials) in other.0 {
+            self.0.entry(name).or_default().extend(credentials);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Restricts the file at `path` to the user, if group or others have access. Returns the
+/// previous mode if it was changed.
+pub async fn restrict_permissions(path: &Path) -> io::Result<Option<u32>> {
+    let mode = metadata(path).await?.permissions().mode() & 0o777;
+    if mode & 0o077 == 0 {
+        return Ok(None);
+    }
+    set_permissions(path, Permissions::from_mode(PRIVATE_MODE)).await?;
+    Ok(Some(mode))
+}
+
+/// Sets the secrets [`scrub`] replaces, replacing the previous ones.
+pub fn set_known_secrets<'a>(secrets: impl IntoIterator<Item = &'a str>) {
+    *KNOWN_SECRETS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = secret_forms(secrets);
+}
+
+/// The forms of `secrets` to replace: As they are and JSON-escaped, as they appear in JSON log
+/// entries if they contain e.g. `"` or `\`. Longest first, in case one contains another.
+fn secret_forms<'a>(secrets: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut forms = Vec::new();
+    for secret in secrets {
+        if secret.len() < MIN_SCRUB_LEN {
+            continue;
+        }
+        forms.push(secret.to_string());
+        if let Ok(quoted) = serde_json::to_string(secret) {
+            let escaped = &quoted[1..quoted.len() - 1];
+            if escaped != secret {
+                forms.push(escaped.to_string());
+            }
+        }
+    }
+    forms.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    forms.dedup();
+    forms
+}
+
+/// Replaces the known secrets (see [`set_known_secrets`]) in `text` with [`REDACTED`].
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    let secrets = KNOWN_SECRETS.read().unwrap_or_else(PoisonError::into_inner);
+    replace_secrets(text, &secrets)
+}
+
+fn replace_secrets<'a>(text: &'a str, secrets: &[String]) -> Cow<'a, str> {
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+/// Client of the freedesktop Secret Service API. The credentials are kept as one item (a JSON
+/// of [`Credentials`]) in the default collection, identified by the path of the settings file.
+/// Locked items and collections are only unlocked if that is possible without a prompt, as there
+/// is nobody to answer it.
+mod secret_service {
+    use super::{Credentials, CredentialsError};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+    use zbus::{Connection, Proxy};
+
+    const DESTINATION: &str = "org.freedesktop.secrets";
+    const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+    const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+    const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+    /// Object path meaning "none", e.g. if no prompt is needed.
+    const NONE: &str = "/";
+    const LABEL: &str = "Decky Syncthing credentials";
+
+    /// Session path, parameters, value and content type.
+    type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+    struct Session {
+        connection: Connection,
+        service: Proxy<'static>,
+        path: OwnedObjectPath,
+    }
+
+    impl Session {
+        /// Opens a session without transport encryption, the session bus is private to the
+        /// user anyway.
+        async fn open() -> Result<Self, CredentialsError> {
+            let connection = Connection::session().await?;
+            let service =
+                Proxy::new(&connection, DESTINATION, SERVICE_PATH, SERVICE_INTERFACE).await?;
+            let (_, path): (OwnedValue, OwnedObjectPath) = service
+                .call("OpenSession", &("plain", Value::from("")))
+                .await?;
+            Ok(Self {
+                connection,
+                service,
+                path,
+            })
+        }
+
+        async fn unlock(&self, objects: Vec<OwnedObjectPath>) -> Result<(), CredentialsError> {
+            let (_, prompt): (Vec<OwnedObjectPath>, OwnedObjectPath) =
+                self.service.call("Unlock", &(objects,)).await?;
+            match prompt.as_str() == NONE {
+                true => Ok(()),
+                false => Err(CredentialsError::Locked),
+            }
+        }
+
+        async fn find(
+            &self,
+            attributes: &HashMap<&str, String>,
+        ) -> Result<Option<OwnedObjectPath>, CredentialsError> {
+            let (unlocked, locked): (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) =
+                self.service.call("SearchItems", &(attributes,)).await?;
+            if let Some(item) = unlocked.into_iter().next() {
+                return Ok(Some(item));
+            }
+            let Some(item) = locked.into_iter().next() else {
+                return Ok(None);
+            };
+            self.unlock(vec![item.clone()]).await?;
+            Ok(Some(item))
+        }
+    }
+
+    fn attributes(settings_path: &Path) -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("application", "decky-syncthing".to_string()),
+            ("settings", settings_path.display().to_string()),
+        ])
+    }
+
+    pub async fn load(settings_path: &Path) -> Result<Credentials, CredentialsError> {
+        let session = Session::open().await?;
+        let Some(item) = session.find(&attributes(settings_path)).await? else {
+            return Ok(Credentials::default());
+        };
+        let secrets: HashMap<OwnedObjectPath, Secret> = session
+            .service
+            .call("GetSecrets", &(vec![item], &session.path))
+            .await?;
+        match secrets.into_values().next() {
+            Some((_, _, value, _)) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(Credentials::default()),
+        }
+    }
+
+    pub async fn save(
+        settings_path: &Path,
+        credentials: &Credentials,
+    ) -> Result<(), CredentialsError> {
+        let session = Session::open().await?;
+        let collection: OwnedObjectPath = session.service.call("ReadAlias", &("default",)).await?;
+        if collection.as_str() == NONE {
+            return Err(CredentialsError::NoCollection);
+        }
+        session.unlock(vec![collection.clone()]).await?;
+        let collection = Proxy::new(
+            &session.connection,
+            DESTINATION,
+            collection,
+            COLLECTION_INTERFACE,
+        )
+        .await?;
+        let properties = HashMap::from([
+            ("org.freedesktop.Secret.Item.Label", Value::from(LABEL)),
+            (
+                "org.freedesktop.Secret.Item.Attributes",
+                Value::from(attributes(settings_path)),
+            ),
+        ]);
+        let secret: Secret = (
+            session.path.clone(),
+            Vec::new(),
+            serde_json::to_vec(credentials)?,
+            "application/json".to_string(),
+        );
+        let (_, prompt): (OwnedObjectPath, OwnedObjectPath) = collection
+            .call("CreateItem", &(properties, secret, true))
+            .await?;
+        match prompt.as_str() == NONE {
+            true => Ok(()),
+            false => Err(CredentialsError::Locked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_json_escaped_secrets() {
+        let secret = r#"pass"word\"#;
+        let secrets = secret_forms([secret, "key1", "no"]);
+        assert_eq!(secrets, [r#"pass\"word\\"#, secret, "key1"]);
+
+        let line = serde_json::json!({ "message": format!("Using {secret} and key1") }).to_string();
+        let scrubbed = replace_secrets(&line, &secrets);
+        assert!(!scrubbed.contains("pass") && !scrubbed.contains("key1"));
+        let scrubbed: Value = serde_json::from_str(&scrubbed).unwrap();
+        assert_eq!(
+            scrubbed["message"],
+            format!("Using {REDACTED} and {REDACTED}")
+        );
+        assert_eq!(replace_secrets(secret, &secrets), REDACTED);
+    }
+}
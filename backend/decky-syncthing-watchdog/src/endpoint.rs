@@ -0,0 +1,62 @@
+//! The endpoints the watchdog listens on.
+//! They are written to a file in the runtime directory (next to the PID file), so that clients
+//! (the plugin, the CLI) can find the watchdog.
+
+use crate::api::BIND_ADDR;
+use crate::settings::Settings;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, remove_file, write};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+pub const ENDPOINT_FILE_NAME: &str = "watchdog.endpoint.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+    /// Address of the HTTP server (proxy and control API).
+    pub http: SocketAddr,
+    /// Unix domain socket serving only the control API, if enabled.
+    pub control_socket: Option<PathBuf>,
+}
+
+impl Endpoint {
+    /// Determines the endpoints to listen on from the settings (including command line
+    /// overrides). Non-loopback addresses are rejected in favor of the default address,
+    /// since the proxy adds the Syncthing credentials to requests.
+    pub fn resolve(settings: &Settings) -> Self {
+        let default = BIND_ADDR.parse().unwrap();
+        let http = match settings.listen_address {
+            Some(addr) if !addr.ip().is_loopback() => {
+                warn!("listen address {addr} is not a loopback address, using {default} instead.");
+                default
+            }
+            Some(addr) => addr,
+            None => default,
+        };
+        Self {
+            http,
+            control_socket: settings.control_socket.clone(),
+        }
+    }
+
+    pub fn file_path(runtime_dir: &Path) -> PathBuf {
+        runtime_dir.join(ENDPOINT_FILE_NAME)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_str(&read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, runtime_dir: &Path) -> Result<(), anyhow::Error> {
+        Ok(write(
+            Self::file_path(runtime_dir),
+            serde_json::to_string(self)?,
+        )?)
+    }
+
+    pub fn remove(runtime_dir: &Path) -> io::Result<()> {
+        remove_file(Self::file_path(runtime_dir))
+    }
+}
@@ -0,0 +1,100 @@
+//! Address of a Syncthing GUI: host, port and whether it uses TLS.
+//! Hosts are kept without brackets (`::1`) and only put into brackets for URIs (`[::1]:8384`).
+
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Host of the GUI if not set, Syncthing's default.
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GuiAddress {
+    /// Host name or IP address the GUI listens on.
+    pub host: String,
+    pub port: u32,
+    /// Whether the GUI uses HTTPS, `None` if not known.
+    pub tls: Option<bool>,
+}
+
+impl GuiAddress {
+    /// Parses an address as in Syncthing's `config.xml`, e.g. `127.0.0.1:8384`, `[::1]:8384`,
+    /// `0.0.0.0:8384` or `:8384`. A scheme (`https://...`) sets `tls`. Unix sockets are not
+    /// supported.
+    pub fn parse(address: &str, tls: Option<bool>) -> Option<Self> {
+        let address = address.trim();
+        let (address, tls) = match (
+            address.strip_prefix("https://"),
+            address.strip_prefix("http://"),
+        ) {
+            (Some(address), _) => (address, Some(true)),
+            (_, Some(address)) => (address, Some(false)),
+            _ => (address, tls),
+        };
+        let (host, port) = address.trim_end_matches('/').rsplit_once(':')?;
+        let host = match host.strip_prefix('[') {
+            Some(host) => host.strip_suffix(']')?,
+            // An IPv6 address without brackets, the port can not be told apart.
+            None if host.contains(':') => return None,
+            None => host,
+        };
+        let port = port
+            .parse()
+            .ok()
+            .filter(|port| (1..=65535).contains(port))?;
+        Some(Self {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+
+    /// `host:port` to connect to, see [`authority`].
+    pub fn authority(&self) -> String {
+        authority(&self.host, self.port)
+    }
+}
+
+/// The host to connect to for a GUI listening on `host`: Unspecified addresses (`0.0.0.0`, `::`
+/// or none) are reached via loopback.
+pub fn connect_host(host: &str) -> String {
+    let host = strip_brackets(host);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.to_string(),
+        Ok(IpAddr::V6(ip)) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.to_string(),
+        _ if host.is_empty() => DEFAULT_HOST.to_string(),
+        _ => host.to_string(),
+    }
+}
+
+/// `host:port` to connect to a GUI listening on `host`, with IPv6 addresses in brackets.
+pub fn authority(host: &str, port: u32) -> String {
+    let host = connect_host(host);
+    match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    }
+}
+
+/// Whether `host` can be used as host of a GUI: an IP address (IPv6 with or without brackets)
+/// or a host name.
+pub fn is_valid_host(host: &str) -> bool {
+    let host = strip_brackets(host);
+    host.parse::<IpAddr>().is_ok()
+        || (!host.is_empty()
+            && host.len() <= 253
+            && host.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            }))
+}
+
+fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
@@ -0,0 +1,131 @@
+//! Protection against DNS rebinding: A web page could point its own host name at 127.0.0.1 and
+//! then talk to the watchdog as "same origin", with the Syncthing credentials added by the proxy.
+//! So only requests for the loopback names of the watchdog (`127.0.0.1`, `localhost`, `[::1]`
+//! and the address it listens on, with its port) and the hosts in the setting `allowed_hosts`
+//! are handled.
+
+use hyper::http::uri::Authority;
+use std::net::{IpAddr, SocketAddr};
+
+/// Host names the watchdog can always be reached with, on the port it listens on.
+const LOOPBACK_HOSTS: &[&str] = &["127.0.0.1", "localhost", "[::1]"];
+
+/// Whether `host` (the `Host` header) is one the watchdog listening on `local` may be requested
+/// with. `allowed_hosts` are additional hosts, with or without port.
+pub fn is_allowed(host: Option<&str>, local: SocketAddr, allowed_hosts: &[String]) -> bool {
+    let Some(host) = host.and_then(|host| host.parse::<Authority>().ok()) else {
+        return false;
+    };
+    let port = host.port_u16().unwrap_or(80);
+    let name = host.host();
+    let local_name = match local.ip() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+    if port == local.port()
+        && LOOPBACK_HOSTS
+            .iter()
+            .copied()
+            .chain([local_name.as_str()])
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    {
+        return true;
+    }
+    allowed_hosts
+        .iter()
+        .filter_map(|allowed| allowed.parse::<Authority>().ok())
+        .any(|allowed| {
+            allowed.host().eq_ignore_ascii_case(name)
+                && allowed.port_u16().unwrap_or(local.port()) == port
+        })
+}
+
+/// Whether `host` can be used in `allowed_hosts`: A host name or IP address (IPv6 in brackets),
+/// optionally with port.
+pub fn is_valid_allowed_host(host: &str) -> bool {
+    host.parse::<Authority>().is_ok_and(|authority| {
+        !authority.host().is_empty()
+            && !host.contains('@')
+            && (authority.port_u16().is_some() || authority.as_str() == authority.host())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: SocketAddr = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 58384);
+
+    fn allowed(host: &str, allowed_hosts: &[&str]) -> bool {
+        let allowed_hosts: Vec<_> = allowed_hosts.iter().map(|h| h.to_string()).collect();
+        is_allowed(Some(host), LOCAL, &allowed_hosts)
+    }
+
+    #[test]
+    fn allows_loopback_names_with_port() {
+        assert!(allowed("127.0.0.1:58384", &[]));
+        assert!(allowed("localhost:58384", &[]));
+        assert!(allowed("LocalHost:58384", &[]));
+        assert!(allowed("[::1]:58384", &[]));
+    }
+
+    #[test]
+    fn rejects_other_ports() {
+        // Without port, the host is on port 80.
+        assert!(!allowed("127.0.0.1", &[]));
+        assert!(!allowed("localhost", &[]));
+        assert!(!allowed("localhost:80", &[]));
+        assert!(!allowed("[::1]:8384", &[]));
+    }
+
+    #[test]
+    fn rejects_other_hosts() {
+        assert!(!allowed("attacker.example:58384", &[]));
+        assert!(!allowed("127.0.0.2:58384", &[]));
+        assert!(!allowed("::1:58384", &[]));
+        assert!(!allowed("", &[]));
+        assert!(!is_allowed(None, LOCAL, &[]));
+    }
+
+    #[test]
+    fn allows_listen_address() {
+        let local = "127.0.0.2:8000".parse().unwrap();
+        assert!(is_allowed(Some("127.0.0.2:8000"), local, &[]));
+        assert!(is_allowed(Some("localhost:8000"), local, &[]));
+        assert!(!is_allowed(Some("127.0.0.2:58384"), local, &[]));
+
+        let local = "[::1]:8000".parse().unwrap();
+        assert!(is_allowed(Some("[::1]:8000"), local, &[]));
+    }
+
+    #[test]
+    fn allows_allowed_hosts() {
+        // Without port, only on the watchdog's port.
+        let allowed_hosts = &["steamdeck.local"];
+        assert!(allowed("steamdeck.local:58384", allowed_hosts));
+        assert!(allowed("SteamDeck.local:58384", allowed_hosts));
+        assert!(!allowed("steamdeck.local", allowed_hosts));
+        assert!(!allowed("steamdeck.local:8080", allowed_hosts));
+        assert!(!allowed("other.local:58384", allowed_hosts));
+
+        // With port, only on that port.
+        let allowed_hosts = &["steamdeck.local:80", "[fd00::1]:8080"];
+        assert!(allowed("steamdeck.local", allowed_hosts));
+        assert!(allowed("steamdeck.local:80", allowed_hosts));
+        assert!(!allowed("steamdeck.local:58384", allowed_hosts));
+        assert!(allowed("[fd00::1]:8080", allowed_hosts));
+        assert!(!allowed("[fd00::1]:58384", allowed_hosts));
+    }
+
+    #[test]
+    fn validates_allowed_hosts() {
+        assert!(is_valid_allowed_host("steamdeck.local"));
+        assert!(is_valid_allowed_host("steamdeck.local:58384"));
+        assert!(is_valid_allowed_host("192.168.1.2"));
+        assert!(is_valid_allowed_host("[fd00::1]:8080"));
+        assert!(!is_valid_allowed_host(""));
+        assert!(!is_valid_allowed_host("user@steamdeck.local"));
+        assert!(!is_valid_allowed_host("http://steamdeck.local/"));
+        assert!(!is_valid_allowed_host("steamdeck.local:port"));
+    }
+}
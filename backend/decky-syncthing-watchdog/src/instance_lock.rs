@@ -0,0 +1,199 @@
+//! Makes sure only one watchdog runs at a time.
+//! The PID file is locked with an advisory lock (`flock`) for the whole lifetime of the process.
+//! The kernel releases the lock when the process exits, even if it crashes, so a stale PID file
+//! never blocks a new watchdog.
+//!
+//! The PID file contains the PID on the first line, followed by `key=value` lines with the
+//! version and the start time (Unix timestamp) of the owning watchdog.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions, TryLockError, metadata, remove_file};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InstanceLockError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("another watchdog is already running ({0})")]
+    AlreadyRunning(LockOwner),
+}
+
+/// Information about the watchdog holding the lock, as read from the PID file.
+/// Fields are `None` if the owner has not written them (yet).
+#[derive(Debug, Default)]
+pub struct LockOwner {
+    pub pid: Option<u32>,
+    pub version: Option<String>,
+    pub started: Option<u64>,
+}
+
+impl LockOwner {
+    fn parse(content: &str) -> Self {
+        let mut lines = content.lines();
+        let mut slf = Self {
+            pid: lines.next().and_then(|l| l.trim().parse().ok()),
+            ..Self::default()
+        };
+        for line in lines {
+            match line.split_once('=') {
+                Some(("version", v)) => slf.version = Some(v.trim().to_string()),
+                Some(("started", v)) => slf.started = v.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        slf
+    }
+}
+
+impl Display for LockOwner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fn or_unknown(v: Option<impl ToString>) -> String {
+            v.map(|v| v.to_string())
+                .unwrap_or_else(|| "unknown".to_string())
+        }
+        write!(
+            f,
+            "pid: {}, version: {}, started: {}",
+            or_unknown(self.pid),
+            or_unknown(self.version.as_ref()),
+            or_unknown(self.started)
+        )
+    }
+}
+
+/// The lock on the PID file. The lock is released when this is dropped.
+pub struct InstanceLock {
+    path: PathBuf,
+    _file: File,
+    previous_owner: Option<LockOwner>,
+}
+
+impl InstanceLock {
+    /// Tries to lock the PID file without blocking and writes the information about this process
+    /// to it.
+    pub fn acquire(pid_file_path: &Path) -> Result<Self, InstanceLockError> {
+        let mut file = loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(pid_file_path)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    return Err(InstanceLockError::AlreadyRunning(LockOwner::parse(
+                        &content,
+                    )));
+                }
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+            // The previous owner removes the PID file while still holding the lock (see
+            // `release`), so the file locked here may not be at the path anymore. Another
+            // watchdog could then create and lock a new one, so try again with that.
+            if is_same_file(&file, pid_file_path)? {
+                break file;
+            }
+        };
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let previous_owner = Some(LockOwner::parse(&content))
+            .filter(|owner| owner.pid.is_some_and(|pid| pid != process::id()));
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(
+            file,
+            "{}\nversion={}\nstarted={}\n",
+            process::id(),
+            env!("CARGO_PKG_VERSION"),
+            started
+        )?;
+        file.sync_all()?;
+        Ok(Self {
+            path: pid_file_path.to_path_buf(),
+            _file: file,
+            previous_owner,
+        })
+    }
+
+    /// The watchdog the PID file named before it was locked, if any. It did not hold the lock,
+    /// so it either exited without removing the file or is of a version from before the lock
+    /// was introduced, which only wrote its PID.
+    pub fn previous_owner(&self) -> Option<&LockOwner> {
+        self.previous_owner.as_ref()
+    }
+
+    /// Removes the PID file and releases the lock.
+    pub fn release(self) -> io::Result<()> {
+        remove_file(&self.path)
+    }
+}
+
+/// Whether `file` is the file currently at `path` (and not one removed or replaced since it was
+/// opened).
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    let opened = file.metadata()?;
+    match metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::test_support::TempDir;
+    use std::fs::{read_to_string, write};
+
+    #[test]
+    fn locks_pid_file() {
+        let dir = TempDir::new("lock");
+        let path = dir.path().join("watchdog.pid");
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert!(lock.previous_owner().is_none());
+        let owner = LockOwner::parse(&read_to_string(&path).unwrap());
+        assert_eq!(owner.pid, Some(process::id()));
+        assert_eq!(owner.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert!(owner.started.is_some());
+
+        match InstanceLock::acquire(&path) {
+            Err(InstanceLockError::AlreadyRunning(owner)) => {
+                assert_eq!(owner.pid, Some(process::id()))
+            }
+            _ => panic!("locked twice"),
+        }
+
+        lock.release().unwrap();
+        assert!(!path.exists());
+        InstanceLock::acquire(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_unlocked_previous_owner() {
+        let dir = TempDir::new("lock-previous");
+        let path = dir.path().join("watchdog.pid");
+        // As written by watchdogs from before the lock.
+        write(&path, "4194305").unwrap();
+        let lock = InstanceLock::acquire(&path).unwrap();
+        let previous = lock.previous_owner().unwrap();
+        assert_eq!(previous.pid, Some(4194305));
+        assert_eq!(previous.version, None);
+        let owner = LockOwner::parse(&read_to_string(&path).unwrap());
+        assert_eq!(owner.pid, Some(process::id()));
+    }
+}
@@ -2,10 +2,31 @@
 //! For tests and examples.
 
 mod api;
+mod auth_token;
+mod backend;
 mod checks;
+mod cli;
+mod cors;
+mod crash_report;
+mod credentials;
+mod endpoint;
+mod gui_address;
+mod host_header;
+mod instance_lock;
+mod logging;
 mod panic_util;
 mod proxy;
+mod remote;
 pub mod service;
 mod settings;
+mod settings_overrides;
+mod settings_validation;
+mod settings_watch;
+mod shutdown;
+mod supervisor;
+mod syncthing_config;
+mod systemd_notify;
+mod tls;
 mod util;
+mod version;
 mod watch_gamescope;
@@ -0,0 +1,321 @@
+//! Logging of the watchdog into rolling log files.
+//! The log level can be changed at runtime (via the settings or the API) and the most recent
+//! entries can be read back from the log files, see [`tail`].
+//! Entries are written as text or as JSON lines (see [`LogFormat`]). Key-value pairs attached to
+//! a record (`info!(unit; "...")`) are only included in the JSON output.
+//! Known secrets are replaced in all entries, see [`crate::credentials::scrub`].
+
+use crate::credentials::scrub;
+use crate::settings::Settings;
+use chrono::{Local, SecondsFormat};
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Record, info};
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::writer::simple::SimpleWriter;
+use log4rs::encode::{self, Encode};
+use log4rs::filter::threshold::ThresholdFilter;
+use log4rs::{Config, Handle};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, json};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::{fs, io};
+use tokio::fs::read;
+
+const LOGFILE_WATCHDOG_ROLLING: &str = "watchdog.{}.log";
+const LOGFILE_WATCHDOG: &str = "watchdog.log";
+/// Number of rolled log files to keep.
+const WINDOW_SIZE: u32 = 3;
+/// Max. size of a log file before it is rolled.
+const SIZE_LIMIT: u64 = 512 * 1024;
+
+/// Format of the log files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `<timestamp> <level>::<message>`
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LogConfig {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+struct Logging {
+    handle: Handle,
+    dir: PathBuf,
+    config: Mutex<LogConfig>,
+}
+
+static LOGGING: OnceLock<Logging> = OnceLock::new();
+
+/// The log level used if none is configured.
+pub fn default_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/// Sets up logging with the default level, until the settings are applied. `format` is the
+/// format given on the command line, which is also an override of the settings.
+pub fn setup_self_logging(dir: &Path, format: Option<LogFormat>) {
+    let config = LogConfig {
+        level: default_level(),
+        format: format.unwrap_or_default(),
+    };
+    let handle = log4rs::init_config(build_config(dir, config).unwrap()).unwrap();
+    LOGGING
+        .set(Logging {
+            handle,
+            dir: dir.to_path_buf(),
+            config: Mutex::new(config),
+        })
+        .ok();
+}
+
+fn build_config(dir: &Path, config: LogConfig) -> Result<Config, anyhow::Error> {
+    let threshold = config.level;
+    let encoder: Box<dyn Encode> = match config.format {
+        LogFormat::Text => Box::new(PatternEncoder::new("{d} {l}::{m}{n}")),
+        LogFormat::Json => Box::new(JsonLineEncoder),
+    };
+    let encoder = Box::new(ScrubbingEncoder(encoder));
+    let fixed_window_roller = FixedWindowRoller::builder().build(
+        dir.join(LOGFILE_WATCHDOG_ROLLING).to_str().unwrap(),
+        WINDOW_SIZE,
+    )?;
+    let size_trigger = SizeTrigger::new(SIZE_LIMIT);
+    let compound_policy =
+        CompoundPolicy::new(Box::new(size_trigger), Box::new(fixed_window_roller));
+
+    Ok(Config::builder()
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(threshold)))
+                .build(
+                    "logfile",
+                    Box::new(
+                        RollingFileAppender::builder()
+                            .encoder(encoder)
+                            .build(dir.join(LOGFILE_WATCHDOG), Box::new(compound_policy))?,
+                    ),
+                ),
+        )
+        .build(Root::builder().appender("logfile").build(threshold))?)
+}
+
+/// Returns the current log level.
+pub fn level() -> LevelFilter {
+    match LOGGING.get() {
+        Some(logging) => logging.config.lock().unwrap().level,
+        None => default_level(),
+    }
+}
+
+/// Changes the log level. Does nothing if logging is not set up.
+pub fn set_level(level: LevelFilter) -> Result<(), anyhow::Error> {
+    configure(|config| config.level = level)
+}
+
+/// Applies the log level and format from the settings, or the defaults if they are not set.
+pub fn apply_settings(settings: &Settings) -> Result<(), anyhow::Error> {
+    configure(|config| {
+        config.level = settings.log_level.unwrap_or_else(default_level);
+        config.format = settings.log_format.unwrap_or_default();
+    })
+}
+
+/// Reconfigures logging if `update` changes the config. Does nothing if logging is not set up.
+fn configure(update: impl FnOnce(&mut LogConfig)) -> Result<(), anyhow::Error> {
+    let Some(logging) = LOGGING.get() else {
+        return Ok(());
+    };
+    let mut current = logging.config.lock().unwrap();
+    let mut config = *current;
+    update(&mut config);
+    if *current != config {
+        logging
+            .handle
+            .set_config(build_config(&logging.dir, config)?);
+        *current = config;
+        info!(
+            "log level set to {}, format to {:?}.",
+            config.level, config.format
+        );
+    }
+    Ok(())
+}
+
+/// Replaces known secrets in the output of another encoder.
+#[derive(Debug)]
+struct ScrubbingEncoder(Box<dyn Encode>);
+
+impl Encode for ScrubbingEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        let mut buffer = SimpleWriter(Vec::new());
+        self.0.encode(&mut buffer, record)?;
+        w.write_all(scrub(&String::from_utf8_lossy(&buffer.0)).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Writes each record as a JSON object on a single line, including the key-value pairs
+/// attached to it.
+#[derive(Debug)]
+struct JsonLineEncoder;
+
+impl Encode for JsonLineEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        let mut fields = FieldCollector(Map::new());
+        record
+            .key_values()
+            .visit(&mut fields)
+            .map_err(|e| anyhow::anyhow!("failed to collect log fields: {e}"))?;
+        let line = JsonLine {
+            timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+            level: record.level(),
+            module: record.module_path(),
+            message: record.args().to_string(),
+            fields: fields.0,
+        };
+        serde_json::to_writer(&mut *w, &line)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: Level,
+    module: Option<&'a str>,
+    message: String,
+    fields: Map<String, serde_json::Value>,
+}
+
+struct FieldCollector(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(v) = value.to_bool() {
+            json!(v)
+        } else if let Some(v) = value.to_i64() {
+            json!(v)
+        } else if let Some(v) = value.to_u64() {
+            json!(v)
+        } else if let Some(v) = value.to_f64() {
+            json!(v)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub timestamp: String,
+    pub level: Level,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, serde_json::Value>,
+}
+
+impl LogEntry {
+    /// Parses the first line of a log entry, written either by [`JsonLineEncoder`] or by the
+    /// `{d} {l}::{m}{n}` pattern.
+    fn parse(line: &str) -> Option<Self> {
+        if line.starts_with('{') {
+            return serde_json::from_str(line).ok();
+        }
+        let (timestamp, rest) = line.split_once(' ')?;
+        if !timestamp.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        let (level, message) = rest.split_once("::")?;
+        Some(Self {
+            timestamp: timestamp.to_string(),
+            level: Level::from_str(level).ok()?,
+            module: None,
+            message: message.to_string(),
+            fields: Map::new(),
+        })
+    }
+}
+
+/// Returns the paths of the log files, oldest first.
+fn log_files(dir: &Path) -> impl DoubleEndedIterator<Item = PathBuf> {
+    // The roller moves `watchdog.log` to `watchdog.0.log`, so higher numbers are older.
+    (0..WINDOW_SIZE)
+        .rev()
+        .map(|i| dir.join(LOGFILE_WATCHDOG_ROLLING.replace("{}", &i.to_string())))
+        .chain([dir.join(LOGFILE_WATCHDOG)])
+}
+
+/// Returns the last `lines` lines of the log files as they are, oldest first. Blocking, for
+/// use where no runtime is available (e.g. in the panic hook).
+pub fn last_lines(lines: usize) -> Vec<String> {
+    let Some(logging) = LOGGING.get() else {
+        return Vec::new();
+    };
+    let mut result = Vec::new();
+    for file in log_files(&logging.dir).rev() {
+        let Ok(content) = fs::read(file) else {
+            continue;
+        };
+        let content = String::from_utf8_lossy(&content);
+        let remaining = lines - result.len();
+        result.extend(content.lines().rev().take(remaining).map(str::to_string));
+        if result.len() >= lines {
+            break;
+        }
+    }
+    result.reverse();
+    result
+}
+
+/// Returns the last `lines` log entries with at least the severity `level`, oldest first.
+/// Reads the rolled log files as well.
+pub async fn tail(lines: usize, level: LevelFilter) -> io::Result<Vec<LogEntry>> {
+    let Some(logging) = LOGGING.get() else {
+        return Ok(Vec::new());
+    };
+    let mut entries: Vec<LogEntry> = Vec::new();
+    for file in log_files(&logging.dir) {
+        let content = match read(file).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for line in String::from_utf8_lossy(&content).lines() {
+            match (LogEntry::parse(line), entries.last_mut()) {
+                (Some(entry), _) => entries.push(entry),
+                // Continuation of a multi-line message.
+                (None, Some(last)) => {
+                    last.message.push('\n');
+                    last.message.push_str(line);
+                }
+                (None, None) => {}
+            }
+        }
+    }
+    entries.retain(|entry| entry.level <= level);
+    let skip = entries.len().saturating_sub(lines);
+    Ok(entries.split_off(skip))
+}
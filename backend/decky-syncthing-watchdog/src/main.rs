@@ -1,193 +1,568 @@
 mod api;
+mod auth_token;
+mod backend;
 mod checks;
+mod cli;
+mod cors;
+mod crash_report;
+mod credentials;
+mod endpoint;
+mod gui_address;
+mod host_header;
+mod instance_lock;
+mod logging;
 mod panic_util;
 mod proxy;
+mod remote;
 mod service;
 mod settings;
+mod settings_overrides;
+mod settings_validation;
+mod settings_watch;
+mod shutdown;
+mod supervisor;
+mod syncthing_config;
+mod systemd_notify;
+mod tls;
 mod util;
+mod version;
 mod watch_gamescope;
 
-use crate::api::handle_api;
+use crate::api::{API_PREFIX, BIND_ADDR, SHUTDOWN_ROUTE, handle_api, make_json_error_response};
+use crate::auth_token::{AuthToken, TOKEN_HEADER};
+use crate::cli::{
+    Cli, ClientArgs, Command, RunArgs, print_credentials, request, run_client_command,
+};
+use crate::cors::Cors;
+use crate::endpoint::Endpoint;
+use crate::instance_lock::{InstanceLock, InstanceLockError};
+use crate::logging::setup_self_logging;
 use crate::panic_util::register_panic_hook;
 use crate::proxy::handle_proxy;
 use crate::service::init_service;
 use crate::settings::SettingsProvider;
+use crate::settings_overrides::Overrides;
+use crate::shutdown::Signals;
+use crate::supervisor::{ExitCause, SubsystemError, supervise};
+use crate::systemd_notify::Systemd;
+use crate::version::mark_started;
 use crate::watch_gamescope::GamescopeWatchdog;
-use hyper::header::{ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_ORIGIN};
+use clap::Parser;
+use hyper::header::HOST;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use log::{LevelFilter, debug, error, info, warn};
-use log4rs::Config;
-use log4rs::append::rolling_file::RollingFileAppender;
-use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
-use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
-use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
-use log4rs::config::{Appender, Root};
-use log4rs::encode::pattern::PatternEncoder;
-use log4rs::filter::threshold::ThresholdFilter;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info, warn};
+use semver::Version;
 use std::convert::Infallible;
-use std::env::args;
-use std::fs::{read_to_string, write};
-use std::net::IpAddr;
+use std::env;
+use std::fs::{
+    DirBuilder, Permissions, remove_dir_all, remove_file, rename, set_permissions, symlink_metadata,
+};
+use std::future::pending;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
 use std::ops::Deref;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process;
 use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
-use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
-use tokio::time::sleep;
+use sysinfo::{Pid, PidExt, ProcessExt, Signal, System, SystemExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::time::{Instant, sleep, timeout};
 
-const LOGFILE_WATCHDOG_ROLLING: &str = "watchdog.{}.log";
-const LOGFILE_WATCHDOG: &str = "watchdog.log";
-const BIND_ADDR: &str = "127.0.0.1:58384";
+/// How long in-flight requests may take to finish when shutting down.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+/// How long to wait for a watchdog of an older version to shut down when replacing it.
+const HANDOVER_TIMEOUT: Duration = SHUTDOWN_DEADLINE.saturating_add(Duration::from_secs(5));
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> ExitCode {
-    let mut args_iter = args();
-    let settings_path = PathBuf::from(args_iter.nth(1).unwrap());
-    let watchdog_pid_path = PathBuf::from(args_iter.next().unwrap());
-    let watchdog_log_dir_path = PathBuf::from(args_iter.next().unwrap());
-
-    if other_process_already_running(&watchdog_pid_path) {
-        debug!("other watchdog was already running. Not starting.");
-        return ExitCode::SUCCESS;
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Run(args) => run(args).await,
+        Command::Credentials { settings } => print_credentials(&settings).await,
+        command => run_client_command(cli.client, command).await,
     }
+}
 
-    update_pid_file(&watchdog_pid_path);
-    // To avoid race conditions on start, we check again.
-    sleep(Duration::from_millis(150)).await;
-    if other_process_already_running(&watchdog_pid_path) {
-        debug!("other watchdog was already running. Not starting.");
-        return ExitCode::SUCCESS;
-    }
+async fn run(args: RunArgs) -> ExitCode {
+    let RunArgs {
+        settings: settings_path,
+        pid_file: watchdog_pid_path,
+        log_dir: watchdog_log_dir_path,
+        log_format,
+        listen,
+        control_socket,
+        set,
+    } = args;
+    let runtime_dir = watchdog_pid_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    mark_started();
+    let instance_lock = match acquire_instance_lock(&watchdog_pid_path, &runtime_dir).await {
+        Ok(lock) => lock,
+        Err(err @ InstanceLockError::AlreadyRunning(_)) => {
+            eprintln!("{err}. Not starting.");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!(
+                "failed to lock PID file {}: {err}",
+                watchdog_pid_path.display()
+            );
+            return ExitCause::PidFile.into();
+        }
+    };
 
-    setup_self_logging(&watchdog_log_dir_path);
-    register_panic_hook(&watchdog_log_dir_path);
+    setup_self_logging(&watchdog_log_dir_path, log_format);
+    register_panic_hook(&watchdog_log_dir_path, &settings_path);
     info!("started {}.", env!("CARGO_PKG_VERSION"));
     debug!("debug logging enabled.");
 
-    let settings = SettingsProvider::new(settings_path).await.unwrap();
+    let systemd = Systemd::from_env();
+    let overrides = match Overrides::new(env::vars_os(), &set)
+        .and_then(|o| o.with_cli("listen_address", listen))
+        .and_then(|o| o.with_cli("control_socket", control_socket))
+        .and_then(|o| o.with_cli("log_format", log_format))
+    {
+        Ok(overrides) => overrides,
+        Err(err) => {
+            error!("invalid settings override: {err}");
+            return exit(instance_lock, &runtime_dir, ExitCause::Settings.into());
+        }
+    };
+    for (field, source) in overrides.iter() {
+        info!("setting {field} overridden by {source}.");
+    }
+    let settings = match SettingsProvider::new(settings_path, overrides).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("failed to load settings: {err:?}");
+            return exit(instance_lock, &runtime_dir, ExitCause::Settings.into());
+        }
+    };
+    if let Err(err) = logging::apply_settings(&*settings.settings().await) {
+        warn!("failed to set log level: {err:?}");
+    }
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("failed to install signal handlers: {err:?}");
+            return exit(instance_lock, &runtime_dir, ExitCause::Signals.into());
+        }
+    };
 
-    let mut gamescope_watchdog = GamescopeWatchdog::new(settings.clone());
     if let Err(e) = init_service(&*settings.settings().await).await {
         warn!("failed to init service: {e:?}");
     }
 
+    let mut endpoint = Endpoint::resolve(&*settings.settings().await);
+    let listen = match systemd_notify::take_activated_listener() {
+        Some((listener, addr)) => {
+            endpoint.http = addr;
+            Listen::Activated(Arc::new(listener))
+        }
+        None => Listen::Bind(endpoint.http),
+    };
+    info!("listening on {}.", endpoint.http);
+    let token = match AuthToken::generate() {
+        Ok(token) => Arc::new(token),
+        Err(err) => {
+            error!("failed to generate the control API token: {err:?}");
+            return exit(instance_lock, &runtime_dir, ExitCause::Token.into());
+        }
+    };
+    if let Err(err) = token.write(&runtime_dir) {
+        warn!(
+            "failed to write token file, the control API can only be used via the control socket: {err:?}"
+        );
+    }
+    if let Err(err) = endpoint.write(&runtime_dir) {
+        warn!("failed to write endpoint file: {err:?}");
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = supervise("server", ExitCause::Server, || {
+        serve(
+            listen.clone(),
+            settings.clone(),
+            token.clone(),
+            systemd.clone(),
+            shutdown_rx.clone(),
+        )
+    });
+    tokio::pin!(server);
+    let control = async {
+        match &endpoint.control_socket {
+            Some(path) => {
+                info!("serving control API on {}.", path.display());
+                supervise("control_socket", ExitCause::ControlSocket, || {
+                    serve_control_socket(path.clone(), settings.clone(), shutdown_rx.clone())
+                })
+                .await
+            }
+            None => pending().await,
+        }
+    };
+    tokio::pin!(control);
+    let watcher = supervise("watcher", ExitCause::Watcher, || {
+        let settings = settings.clone();
+        async move { match GamescopeWatchdog::new(settings).background_watch().await {} }
+    });
+    let settings_watcher = supervise("settings_watcher", ExitCause::SettingsWatcher, || {
+        settings_watch::watch(settings.clone())
+    });
+
+    let reason = tokio::select! {
+        r = &mut server => return exit_unrecoverable(instance_lock, &runtime_dir, "server", ExitCause::Server, r),
+        r = &mut control => return exit_unrecoverable(instance_lock, &runtime_dir, "control socket", ExitCause::ControlSocket, r),
+        r = watcher => return exit_unrecoverable(instance_lock, &runtime_dir, "watcher", ExitCause::Watcher, r),
+        r = settings_watcher => return exit_unrecoverable(instance_lock, &runtime_dir, "settings watcher", ExitCause::SettingsWatcher, r),
+        r = systemd_notify::keep_alive(&systemd, &settings) => match r {},
+        reason = signals.wait_for_shutdown(&settings) => reason,
+    };
+
+    info!("shutting down: received {reason}.");
+    systemd_notify::stopping(&systemd);
+    shutdown_tx.send(true).ok();
+    let drain = async {
+        server.await.ok();
+        if endpoint.control_socket.is_some() {
+            control.await.ok();
+        }
+    };
+    match timeout(SHUTDOWN_DEADLINE, drain).await {
+        Ok(()) => debug!("all connections closed."),
+        Err(_) => warn!("connections still open after {SHUTDOWN_DEADLINE:?}, closing them."),
+    }
+    exit(instance_lock, &runtime_dir, ExitCode::SUCCESS)
+}
+
+/// Locks the PID file. If it is held by a watchdog of an older version (e.g. after a plugin
+/// update), that watchdog is asked to shut down and the lock is taken over. Newer watchdogs and
+/// ones of the same version are left running. Watchdogs from before the lock are stopped, see
+/// [`stop_unlocked_watchdog`].
+async fn acquire_instance_lock(
+    pid_path: &Path,
+    runtime_dir: &Path,
+) -> Result<InstanceLock, InstanceLockError> {
+    let owner = match InstanceLock::acquire(pid_path) {
+        Err(InstanceLockError::AlreadyRunning(owner))
+            if owner.version.as_deref().is_some_and(is_older_version) =>
+        {
+            owner
+        }
+        Ok(lock) => {
+            if let Some(pid) = lock.previous_owner().and_then(|owner| owner.pid) {
+                stop_unlocked_watchdog(pid).await;
+            }
+            return Ok(lock);
+        }
+        r => return r,
+    };
+    eprintln!("replacing running watchdog ({owner}).");
+    let client = ClientArgs::for_runtime_dir(runtime_dir);
+    match request(&client, Method::POST, SHUTDOWN_ROUTE).await {
+        Ok((true, _)) => {}
+        Ok((false, body)) => {
+            eprintln!("running watchdog refused to shut down: {body}");
+            return Err(InstanceLockError::AlreadyRunning(owner));
+        }
+        Err(err) => {
+            eprintln!("failed to ask running watchdog to shut down: {err}");
+            return Err(InstanceLockError::AlreadyRunning(owner));
+        }
+    }
+    let deadline = Instant::now() + HANDOVER_TIMEOUT;
+    loop {
+        sleep(Duration::from_millis(200)).await;
+        match InstanceLock::acquire(pid_path) {
+            Err(InstanceLockError::AlreadyRunning(_)) if Instant::now() < deadline => {}
+            r => return r,
+        }
+    }
+}
+
+/// Stops the watchdog with `pid` if it is still running: One of a version from before the PID
+/// file was locked, which only wrote its PID. It can not be asked to shut down via the API, so
+/// it is sent `SIGTERM`. Waits until the address these versions listened on is released.
+async fn stop_unlocked_watchdog(pid: u32) {
+    let mut system = System::new();
+    let Some(process) = GamescopeWatchdog::does_process_exist_and_match_cond(
+        &mut system,
+        Pid::from_u32(pid),
+        // The binary may have been replaced meanwhile (`/proc/<pid>/exe` ends in ` (deleted)`).
+        |process| {
+            process
+                .exe()
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(env!("CARGO_BIN_NAME")))
+        },
+    ) else {
+        return;
+    };
+    eprintln!("stopping running watchdog of an older version (pid: {pid}).");
+    if process.kill_with(Signal::Term) != Some(true) {
+        eprintln!("failed to stop running watchdog.");
+        return;
+    }
+    let deadline = Instant::now() + HANDOVER_TIMEOUT;
+    while TcpListener::bind(BIND_ADDR).is_err() {
+        if Instant::now() >= deadline {
+            eprintln!("running watchdog did not release {BIND_ADDR}.");
+            return;
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// Whether `version` (of another watchdog) is lower than the version of this one. Versions that
+/// can not be compared are not.
+fn is_older_version(version: &str) -> bool {
+    match (
+        Version::parse(version),
+        Version::parse(env!("CARGO_PKG_VERSION")),
+    ) {
+        (Ok(other), Ok(own)) => other < own,
+        _ => false,
+    }
+}
+
+/// Where the HTTP server listens.
+#[derive(Clone)]
+enum Listen {
+    /// Bind to this address.
+    Bind(SocketAddr),
+    /// Use the listening socket passed by systemd.
+    Activated(Arc<TcpListener>),
+}
+
+/// Runs the HTTP server until a shutdown is requested via `shutdown`.
+async fn serve(
+    listen: Listen,
+    settings: Arc<SettingsProvider>,
+    token: Arc<AuthToken>,
+    systemd: Systemd,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), SubsystemError> {
     let make_svc = make_service_fn(|conn: &AddrStream| {
         let settings = settings.clone();
+        let token = token.clone();
         let remote_addr = conn.remote_addr().ip();
+        let local_addr = conn.local_addr();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle(remote_addr, req, settings.clone())
+                handle(
+                    remote_addr,
+                    local_addr,
+                    req,
+                    settings.clone(),
+                    token.clone(),
+                )
             }))
         }
     });
 
-    let server = Server::bind(&BIND_ADDR.parse().unwrap()).serve(make_svc);
-    let watcher = gamescope_watchdog.background_watch();
+    let builder = match listen {
+        Listen::Bind(addr) => Server::try_bind(&addr).map_err(anyhow::Error::from),
+        Listen::Activated(listener) => listener
+            .try_clone()
+            .map_err(anyhow::Error::from)
+            .and_then(|listener| Ok(Server::from_tcp(listener)?)),
+    }
+    .map_err(|e| SubsystemError::new(ExitCause::ServerBind, e))?;
+    systemd_notify::ready(&systemd, "Watchdog ready.");
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            shutdown.wait_for(|v| *v).await.ok();
+        })
+        .await
+        .map_err(|e| SubsystemError::new(ExitCause::Server, e))
+}
+
+/// Runs the control API on a Unix domain socket until a shutdown is requested via `shutdown`.
+/// Only the owner of the watchdog process may connect to the socket.
+async fn serve_control_socket(
+    path: PathBuf,
+    settings: Arc<SettingsProvider>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), SubsystemError> {
+    let listener =
+        bind_control_socket(&path).map_err(|e| SubsystemError::new(ExitCause::ControlSocket, e))?;
+
+    let make_svc = make_service_fn(|_conn: &UnixStream| {
+        let settings = settings.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_control(req, settings.clone()))) }
+    });
+
+    let result = Server::builder(hyper::server::accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|r| Some(r.map(|(stream, _)| stream)))
+    }))
+    .serve(make_svc)
+    .with_graceful_shutdown(async move {
+        shutdown.wait_for(|v| *v).await.ok();
+    })
+    .await
+    .map_err(|e| SubsystemError::new(ExitCause::ControlSocket, e));
+    remove_file(&path).ok();
+    result
+}
 
-    let (r1, r2) = tokio::join!(server, watcher);
+/// Binds the control socket at `path`. Only a socket there is replaced, e.g. one left over from a
+/// previous run (we hold the instance lock), anything else is an error.
+/// The socket is bound in a directory only the user can access and only moved to `path` once
+/// its permissions are restricted, so others can not connect to it in between.
+fn bind_control_socket(path: &Path) -> io::Result<UnixListener> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{file_name}.tmp"));
+    if symlink_metadata(&dir).is_ok_and(|metadata| metadata.is_dir()) {
+        remove_dir_all(&dir)?;
+    }
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("socket");
+    let bind = || -> io::Result<UnixListener> {
+        let listener = UnixListener::bind(&tmp)?;
+        set_permissions(&tmp, Permissions::from_mode(0o600))?;
+        rename(&tmp, path)?;
+        Ok(listener)
+    };
+    let result = bind();
+    remove_dir_all(&dir).ok();
+    result
+}
+
+fn exit_unrecoverable(
+    instance_lock: InstanceLock,
+    runtime_dir: &Path,
+    name: &str,
+    cause: ExitCause,
+    result: Result<(), ExitCause>,
+) -> ExitCode {
+    // Before the shutdown, the subsystems only finish on their own if something went wrong.
+    let cause = match result {
+        Ok(()) => {
+            error!("{name} stopped unexpectedly.");
+            cause
+        }
+        Err(cause) => {
+            error!("{name} stopped and can not be recovered ({cause:?}).");
+            cause
+        }
+    };
+    exit(instance_lock, runtime_dir, cause.into())
+}
+
+fn exit(instance_lock: InstanceLock, runtime_dir: &Path, code: ExitCode) -> ExitCode {
+    if let Err(err) = Endpoint::remove(runtime_dir)
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        warn!("failed to remove endpoint file: {err:?}");
+    }
+    if let Err(err) = AuthToken::remove(runtime_dir)
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        warn!("failed to remove token file: {err:?}");
+    }
+    if let Err(err) = instance_lock.release() {
+        warn!("failed to remove PID file: {err:?}");
+    }
+    info!("shut down.");
+    code
+}
 
-    error!("server exited with: {r1:?} & {r2:?}");
-    ExitCode::FAILURE
+/// Handles requests on the control socket. Only the control API is available there, not
+/// the proxy.
+async fn handle_control<S>(
+    mut req: Request<Body>,
+    settings: S,
+) -> Result<Response<Body>, Infallible>
+where
+    S: Deref<Target = SettingsProvider>,
+{
+    debug!(method:% = req.method(), path = req.uri().path(); "incoming control request");
+    match handle_api(&Ipv4Addr::LOCALHOST.into(), &mut req, &settings).await {
+        Some(response) => response,
+        None => make_json_error_response("Unknown route.", StatusCode::NOT_FOUND),
+    }
 }
 
+/// Handles requests on the HTTP server (at `local_addr`). Requests for other hosts are denied,
+/// the control API needs the token there.
 async fn handle<S>(
     client_ip: IpAddr,
-    req: Request<Body>,
+    local_addr: SocketAddr,
+    mut req: Request<Body>,
     settings: S,
+    token: Arc<AuthToken>,
 ) -> Result<Response<Body>, Infallible>
 where
     S: Deref<Target = SettingsProvider>,
 {
-    debug!("incoming request");
-    let response_result = match handle_api(&client_ip, &req, &settings).await {
-        Some(v) => v,
-        None => handle_proxy(client_ip, req, &settings).await,
+    debug!(method:% = req.method(), path = req.uri().path(); "incoming request");
+    let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+    let allowed_hosts = settings.settings().await.allowed_hosts.clone();
+    if !host_header::is_allowed(
+        host,
+        local_addr,
+        allowed_hosts.as_deref().unwrap_or_default(),
+    ) {
+        warn!(
+            "Denied {} {} from {client_ip} for host {}.",
+            req.method(),
+            req.uri().path(),
+            host.unwrap_or("(none)")
+        );
+        return make_json_error_response(
+            "Host not allowed. Use 127.0.0.1 or localhost, or add the host to the setting allowed_hosts.",
+            StatusCode::MISDIRECTED_REQUEST,
+        );
+    }
+    let allowed_origin = match cors::check(&req, &settings).await {
+        Cors::Respond(response) => return Ok(response),
+        Cors::SameOrigin => None,
+        Cors::Allowed(origin) => Some(origin),
+    };
+    let is_api = req.uri().path().starts_with(API_PREFIX);
+    let response_result = if is_api && !token.verify(req.headers()) {
+        warn!(
+            "Denied {} {} from {client_ip}: missing or wrong token.",
+            req.method(),
+            req.uri().path()
+        );
+        make_json_error_response("Missing or wrong token.", StatusCode::UNAUTHORIZED)
+    } else {
+        if !is_api {
+            // Not for Syncthing.
+            req.headers_mut().remove(TOKEN_HEADER);
+        }
+        match handle_api(&client_ip, &mut req, &settings).await {
+            Some(v) => v,
+            None => handle_proxy(client_ip, req, &settings).await,
+        }
     };
     debug!("request handled");
     let mut response = response_result?;
-    // XXX: since we are only ever listening on localhost and are pretty niche,
-    //      this is probably fine enough, but really
-    //      we should eventually make this more strict.
-    let headers_mut = response.headers_mut();
-    headers_mut.insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
-    headers_mut.insert(ACCESS_CONTROL_ALLOW_HEADERS, "*".parse().unwrap());
-    Ok(response)
-}
-
-fn update_pid_file(pid_file_path: &Path) {
-    write(pid_file_path, process::id().to_string()).unwrap();
-}
-
-fn other_process_already_running(pid_file_path: &Path) -> bool {
-    if pid_file_path.exists() {
-        let pid_in_file = read_to_string(pid_file_path)
-            .unwrap()
-            .trim()
-            .parse::<u32>()
-            .unwrap_or_default();
-        if process::id() == pid_in_file {
-            // If we are ourselves in the PID file, then we continue as if no other instance
-            // was running.
-            return false;
-        }
-        let mut system = System::new();
-        let pid_in_file_as_pid = Pid::from_u32(pid_in_file);
-
-        GamescopeWatchdog::does_process_exist_and_match_cond(
-            &mut system,
-            pid_in_file_as_pid,
-            |proc| {
-                // we check if the process matches the binary name, it could be it's just another
-                // unrelated process after restart.
-                proc.exe()
-                    .to_string_lossy()
-                    .contains(env!("CARGO_BIN_NAME"))
-            },
-        )
-        .is_some()
-    } else {
-        false
+    if let Some(origin) = allowed_origin {
+        cors::add_headers(&mut response, origin);
     }
-}
-
-fn setup_self_logging(dir: &Path) {
-    let threshold = if cfg!(debug_assertions) {
-        LevelFilter::Debug
-    } else {
-        LevelFilter::Info
-    };
-
-    let window_size = 3;
-    let fixed_window_roller = FixedWindowRoller::builder()
-        .build(
-            dir.join(LOGFILE_WATCHDOG_ROLLING).to_str().unwrap(),
-            window_size,
-        )
-        .unwrap();
-    let size_limit = 512 * 1024; // 512KB as max log file size to roll
-    let size_trigger = SizeTrigger::new(size_limit);
-    let compound_policy =
-        CompoundPolicy::new(Box::new(size_trigger), Box::new(fixed_window_roller));
-
-    let config = Config::builder()
-        .appender(
-            Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(threshold)))
-                .build(
-                    "logfile",
-                    Box::new(
-                        RollingFileAppender::builder()
-                            .encoder(Box::new(PatternEncoder::new("{d} {l}::{m}{n}")))
-                            .build(dir.join(LOGFILE_WATCHDOG), Box::new(compound_policy))
-                            .unwrap(),
-                    ),
-                ),
-        )
-        .build(Root::builder().appender("logfile").build(threshold))
-        .unwrap();
-
-    log4rs::init_config(config).unwrap();
+    Ok(response)
 }
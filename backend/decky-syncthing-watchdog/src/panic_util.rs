@@ -1,20 +1,26 @@
+use crate::crash_report;
 use backtrace::Backtrace;
 use log::error;
 use std::any::Any;
-use std::fs::File;
-use std::io::Write;
 use std::panic;
 use std::path::Path;
+use std::thread;
 
-/// Registers the panic hook that captures the backtrace.
-pub fn register_panic_hook(log_dir: &Path) {
-    let out_file = log_dir.join("last_panic.txt");
+/// Registers the panic hook that captures the backtrace and writes a crash report.
+pub fn register_panic_hook(log_dir: &Path, settings_path: &Path) {
+    crash_report::init(log_dir, settings_path);
     panic::set_hook(Box::new(move |panic_info| {
-        let mut w = File::create(&out_file).unwrap();
         let panic_str = panic_to_string(panic_info.payload());
-        writeln!(&mut w, "{}", panic_str).unwrap();
-        writeln!(&mut w, "{:?}", Backtrace::new()).unwrap();
-        error!("panicked: {}", panic_str)
+        error!("panicked: {}", panic_str);
+        match crash_report::write_report(
+            panic_str,
+            panic_info.location().map(ToString::to_string),
+            thread::current().name().map(str::to_string),
+            format!("{:?}", Backtrace::new()),
+        ) {
+            Ok(path) => error!("crash report written to {}", path.display()),
+            Err(err) => error!("failed to write crash report: {err:?}"),
+        }
     }));
 }
 
@@ -1,71 +1,396 @@
+use crate::api::make_json_response;
 use crate::service::last_start_ago;
-use crate::settings::SettingsProvider;
-use crate::util::make_unsafe_https_client;
+use crate::settings::{DEFAULT_INSTANCE, SettingsProvider};
+use crate::tls::{TlsVerification, make_https_client};
 use base64::Engine;
 use hyper::client::HttpConnector;
-use hyper::header::AUTHORIZATION;
-use hyper::{Body, Request, Response, Uri};
+use hyper::header::{AUTHORIZATION, HeaderMap, HeaderValue, LOCATION};
+use hyper::http::uri::{PathAndQuery, Scheme};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
 use hyper_reverse_proxy::ReverseProxy;
 use hyper_rustls::HttpsConnector;
 use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::mem::take;
 use std::net::IpAddr;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
 
 const RESPONSE_BAD_GATEWAY: &str =
     "<html><body><h1>Bad Gateway</h1><p>Is Syncthing running?</p></body></html>";
 const RESPONSE_TOO_EARLY: &str = "<html><body><h1>Too Early</h1><p>Syncthing is still starting, try again in a moment.</p></body></html>";
+const RESPONSE_UNKNOWN_INSTANCE: &str =
+    "<html><body><h1>Not Found</h1><p>Unknown Syncthing instance.</p></body></html>";
 
-static REVERSE_CLIENT: LazyLock<ReverseProxy<HttpsConnector<HttpConnector>>> =
-    LazyLock::new(|| ReverseProxy::new(make_unsafe_https_client::<Body>()));
+/// Header selecting the Syncthing instance to proxy to. Defaults to the default instance.
+pub const INSTANCE_HEADER: &str = "x-decky-syncthing-instance";
+/// Path prefix selecting the Syncthing instance to proxy to (`/__decky-instance/<name>/...`).
+/// Removed before forwarding the request.
+pub const INSTANCE_PATH_PREFIX: &str = "/__decky-instance/";
+/// Requests to Syncthing's REST API get the API key of the instance.
+const REST_PATH_PREFIX: &str = "/rest/";
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Which requests the proxy forwards to Syncthing (setting `proxy_policy`). Without it, all are.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyPolicy {
+    /// Only forward requests that do not change anything (`GET`, `HEAD` and `OPTIONS`).
+    #[serde(default)]
+    pub read_only: bool,
+    /// If set, only forward requests matching one of the rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<ProxyRule>>,
+}
+
+/// `<method> <path>`, e.g. `GET /rest/system/*` or `* /rest/db/scan`. `*` as method matches
+/// any method, `*` in the path any characters (including `/`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProxyRule {
+    /// `None` for any method.
+    method: Option<Method>,
+    path: String,
+}
+
+impl TryFrom<String> for ProxyRule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("`{rule}` is not a rule like `GET /rest/system/*`");
+        let (method, path) = rule.trim().split_once(' ').ok_or_else(invalid)?;
+        let path = path.trim();
+        if !path.starts_with('/') && path != "*" {
+            return Err(invalid());
+        }
+        Ok(Self {
+            method: match method {
+                "*" => None,
+                // Methods are case-sensitive, but `get` in a rule is meant to be `GET`.
+                method => Some(
+                    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| invalid())?,
+                ),
+            },
+            path: path.to_string(),
+        })
+    }
+}
+
+impl From<ProxyRule> for String {
+    fn from(rule: ProxyRule) -> Self {
+        match rule.method {
+            Some(method) => format!("{method} {}", rule.path),
+            None => format!("* {}", rule.path),
+        }
+    }
+}
+
+impl ProxyRule {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method) && glob_matches(&self.path, path)
+    }
+}
+
+/// Why the policy denied a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Denial {
+    /// The request may change something, but the policy is read-only.
+    ReadOnly,
+    /// No rule of the allowlist matches.
+    NotAllowed,
+}
+
+impl ProxyPolicy {
+    /// Checks a request for the (already normalized, see [`normalize_path`]) `path`.
+    fn check(&self, method: &Method, path: &str) -> Result<(), Denial> {
+        if self.read_only && ![Method::GET, Method::HEAD, Method::OPTIONS].contains(method) {
+            return Err(Denial::ReadOnly);
+        }
+        match &self.allow {
+            Some(rules) if !rules.iter().any(|rule| rule.matches(method, path)) => {
+                Err(Denial::NotAllowed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Body of the response to a request denied by the policy.
+#[derive(Serialize)]
+struct DeniedResponse<'a> {
+    error: &'static str,
+    reason: Denial,
+    instance: &'a str,
+    method: &'a str,
+    path: &'a str,
+}
+
+type Proxy = ReverseProxy<HttpsConnector<HttpConnector>>;
+
+/// Per certificate verification, to reuse connections.
+static REVERSE_CLIENTS: LazyLock<Mutex<HashMap<TlsVerification, Arc<Proxy>>>> =
+    LazyLock::new(Default::default);
+
+fn reverse_client(verification: TlsVerification) -> Arc<Proxy> {
+    REVERSE_CLIENTS
+        .lock()
+        .unwrap()
+        .entry(verification)
+        .or_insert_with(|| Arc::new(ReverseProxy::new(make_https_client::<Body>(verification))))
+        .clone()
+}
 
 pub async fn handle_proxy(
     client_ip: IpAddr,
     mut req: Request<Body>,
     settings: &SettingsProvider,
 ) -> Result<Response<Body>, Infallible> {
+    let name = match select_instance(&mut req) {
+        Ok(name) => name,
+        // The Syncthing GUI uses relative links, which need the trailing slash.
+        Err(SelectInstanceError::NoTrailingSlash(location)) => {
+            return Ok(Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(LOCATION, location)
+                .body(Body::empty())
+                .unwrap());
+        }
+        Err(SelectInstanceError::Invalid) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
     let settings_lock = settings.settings().await;
-    let port = settings_lock.port;
+    let Some(instance) = settings_lock.instance(&name) else {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(RESPONSE_UNKNOWN_INSTANCE))
+            .unwrap());
+    };
+    if let Some(policy) = &settings_lock.proxy_policy {
+        let path = normalize_path(req.uri().path());
+        if let Err(reason) = policy.check(req.method(), &path) {
+            warn!(
+                instance = name.as_str();
+                "Proxy denied {} {path} from {client_ip}: {reason:?}",
+                req.method()
+            );
+            return make_json_response(
+                &DeniedResponse {
+                    error: "Denied by the proxy policy of the watchdog.",
+                    reason,
+                    instance: &name,
+                    method: req.method().as_str(),
+                    path: &path,
+                },
+                StatusCode::FORBIDDEN,
+            );
+        }
+    }
     let mut auth_header = None;
-    if !settings_lock.basic_auth_user.is_empty() {
-        let raw_auth_header = format!(
-            "{}:{}",
-            &settings_lock.basic_auth_user, settings_lock.basic_auth_pass
-        );
+    if !instance.basic_auth_user.is_empty() {
+        let raw_auth_header = format!("{}:{}", instance.basic_auth_user, instance.basic_auth_pass);
         auth_header = Some(base64::engine::general_purpose::STANDARD.encode(raw_auth_header));
     }
-    match settings.backend_uri().await {
-        Ok((scheme, backend_uri)) => {
+    if req.uri().path().starts_with(REST_PATH_PREFIX) {
+        inject_api_key(
+            req.headers_mut(),
+            instance.api_key,
+            settings_lock.strip_client_api_key.unwrap_or(false),
+        );
+    }
+    drop(settings_lock);
+    match settings.backend_uri(&name).await {
+        Ok((backend_uri, verification)) => {
             // fake Host
             let uri = take(req.uri_mut());
-            *req.uri_mut() = Uri::builder()
-                .scheme(scheme)
-                .authority(format!("127.0.0.1:{port}"))
-                .path_and_query(uri.path_and_query().unwrap().clone())
-                .build()
-                .unwrap();
-            if let Some(auth_header) = auth_header {
-                if !req.headers().contains_key(AUTHORIZATION) {
-                    req.headers_mut().insert(
-                        AUTHORIZATION,
-                        format!("Basic {auth_header}").parse().unwrap(),
-                    );
+            let mut parts = backend_uri.clone().into_parts();
+            parts.path_and_query = Some(
+                uri.path_and_query()
+                    .cloned()
+                    .unwrap_or_else(|| PathAndQuery::from_static("/")),
+            );
+            *req.uri_mut() = Uri::from_parts(parts).unwrap();
+            if let Some(auth_header) = auth_header
+                && !req.headers().contains_key(AUTHORIZATION)
+            {
+                req.headers_mut().insert(
+                    AUTHORIZATION,
+                    format!("Basic {auth_header}").parse().unwrap(),
+                );
+            }
+            match reverse_client(verification)
+                .call(client_ip, &backend_uri.to_string(), req)
+                .await
+            {
+                Ok(response) => {
+                    if is_https_redirect(&response) && backend_uri.scheme() != Some(&Scheme::HTTPS)
+                    {
+                        // TLS was enabled in the GUI since the scheme was detected.
+                        settings.invalidate_backend(&name).await;
+                    }
+                    Ok(response)
+                }
+                Err(err) => {
+                    // Syncthing may have been restarted with another scheme or port.
+                    settings.invalidate_backend(&name).await;
+                    handle_proxy_error(&name, err).await
                 }
             }
-            match REVERSE_CLIENT.call(client_ip, &backend_uri, req).await {
-                Ok(response) => Ok(response),
-                Err(err) => handle_proxy_error(err).await,
+        }
+        Err(err) => handle_proxy_error(&name, err).await,
+    }
+}
+
+/// Adds the API key of the instance (unless it is empty), so clients of the proxy do not need
+/// to know it. With `strip_client_key`, API keys sent by the client are removed first, otherwise
+/// they are used instead.
+fn inject_api_key(headers: &mut HeaderMap, api_key: &str, strip_client_key: bool) {
+    if strip_client_key {
+        headers.remove(API_KEY_HEADER);
+        if has_bearer_token(headers) {
+            headers.remove(AUTHORIZATION);
+        }
+    }
+    if api_key.is_empty() || headers.contains_key(API_KEY_HEADER) || has_bearer_token(headers) {
+        return;
+    }
+    match HeaderValue::from_str(api_key) {
+        Ok(mut value) => {
+            value.set_sensitive(true);
+            headers.insert(API_KEY_HEADER, value);
+        }
+        Err(_) => warn!("The API key can not be sent in a header."),
+    }
+}
+
+/// Whether the request has an `Authorization: Bearer` header, which Syncthing takes as API key.
+fn has_bearer_token(headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.as_bytes().get(..7))
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case(b"bearer "))
+}
+
+/// Whether `pattern` matches all of `text`, `*` in the pattern matching any characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part has to be at the end, after the last `*`.
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// The path as Syncthing sees it, to check it against the policy: Percent-decoded, without
+/// empty and `.` segments and with `..` segments resolved.
+fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
             }
+            segment => segments.push(segment),
         }
-        Err(err) => handle_proxy_error(err).await,
     }
+    let trailing_slash = decoded.ends_with('/') && !segments.is_empty();
+    let mut path = format!("/{}", segments.join("/"));
+    if trailing_slash {
+        path.push('/');
+    }
+    path
+}
+
+/// Whether `response` redirects to HTTPS, as Syncthing does for HTTP requests when TLS is on.
+fn is_https_redirect(response: &Response<Body>) -> bool {
+    response.status().is_redirection()
+        && response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .is_some_and(|location| location.starts_with("https://"))
+}
+
+enum SelectInstanceError {
+    /// The path is just the prefix and the name, contains the path with a trailing slash.
+    NoTrailingSlash(String),
+    Invalid,
 }
 
-async fn handle_proxy_error(err: impl Debug) -> Result<Response<Body>, Infallible> {
-    let (status, body) = if last_start_ago().await < Duration::from_secs(30) {
+/// Returns the name of the instance selected via [`INSTANCE_PATH_PREFIX`] (which is removed
+/// from the request) or [`INSTANCE_HEADER`].
+fn select_instance(req: &mut Request<Body>) -> Result<String, SelectInstanceError> {
+    if let Some(rest) = req.uri().path().strip_prefix(INSTANCE_PATH_PREFIX) {
+        let Some((name, path)) = rest.find('/').map(|i| rest.split_at(i)) else {
+            return Err(SelectInstanceError::NoTrailingSlash(
+                match req.uri().query() {
+                    Some(query) => format!("{}/?{query}", req.uri().path()),
+                    None => format!("{}/", req.uri().path()),
+                },
+            ));
+        };
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        let name = name.to_string();
+        *req.uri_mut() = Uri::builder()
+            .path_and_query(path_and_query)
+            .build()
+            .map_err(|_| SelectInstanceError::Invalid)?;
+        return Ok(name);
+    }
+    match req.headers_mut().remove(INSTANCE_HEADER) {
+        Some(value) => value
+            .to_str()
+            .map(str::to_string)
+            .map_err(|_| SelectInstanceError::Invalid),
+        None => Ok(DEFAULT_INSTANCE.to_string()),
+    }
+}
+
+async fn handle_proxy_error(instance: &str, err: impl Debug) -> Result<Response<Body>, Infallible> {
+    let (status, body) = if last_start_ago(instance).await < Duration::from_secs(30) {
         (425, Body::from(RESPONSE_TOO_EARLY))
     } else {
         warn!("Proxy failed: {err:?}");
@@ -73,3 +398,185 @@ async fn handle_proxy_error(err: impl Debug) -> Result<Response<Body>, Infallibl
     };
     Ok(Response::builder().status(status).body(body).unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> ProxyPolicy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Checks a request as `handle_proxy` does.
+    fn check(policy: &ProxyPolicy, method: &str, path: &str) -> Result<(), Denial> {
+        policy.check(
+            &Method::from_bytes(method.as_bytes()).unwrap(),
+            &normalize_path(path),
+        )
+    }
+
+    #[test]
+    fn parses_rules() {
+        let policy = policy(r#"{"allow": ["GET /rest/*", "* /rest/db/scan", "post *"]}"#);
+        let rules = policy.allow.as_ref().unwrap();
+        assert_eq!(rules[0].method, Some(Method::GET));
+        assert_eq!(rules[1].method, None);
+        assert_eq!(rules[2].method, Some(Method::POST));
+        assert_eq!(
+            serde_json::to_string(&policy).unwrap(),
+            r#"{"read_only":false,"allow":["GET /rest/*","* /rest/db/scan","POST *"]}"#
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        for json in [
+            r#"{"allow": ["GET"]}"#,
+            r#"{"allow": ["GET rest/*"]}"#,
+            r#"{"allow": ["G(T /rest/*"]}"#,
+            r#"{"read_only": true, "allow_all": true}"#,
+        ] {
+            assert!(serde_json::from_str::<ProxyPolicy>(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let policy = policy(r#"{"read_only": true}"#);
+        for method in ["GET", "HEAD", "OPTIONS"] {
+            assert_eq!(check(&policy, method, "/rest/system/shutdown"), Ok(()));
+        }
+        for method in ["POST", "PUT", "PATCH", "DELETE", "get", "post", "PROPFIND"] {
+            assert_eq!(
+                check(&policy, method, "/rest/system/status"),
+                Err(Denial::ReadOnly),
+                "{method}"
+            );
+        }
+    }
+
+    #[test]
+    fn allowlist() {
+        let policy = policy(r#"{"allow": ["GET /rest/system/*", "POST /rest/db/scan"]}"#);
+        assert_eq!(check(&policy, "GET", "/rest/system/status"), Ok(()));
+        assert_eq!(check(&policy, "POST", "/rest/db/scan"), Ok(()));
+        for (method, path) in [
+            ("POST", "/rest/system/shutdown"),
+            ("GET", "/rest/db/scan"),
+            ("GET", "/rest/config"),
+            ("GET", "/rest/system"),
+            ("GET", "/"),
+            // Methods are case-sensitive, as for Syncthing.
+            ("get", "/rest/system/status"),
+            ("post", "/rest/db/scan"),
+        ] {
+            assert_eq!(
+                check(&policy, method, path),
+                Err(Denial::NotAllowed),
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_and_allowlist() {
+        let policy = policy(r#"{"read_only": true, "allow": ["* /rest/db/*"]}"#);
+        assert_eq!(check(&policy, "GET", "/rest/db/status"), Ok(()));
+        assert_eq!(
+            check(&policy, "POST", "/rest/db/scan"),
+            Err(Denial::ReadOnly)
+        );
+        assert_eq!(
+            check(&policy, "GET", "/rest/config"),
+            Err(Denial::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn empty_allowlist_denies_everything() {
+        let policy = policy(r#"{"allow": []}"#);
+        assert_eq!(check(&policy, "GET", "/"), Err(Denial::NotAllowed));
+    }
+
+    #[test]
+    fn paths_can_not_escape_the_allowlist() {
+        let policy = policy(r#"{"allow": ["GET /rest/db/status", "* /rest/db/browse/*"]}"#);
+        for (method, path) in [
+            ("POST", "/rest/db/status/../../system/shutdown"),
+            ("POST", "/rest/db/browse/../../system/shutdown"),
+            ("POST", "/rest/db/browse/%2e%2e/%2E%2E/system/shutdown"),
+            ("POST", "/rest/db/browse/.%2e/.%2e/system/shutdown"),
+            ("POST", "/rest/db/browse/..%2f..%2fsystem/shutdown"),
+            (
+                "POST",
+                "/rest/db/browse/%2e%2e%2f%2e%2e%2fsystem%2fshutdown",
+            ),
+            ("POST", "/rest/db/browse//..//..//system/shutdown"),
+            ("GET", "/rest/db/status/../../config"),
+            ("GET", "/rest/db/status/"),
+            ("GET", "/rest/db/status%2f"),
+        ] {
+            assert_eq!(
+                check(&policy, method, path),
+                Err(Denial::NotAllowed),
+                "{method} {path} -> {}",
+                normalize_path(path)
+            );
+        }
+    }
+
+    #[test]
+    fn equivalent_paths_are_allowed() {
+        let policy = policy(r#"{"allow": ["GET /rest/db/status"]}"#);
+        for path in [
+            "/rest/db/status",
+            "//rest//db//status",
+            "/rest/./db/status",
+            "/rest/db/status/.",
+            "/rest/system/../db/status",
+            "/%72est/db/%73tatus",
+            "/rest%2Fdb%2Fstatus",
+        ] {
+            assert_eq!(check(&policy, "GET", path), Ok(()), "{path}");
+        }
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        for (path, normalized) in [
+            ("/", "/"),
+            ("", "/"),
+            ("//", "/"),
+            ("/..", "/"),
+            ("/../../rest", "/rest"),
+            ("/rest/", "/rest/"),
+            ("/rest//", "/rest/"),
+            ("/rest/db/..", "/rest"),
+            ("/rest/db/../", "/rest/"),
+            ("/rest/%2e%2e/%2e", "/"),
+            ("/rest/%2", "/rest/%2"),
+            ("/rest/%zz", "/rest/%zz"),
+            ("/a%20b", "/a b"),
+        ] {
+            assert_eq!(normalize_path(path), normalized, "{path}");
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "/rest/system/status"));
+        assert!(glob_matches("/rest/*", "/rest/"));
+        assert!(glob_matches("/rest/*", "/rest/db/status"));
+        assert!(glob_matches("/rest/*/status", "/rest/db/status"));
+        assert!(glob_matches("/rest/*/status", "/rest/db/x/status"));
+        assert!(glob_matches("/a*a", "/aa"));
+        assert!(glob_matches("/a*b*c", "/abc"));
+        assert!(!glob_matches("/rest/*", "/rest"));
+        assert!(!glob_matches("/rest/*", "/rests/x"));
+        assert!(!glob_matches("/rest/db/status", "/rest/db/status/"));
+        assert!(!glob_matches("/rest/*/status", "/rest/db/statuses"));
+        assert!(!glob_matches("/a*a", "/a"));
+        assert!(!glob_matches("/a*b*c", "/acb"));
+    }
+}
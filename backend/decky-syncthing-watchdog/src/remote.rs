@@ -0,0 +1,224 @@
+//! Syncthing on another machine (mode `remote`). It is not started from here, its state and
+//! stopping it map to Syncthing's REST API.
+
+use crate::service::{ServiceError, SyncthingState};
+use crate::settings::Instance;
+use crate::tls::{TlsVerification, make_https_client};
+use anyhow::anyhow;
+use base64::Engine;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response};
+use log::{debug, warn};
+use std::io;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Max. time a request to the remote Syncthing may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `running` if Syncthing answers a ping, `failed` if it rejects the request or its certificate
+/// is not accepted, otherwise `stopped`.
+pub async fn get_state(instance: &Instance<'_>) -> Result<SyncthingState, ServiceError> {
+    match request(instance, Method::GET, "rest/system/ping").await {
+        Ok(response) if response.status().is_success() => Ok(SyncthingState::Running),
+        Ok(response) => {
+            warn!(
+                "remote Syncthing of {} rejected the ping: {}",
+                instance.name,
+                response.status()
+            );
+            Ok(SyncthingState::Failed)
+        }
+        Err(err) if is_tls_error(&err) => {
+            warn!(
+                "remote Syncthing of {} not accepted: {err:#}",
+                instance.name
+            );
+            Ok(SyncthingState::Failed)
+        }
+        Err(err) => {
+            debug!(
+                "remote Syncthing of {} not reachable: {err:#}",
+                instance.name
+            );
+            Ok(SyncthingState::Stopped)
+        }
+    }
+}
+
+/// Shuts the remote Syncthing down.
+pub async fn shutdown(instance: &Instance<'_>) -> Result<(), ServiceError> {
+    let response = request(instance, Method::POST, "rest/system/shutdown").await?;
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "Syncthing rejected the shutdown: {}",
+            response.status()
+        )),
+    }
+}
+
+/// Sends a request to the REST API of the remote Syncthing, with the API key and Basic auth
+/// credentials of the instance.
+async fn request(
+    instance: &Instance<'_>,
+    method: Method,
+    path: &str,
+) -> Result<Response<Body>, ServiceError> {
+    let base = instance
+        .remote_uri()
+        .ok_or_else(|| anyhow!("no valid `remote_url` set"))?;
+    let verification =
+        TlsVerification::for_host(base.host().unwrap_or_default(), instance.tls_pin());
+    let mut req = Request::builder()
+        .method(method)
+        .uri(format!("{base}{path}"));
+    if !instance.api_key.is_empty() {
+        req = req.header("X-API-Key", instance.api_key);
+    }
+    if !instance.basic_auth_user.is_empty() {
+        let credentials = format!("{}:{}", instance.basic_auth_user, instance.basic_auth_pass);
+        req = req.header(
+            AUTHORIZATION,
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            ),
+        );
+    }
+    let client = make_https_client::<Body>(verification);
+    match timeout(REQUEST_TIMEOUT, client.request(req.body(Body::empty())?)).await {
+        Ok(response) => Ok(response?),
+        Err(_) => Err(anyhow!("timed out")),
+    }
+}
+
+/// Whether the request failed because the certificate was not accepted.
+fn is_tls_error(err: &ServiceError) -> bool {
+    err.chain().any(|mut err| {
+        // The TLS error can be wrapped in several I/O errors.
+        while let Some(inner) = err.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+            err = inner;
+        }
+        err.is::<rustls::Error>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::settings::test_support::settings_v2;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Server, StatusCode};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    const API_KEY: &str = "key";
+    /// `user:pass`
+    const BASIC_AUTH: &str = "Basic dXNlcjpwYXNz";
+
+    /// Method and path of the requests the stand-in received.
+    type Received = Arc<Mutex<Vec<(Method, String)>>>;
+
+    /// Starts a stand-in for Syncthing's REST API, which only accepts requests with [`API_KEY`]
+    /// and [`BASIC_AUTH`].
+    fn stand_in() -> (SocketAddr, Received) {
+        let received = Received::default();
+        let make_svc = make_service_fn({
+            let received = received.clone();
+            move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let headers = req.headers();
+                        let authorized = headers.get("X-API-Key").is_some_and(|v| v == API_KEY)
+                            && headers.get(AUTHORIZATION).is_some_and(|v| v == BASIC_AUTH);
+                        received
+                            .lock()
+                            .unwrap()
+                            .push((req.method().clone(), req.uri().path().to_string()));
+                        let status = match authorized {
+                            true => StatusCode::OK,
+                            false => StatusCode::FORBIDDEN,
+                        };
+                        let response = Response::builder().status(status).body(Body::empty());
+                        async move { Ok::<_, Infallible>(response.unwrap()) }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    fn settings(remote_url: &str, api_key: &str, tls_device_id: Option<&str>) -> Settings {
+        serde_json::from_value(settings_v2(json!({
+            "mode": "remote",
+            "api_key": api_key,
+            "basic_auth_user": "user",
+            "basic_auth_pass": "pass",
+            "remote_url": remote_url,
+            "tls_device_id": tls_device_id,
+        })))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn running_if_ping_succeeds() {
+        let (addr, received) = stand_in();
+        let settings = settings(&format!("http://{addr}/syncthing"), API_KEY, None);
+        let state = get_state(&settings.default_instance()).await.unwrap();
+        assert!(matches!(state, SyncthingState::Running), "{state:?}");
+        assert_eq!(
+            *received.lock().unwrap(),
+            [(Method::GET, "/syncthing/rest/system/ping".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_if_ping_is_rejected() {
+        let (addr, _) = stand_in();
+        let settings = settings(&format!("http://{addr}/"), "wrong", None);
+        let state = get_state(&settings.default_instance()).await.unwrap();
+        assert!(matches!(state, SyncthingState::Failed), "{state:?}");
+    }
+
+    #[tokio::test]
+    async fn failed_if_tls_fails() {
+        // The stand-in does not speak TLS.
+        let (addr, _) = stand_in();
+        let device_id = "P56IOI7-MZJNU2Y-IQGDREY-DM2MGTI-MGL3BXN-PQ6W5BM-TBBZ4TJ-XZWICQ2";
+        let settings = settings(&format!("https://{addr}/"), API_KEY, Some(device_id));
+        let state = get_state(&settings.default_instance()).await.unwrap();
+        assert!(matches!(state, SyncthingState::Failed), "{state:?}");
+    }
+
+    #[tokio::test]
+    async fn stopped_if_not_reachable() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let settings = settings(&format!("http://{addr}/"), API_KEY, None);
+        let state = get_state(&settings.default_instance()).await.unwrap();
+        assert!(matches!(state, SyncthingState::Stopped), "{state:?}");
+    }
+
+    #[tokio::test]
+    async fn shuts_down() {
+        let (addr, received) = stand_in();
+        let accepted = settings(&format!("http://{addr}"), API_KEY, None);
+        let rejected = settings(&format!("http://{addr}"), "wrong", None);
+        shutdown(&accepted.default_instance()).await.unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            [(Method::POST, "/rest/system/shutdown".to_string())]
+        );
+        assert!(shutdown(&rejected.default_instance()).await.is_err());
+    }
+}
@@ -1,12 +1,16 @@
-//! Manages the Systemd service.
+//! Manages the Systemd services, one per Syncthing instance.
 //! If in Flatpak mode: Installs a custom service and controls it.
 //! If not in Flatpak mode: Uninstalls it (if exists) and controls the configured service.
+//! If in remote mode: There is no service, see `remote.rs`.
 
-use crate::settings::{Autostart, Mode, Settings};
+use crate::remote;
+use crate::settings::{Autostart, DEFAULT_INSTANCE, Instance, Mode, Settings};
+use anyhow::Context;
 use homedir::my_home;
 use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 use std::time::Duration;
 use tokio::fs;
@@ -17,7 +21,8 @@ use which::which;
 pub use systemctl::State as SyncthingState;
 use systemctl::{SessionType, Systemctl};
 
-static LAST_START: LazyLock<Mutex<Option<Instant>>> = LazyLock::new(Default::default);
+/// Per instance name.
+static LAST_START: LazyLock<Mutex<HashMap<String, Instant>>> = LazyLock::new(Default::default);
 static WHICH_FLATPAK: LazyLock<Result<PathBuf, which::Error>> = LazyLock::new(|| which("flatpak"));
 static SERVICE_DIR: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
     my_home()
@@ -29,31 +34,46 @@ static SERVICE_DIR: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
 pub type ServiceError = anyhow::Error;
 
 enum ServiceType<'a> {
-    Managed,
+    Managed { unit: String },
     External { name: &'a str, user_service: bool },
+    Remote,
 }
 
 impl<'a> ServiceType<'a> {
     const MANAGED_SERVICE_NAME: &'static str = "decky-syncthing";
 
-    fn get_for(settings: &'a Settings) -> Self {
-        match settings.mode {
+    fn get_for(instance: &Instance<'a>) -> Self {
+        match instance.mode {
             Mode::Systemd => Self::External {
-                name: &settings.service_name,
+                name: instance.service_name,
                 user_service: true,
             },
             Mode::SystemdSystem => Self::External {
-                name: &settings.service_name,
+                name: instance.service_name,
                 user_service: false,
             },
-            Mode::Flatpak => Self::Managed,
+            Mode::Flatpak => Self::Managed {
+                unit: Self::managed_unit(instance.name),
+            },
+            Mode::Remote => Self::Remote,
         }
     }
 
-    fn systemd_unit(&'a self) -> (&'a str, bool) {
+    /// Name of the managed service of the instance. Instances other than the default one get
+    /// their name appended.
+    fn managed_unit(instance_name: &str) -> String {
+        match instance_name {
+            DEFAULT_INSTANCE => Self::MANAGED_SERVICE_NAME.to_string(),
+            name => format!("{}-{name}", Self::MANAGED_SERVICE_NAME),
+        }
+    }
+
+    /// `None` for remote instances.
+    fn systemd_unit(&self) -> Option<(&str, bool)> {
         match self {
-            ServiceType::Managed => (Self::MANAGED_SERVICE_NAME, true),
-            ServiceType::External { name, user_service } => (name, *user_service),
+            ServiceType::Managed { unit } => Some((unit, true)),
+            ServiceType::External { name, user_service } => Some((name, *user_service)),
+            ServiceType::Remote => None,
         }
     }
 }
@@ -64,22 +84,40 @@ pub async fn init_service(settings: &Settings) -> Result<(), ServiceError> {
         return Ok(());
     }
     debug!("Init service");
-    let service_type = ServiceType::get_for(settings);
-    let managed_service_name = ServiceType::Managed.systemd_unit().0;
     let systemctl_user = Systemctl::new(SessionType::Session).await?;
+    for instance in settings.instances() {
+        init_instance(&instance, &systemctl_user)
+            .await
+            .with_context(|| format!("failed to init instance {}", instance.name))?;
+    }
+    if let Err(e) = remove_stale_managed_services(settings, &systemctl_user).await {
+        warn!("failed to remove managed services of removed instances: {e:?}");
+    }
+    debug!("Init service done");
+    Ok(())
+}
+
+async fn init_instance(
+    instance: &Instance<'_>,
+    systemctl_user: &Systemctl<'_>,
+) -> Result<(), ServiceError> {
+    debug!(instance = instance.name; "Init instance {}", instance.name);
+    let service_type = ServiceType::get_for(instance);
+    let managed_service_name = &ServiceType::managed_unit(instance.name);
     match &service_type {
-        ServiceType::Managed => {
+        ServiceType::Managed { .. } => {
             // Create/Update the managed service.
             debug!("Create managed service service");
             create_managed_service(
                 managed_service_name,
-                &settings.flatpak_name,
-                &settings.flatpak_binary,
+                instance.flatpak_name,
+                instance.flatpak_binary,
+                instance.home,
             )
             .await?;
             systemctl_user.daemon_reload().await?;
             // Enable or disable the managed service based on autostart settings.
-            match settings.autostart {
+            match instance.autostart {
                 Autostart::Boot => {
                     debug!("Enable service {managed_service_name}");
                     systemctl_user.enable(managed_service_name).await?;
@@ -92,22 +130,17 @@ pub async fn init_service(settings: &Settings) -> Result<(), ServiceError> {
             }
         }
         ServiceType::External { name, user_service } => {
-            // Disable & delete the managed service.
-            if service_exists_in_config(managed_service_name).await? {
-                debug!("Disable and delete managed service");
-                systemctl_user.disable(managed_service_name).await.ok();
-
-                let r = delete_managed_service(managed_service_name).await;
-                if r.is_ok() {
-                    systemctl_user.daemon_reload().await.ok();
-                }
-            }
+            remove_managed_service(managed_service_name, systemctl_user).await?;
             // Enable or disable the external service based on autostart settings.
+            let systemctl_system;
             let systemctl_client = match *user_service {
                 true => systemctl_user,
-                false => Systemctl::new(SessionType::System).await?,
+                false => {
+                    systemctl_system = Systemctl::new(SessionType::System).await?;
+                    &systemctl_system
+                }
             };
-            match settings.autostart {
+            match instance.autostart {
                 Autostart::Boot => {
                     debug!("Enable service {name}");
                     if let Err(e) = systemctl_client.enable(name).await {
@@ -126,15 +159,74 @@ pub async fn init_service(settings: &Settings) -> Result<(), ServiceError> {
                 }
             }
         }
+        ServiceType::Remote => {
+            remove_managed_service(managed_service_name, systemctl_user).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Disables and deletes the managed service of an instance that does not use it (anymore).
+async fn remove_managed_service(
+    unit: &str,
+    systemctl_user: &Systemctl<'_>,
+) -> Result<(), io::Error> {
+    if service_exists_in_config(unit).await? {
+        debug!("Disable and delete managed service");
+        systemctl_user.disable(unit).await.ok();
+
+        let r = delete_managed_service(unit).await;
+        if r.is_ok() {
+            systemctl_user.daemon_reload().await.ok();
+        }
+    }
+    Ok(())
+}
+
+/// Disables and deletes the managed services of instances that were removed from the settings.
+async fn remove_stale_managed_services(
+    settings: &Settings,
+    systemctl_user: &Systemctl<'_>,
+) -> Result<(), io::Error> {
+    let Some(dir) = SERVICE_DIR.as_ref() else {
+        return Ok(());
+    };
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let prefix = format!("{}-", ServiceType::MANAGED_SERVICE_NAME);
+    let mut removed = false;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(unit) = file_name.to_str().and_then(|n| n.strip_suffix(".service")) else {
+            continue;
+        };
+        let Some(name) = unit.strip_prefix(&prefix) else {
+            continue;
+        };
+        if settings.instance(name).is_some() {
+            continue;
+        }
+        debug!("Stop, disable and delete managed service of removed instance {name}");
+        systemctl_user.stop(unit).await.ok();
+        systemctl_user.disable(unit).await.ok();
+        delete_managed_service(unit).await?;
+        removed = true;
+    }
+    if removed {
+        systemctl_user.daemon_reload().await.ok();
     }
-    debug!("Init service done");
     Ok(())
 }
 
-pub async fn get_state(settings: &Settings) -> Result<SyncthingState, ServiceError> {
-    debug!("get_state");
-    let service_type = ServiceType::get_for(settings);
-    let (service_name, is_user_service) = service_type.systemd_unit();
+pub async fn get_state(instance: &Instance<'_>) -> Result<SyncthingState, ServiceError> {
+    debug!(instance = instance.name; "get_state");
+    let service_type = ServiceType::get_for(instance);
+    let Some((service_name, is_user_service)) = service_type.systemd_unit() else {
+        return remote::get_state(instance).await;
+    };
     let systemctl_client = match is_user_service {
         true => Systemctl::new(SessionType::Session),
         false => Systemctl::new(SessionType::System),
@@ -143,14 +235,17 @@ pub async fn get_state(settings: &Settings) -> Result<SyncthingState, ServiceErr
     systemctl_client.state(service_name).await
 }
 
-pub async fn start_service(settings: &Settings) -> Result<(), ServiceError> {
-    if settings.is_not_setup() {
+pub async fn start_service(instance: &Instance<'_>) -> Result<(), ServiceError> {
+    if instance.is_not_setup() {
         info!("Skipping service start: Configuration not setup.");
         return Ok(());
     }
-    debug!("start_service");
-    let service_type = ServiceType::get_for(settings);
-    let (service_name, is_user_service) = service_type.systemd_unit();
+    debug!(instance = instance.name; "start_service");
+    let service_type = ServiceType::get_for(instance);
+    let Some((service_name, is_user_service)) = service_type.systemd_unit() else {
+        debug!("Not starting remote instance {}.", instance.name);
+        return Ok(());
+    };
     let systemctl_client = match is_user_service {
         true => Systemctl::new(SessionType::Session),
         false => Systemctl::new(SessionType::System),
@@ -158,19 +253,24 @@ pub async fn start_service(settings: &Settings) -> Result<(), ServiceError> {
     .await?;
     let r = systemctl_client.start(service_name).await;
     if r.is_ok() {
-        *LAST_START.lock().await = Some(Instant::now());
+        LAST_START
+            .lock()
+            .await
+            .insert(instance.name.to_string(), Instant::now());
     }
     r
 }
 
-pub async fn stop_service(settings: &Settings) -> Result<(), ServiceError> {
-    if settings.is_not_setup() {
+pub async fn stop_service(instance: &Instance<'_>) -> Result<(), ServiceError> {
+    if instance.is_not_setup() {
         info!("Skipping service stop: Configuration not setup.");
         return Ok(());
     }
-    debug!("stop_service");
-    let service_type = ServiceType::get_for(settings);
-    let (service_name, is_user_service) = service_type.systemd_unit();
+    debug!(instance = instance.name; "stop_service");
+    let service_type = ServiceType::get_for(instance);
+    let Some((service_name, is_user_service)) = service_type.systemd_unit() else {
+        return remote::shutdown(instance).await;
+    };
     let systemctl_client = match is_user_service {
         true => Systemctl::new(SessionType::Session),
         false => Systemctl::new(SessionType::System),
@@ -179,10 +279,10 @@ pub async fn stop_service(settings: &Settings) -> Result<(), ServiceError> {
     systemctl_client.stop(service_name).await
 }
 
-pub async fn last_start_ago() -> Duration {
+pub async fn last_start_ago(instance_name: &str) -> Duration {
     debug!("last_start_ago");
     let last_update = LAST_START.lock().await;
-    match &*last_update {
+    match last_update.get(instance_name) {
         None => Duration::from_secs(999),
         Some(last_update) => Instant::now().duration_since(*last_update),
     }
@@ -199,6 +299,7 @@ async fn create_managed_service(
     unit: &str,
     flatpak: &str,
     flatpak_exec: &str,
+    home: Option<&Path>,
 ) -> Result<(), io::Error> {
     let path = service_path(unit)?;
     fs::create_dir_all(path.parent().unwrap()).await?;
@@ -207,6 +308,13 @@ async fn create_managed_service(
         .map_err(|e| io::Error::other(*e))?
         .to_str()
         .unwrap();
+    // `%` starts a specifier in unit files.
+    let home_arg = home.map_or(String::new(), |home| {
+        format!(
+            " \"--home={}\"",
+            home.display().to_string().replace('%', "%%")
+        )
+    });
     fs::write(
         path,
         format!(
@@ -214,14 +322,14 @@ async fn create_managed_service(
 Description=Decky managed Syncthing starter - Open Source Continuous File Synchronization
 
 [Service]
-ExecStart={} run --die-with-parent --command={} {} --no-browser --no-restart
+ExecStart={} run --die-with-parent --command={} {} --no-browser --no-restart{}
 Restart=on-failure
 SuccessExitStatus=3 4
 RestartForceExitStatus=3 4
 
 [Install]
 WantedBy=default.target",
-            flatpak_cmd, flatpak_exec, flatpak
+            flatpak_cmd, flatpak_exec, flatpak, home_arg
         ),
     )
     .await
@@ -266,7 +374,7 @@ pub mod systemctl {
         }
 
         pub async fn start(&self, unit: &str) -> anyhow::Result<()> {
-            debug!("systemd: starting {unit}");
+            debug!(unit; "systemd: starting {unit}");
             if !unit.ends_with(".service") {
                 let unit = format!("{unit}.service");
                 self.run_job(|proxy| proxy.start_unit(&unit, Mode::Replace))
@@ -278,7 +386,7 @@ pub mod systemctl {
         }
 
         pub async fn stop(&self, unit: &str) -> anyhow::Result<()> {
-            debug!("systemd: stopping {unit}");
+            debug!(unit; "systemd: stopping {unit}");
             if !unit.ends_with(".service") {
                 let unit = format!("{unit}.service");
                 self.run_job(|proxy| proxy.stop_unit(&unit, Mode::Replace))
@@ -290,7 +398,7 @@ pub mod systemctl {
         }
 
         pub async fn enable(&self, unit: &str) -> anyhow::Result<()> {
-            debug!("systemd: enabling {unit}");
+            debug!(unit; "systemd: enabling {unit}");
             if !unit.ends_with(".service") {
                 let unit = format!("{unit}.service");
                 self.1.enable_unit_files(&[&unit], false, false).await?;
@@ -301,7 +409,7 @@ pub mod systemctl {
         }
 
         pub async fn disable(&self, unit: &str) -> anyhow::Result<()> {
-            debug!("systemd: disabling {unit}");
+            debug!(unit; "systemd: disabling {unit}");
             if !unit.ends_with(".service") {
                 let unit = format!("{unit}.service");
                 self.1.disable_unit_files(&[&unit], false).await?;
@@ -312,7 +420,7 @@ pub mod systemctl {
         }
 
         pub async fn state(&self, unit: &str) -> anyhow::Result<State> {
-            debug!("systemd: getting state for {unit}");
+            debug!(unit; "systemd: getting state for {unit}");
             let unit_result = if !unit.ends_with(".service") {
                 let unit = format!("{unit}.service");
                 self.1.get_unit(&unit).await
@@ -349,6 +457,7 @@ pub mod systemctl {
             while let Some(msg) = job_removed_stream.next().await {
                 let args = msg.args()?;
                 debug!(
+                    unit = args.unit, job_result = args.result;
                     "systemd: job removed: {} for {}: {}",
                     args.id, args.unit, args.result
                 );
@@ -364,12 +473,15 @@ pub mod systemctl {
         async fn check_job_status(&self, msg: JobRemovedArgs<'_>) -> anyhow::Result<()> {
             match msg.result {
                 "done" | "skipped" => {
-                    debug!("systemd: job done or skipped");
+                    debug!(unit = msg.unit, job_result = msg.result; "systemd: job done or skipped");
                     Ok(())
                 }
                 "canceled" | "timeout" | "dependency" | "invalid" | "assert" | "unsupported"
                 | "collected" | "once" | "frozen" | "concurrency" => {
-                    error!("systemd: job failed with status {}", msg.result);
+                    error!(
+                        unit = msg.unit, job_result = msg.result;
+                        "systemd: job failed with status {}", msg.result
+                    );
                     Err(anyhow!("systemd job failed with status {}", msg.result))
                 }
                 _result if _result.ends_with(".service") => {
@@ -381,11 +493,15 @@ pub mod systemctl {
                         .await?;
                     match &*unit_obj.result().await? {
                         "success" => {
-                            debug!("systemd: service indicates job success");
+                            debug!(
+                                unit = msg.unit, job_result = msg.result, unit_result = "success";
+                                "systemd: service indicates job success"
+                            );
                             Ok(())
                         }
                         result => {
                             error!(
+                                unit = msg.unit, job_result = msg.result, unit_result = result;
                                 "systemd: job finished with unknown status {} - unit result: {}",
                                 msg.result, result
                             );
@@ -398,7 +514,10 @@ pub mod systemctl {
                     }
                 }
                 result => {
-                    error!("systemd: job finished with unknown status {}", result);
+                    error!(
+                        unit = msg.unit, job_result = result;
+                        "systemd: job finished with unknown status {}", result
+                    );
                     Err(anyhow!(
                         "systemd job finished with unknown status {}",
                         result
@@ -1,16 +1,34 @@
-use crate::util::make_unsafe_https_client;
+use crate::backend::{BackendCache, ProbeResult, ProbeTarget};
+use crate::credentials::{
+    Credentials, CredentialsError, CredentialsStore, PRIVATE_MODE, restrict_permissions,
+    set_known_secrets,
+};
+use crate::gui_address::{DEFAULT_HOST, GuiAddress};
+use crate::logging::LogFormat;
+use crate::proxy::ProxyPolicy;
+use crate::service::init_service;
+use crate::settings_overrides::{EffectiveSetting, Overrides, Source};
+use crate::settings_validation::{
+    Severity, ValidationIssue, field_names, is_known_field, validate,
+};
+use crate::tls::{TlsVerification, parse_device_id, parse_fingerprint};
+use hyper::Uri;
 use hyper::http::uri::Scheme;
-use hyper::{Body, Method, Request, Uri};
+use log::{LevelFilter, debug, error, info, warn};
 use serde::de::Unexpected;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::io;
+use std::mem::replace;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::fs::read_to_string;
-use tokio::join;
-use tokio::sync::RwLock;
+use tokio::fs::{File, OpenOptions, copy, read, read_to_string, rename};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock};
 
 #[derive(Debug, Error)]
 pub enum SettingsError {
@@ -24,19 +42,41 @@ pub enum SettingsError {
     BackendOffline,
     #[error("Error during an HTTP request: {0}")]
     Hyper(#[from] hyper::http::Error),
+    #[error("Invalid URI: {0}")]
+    InvalidUri(#[from] hyper::http::uri::InvalidUri),
     #[error("Unsupported config version. Is: {0}, Need: {1}")]
     UnsupportedVersion(u32, u32),
+    #[error("Settings JSON has no valid config version.")]
+    MissingVersion,
+    #[error("Invalid settings: {}", format_issues(.0))]
+    Invalid(Vec<ValidationIssue>),
+    #[error("Failed to apply settings: {0}")]
+    Apply(anyhow::Error),
+    #[error("Unknown instance: {0}")]
+    UnknownInstance(String),
+    #[error("Failed to access the credentials: {0}")]
+    Credentials(#[from] CredentialsError),
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+fn format_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| format!("{}: {}", i.field, i.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum Mode {
     Systemd,
     SystemdSystem,
     Flatpak,
+    /// Syncthing on another machine, at `remote_url`. Not started or stopped as a service.
+    Remote,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Autostart {
     No,
@@ -44,16 +84,19 @@ pub enum Autostart {
     Gamescope,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 #[serde(untagged)]
 pub enum IsSetup {
     Bool(bool),
     Other(String),
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(unused)]
-// v2
+// v2, plus optional settings added since, see `SettingsV2`.
+// When changing the schema in an incompatible way, increase `SUPPORTED_VERSION`, freeze this
+// struct as it is now as `SettingsV3` and add a migration step from `SettingsV2` to it to
+// `migrate_step`.
 pub struct Settings {
     #[serde(deserialize_with = "try_deserialize_u32_from_str")]
     config_version: u32,
@@ -75,104 +118,779 @@ pub struct Settings {
     // Only for the wizard (checks.rs) - if set force looking for the Syncthing configuration XML
     // in a Flatpak settings directory, even if the `flatpak` mode is not enabled. The value
     // is the name of the Flatpak
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub _wizard_force_flatpak_config_for: Option<String>,
+    // Optional: Address the watchdog listens on. Defaults to `127.0.0.1:58384`. Must be a
+    // loopback address. Only read on startup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_address: Option<SocketAddr>,
+    // Optional: Unix domain socket to additionally serve the watchdog control API on.
+    // Only read on startup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_socket: Option<PathBuf>,
+    // Optional: Log level of the watchdog (`error`, `warn`, `info`, `debug`, `trace` or `off`).
+    // Defaults to `info` (`debug` for debug builds).
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_log_level"
+    )]
+    pub log_level: Option<LevelFilter>,
+    // Optional: Format of the watchdog log files (`text` or `json`). Defaults to `text`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<LogFormat>,
+    // Optional: Additional Syncthing instances managed next to the one configured above
+    // (which is the instance named `default`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<InstanceSettings>,
+    // Optional: Where `api_key`, `basic_auth_user` and `basic_auth_pass` are kept (`settings`,
+    // `file` or `secret_service`, see `credentials.rs`). Defaults to `settings`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials_store: Option<CredentialsStore>,
+    // Optional: Host (name or IP address) the Syncthing GUI listens on, as detected by the
+    // wizard. Defaults to `127.0.0.1`, `0.0.0.0` and `::` are reached via loopback.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Optional: Whether the Syncthing GUI uses HTTPS, as detected by the wizard. Syncthing's
+    // `config.xml` takes precedence if it is found, if neither is known both are tried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    // Mode: remote. Base URL of the Syncthing GUI, e.g. `https://nas.local:8384/`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_url: Option<String>,
+    // Optional: SHA-256 fingerprint of the certificate of the Syncthing GUI (hex). Only that
+    // certificate is accepted. Without a pin, certificates of hosts other than this machine must
+    // be signed by a system root CA.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert_sha256: Option<String>,
+    // Optional: Like `tls_cert_sha256`, but as Syncthing device ID of the certificate. The same
+    // as the ID of the device if its GUI uses the device certificate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_device_id: Option<String>,
+    // Optional: Whether the proxy removes API keys sent by clients (`X-API-Key` or a bearer
+    // token) from requests to Syncthing's REST API, so only the key of the instance is used.
+    // Defaults to `false`: The key of the instance is only added if the client sent none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_client_api_key: Option<bool>,
+    // Optional: Which requests the proxy forwards to Syncthing, see `ProxyPolicy`. Defaults to
+    // all of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_policy: Option<ProxyPolicy>,
+    // Optional: Origins allowed to make cross-origin requests to the watchdog, e.g.
+    // `https://steamloopback.host`. Defaults to `cors::DEFAULT_ORIGINS`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors_origins: Option<Vec<String>>,
+    // Optional: Hosts the watchdog may be requested with in addition to `127.0.0.1`, `localhost`
+    // and `[::1]`, e.g. `steamdeck.local:58384`. Without port, the port of the watchdog.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_hosts: Option<Vec<String>>,
+}
+
+/// Name of the instance described by the top-level settings.
+pub const DEFAULT_INSTANCE: &str = "default";
+
+/// `value` unless it is empty, as the plugin's settings page sets text settings to `""`.
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.trim().is_empty())
+}
+
+/// An additional Syncthing instance. Same meaning as the fields of [`Settings`], plus `home`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceSettings {
+    pub name: String,
+    pub mode: Mode,
+    // Mode: systemd
+    #[serde(default)]
+    pub service_name: String,
+    // Mode: flatpak
+    #[serde(default)]
+    pub flatpak_name: String,
+    #[serde(default = "default_flatpak_binary")]
+    pub flatpak_binary: String,
+    // Mode: flatpak. Optional: Configuration directory of Syncthing (`--home`), needed to run
+    // several instances from the same Flatpak.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<PathBuf>,
+    pub autostart: Autostart,
+    #[serde(default)]
+    pub keep_running_on_desktop: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(deserialize_with = "try_deserialize_u32_from_str")]
+    pub port: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cert_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_device_id: Option<String>,
+    #[serde(default)]
+    pub api_key: String,
+    #[serde(default)]
+    pub basic_auth_user: String,
+    #[serde(default)]
+    pub basic_auth_pass: String,
+}
+
+fn default_flatpak_binary() -> String {
+    "syncthing".to_string()
+}
+
+/// The settings of one Syncthing instance, either the default instance or one of
+/// [`Settings::instances`].
+#[derive(Debug, Clone, Copy)]
+pub struct Instance<'a> {
+    pub name: &'a str,
+    pub mode: Mode,
+    pub service_name: &'a str,
+    pub flatpak_name: &'a str,
+    pub flatpak_binary: &'a str,
+    pub home: Option<&'a Path>,
+    pub autostart: Autostart,
+    pub keep_running_on_desktop: bool,
+    /// `None` for [`DEFAULT_HOST`].
+    pub host: Option<&'a str>,
+    pub port: u32,
+    pub tls: Option<bool>,
+    pub remote_url: Option<&'a str>,
+    pub tls_cert_sha256: Option<&'a str>,
+    pub tls_device_id: Option<&'a str>,
+    pub api_key: &'a str,
+    pub basic_auth_user: &'a str,
+    pub basic_auth_pass: &'a str,
+    /// The setup is done for all instances at once.
+    is_setup: bool,
+}
+
+impl Instance<'_> {
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_INSTANCE
+    }
+
+    pub fn is_not_setup(&self) -> bool {
+        !self.is_setup
+    }
+
+    /// Base URL of the GUI in mode `remote`, ending with `/`. `None` if not set or invalid.
+    pub fn remote_uri(&self) -> Option<Uri> {
+        let url = self.remote_url?.trim();
+        let uri: Uri = match url.ends_with('/') {
+            true => url.parse().ok()?,
+            false => format!("{url}/").parse().ok()?,
+        };
+        let scheme = uri.scheme()?;
+        (uri.host().is_some() && (*scheme == Scheme::HTTPS || *scheme == Scheme::HTTP))
+            .then_some(uri)
+    }
+
+    /// Hash of the pinned certificate of the GUI, if one is set (and valid).
+    pub fn tls_pin(&self) -> Option<[u8; 32]> {
+        self.tls_cert_sha256
+            .and_then(parse_fingerprint)
+            .or_else(|| self.tls_device_id.and_then(parse_device_id))
+    }
+
+    /// Address of the GUI as set in the settings.
+    pub fn gui_address(&self) -> GuiAddress {
+        GuiAddress {
+            host: self.host.unwrap_or(DEFAULT_HOST).to_string(),
+            port: self.port,
+            tls: self.tls,
+        }
+    }
+}
+
+/// Settings holding credentials, which must not be logged or reported. Also applies to the
+/// settings of each of the `instances`.
+pub const SECRET_SETTINGS: &[&str] = &["api_key", "basic_auth_pass"];
+/// Replaces the values of [`SECRET_SETTINGS`] where settings are shown or reported.
+pub const REDACTED: &str = "<redacted>";
+
+/// Replaces the values of the secret settings with [`REDACTED`], unless they are empty.
+pub fn redact(settings: &mut Value) {
+    if let Some(settings) = settings.as_object_mut() {
+        for key in SECRET_SETTINGS {
+            if let Some(value) = settings.get_mut(*key)
+                && *value != ""
+            {
+                *value = Value::from(REDACTED);
+            }
+        }
+        if let Some(Value::Array(instances)) = settings.get_mut("instances") {
+            instances.iter_mut().for_each(redact);
+        }
+    }
+}
+
+/// Reverts [`redact`] for an update of the settings: Secrets set to [`REDACTED`] get their
+/// value from `old`. Instances are matched by name.
+fn unredact(new: &mut Map<String, Value>, old: &Value) {
+    for key in SECRET_SETTINGS {
+        if new.get(*key).is_some_and(|value| value == REDACTED) {
+            match old.get(*key) {
+                Some(value) => new.insert(key.to_string(), value.clone()),
+                None => new.remove(*key),
+            };
+        }
+    }
+    if let Some(Value::Array(instances)) = new.get_mut("instances") {
+        let old_instances = old.get("instances").and_then(Value::as_array);
+        for instance in instances.iter_mut().filter_map(Value::as_object_mut) {
+            let old = old_instances
+                .into_iter()
+                .flatten()
+                .find(|old| old.get("name").is_some() && old.get("name") == instance.get("name"));
+            unredact(instance, old.unwrap_or(&Value::Null));
+        }
+    }
 }
 
 impl Settings {
     pub const SUPPORTED_VERSION: u32 = 2;
 
-    async fn new(path: &Path) -> Result<Self, SettingsError> {
-        let slf: Self = serde_json::from_str(&read_to_string(path).await?)?;
-        if slf.config_version != Self::SUPPORTED_VERSION {
+    /// Loads the settings and applies the overrides. Settings of older versions are migrated to
+    /// the current version and written back, after saving a backup of the old file.
+    /// Also restricts the settings file to the user and moves credentials into the configured
+    /// credentials store. Returns the settings with the problems found doing that.
+    async fn new(
+        path: &Path,
+        overrides: &Overrides,
+    ) -> Result<(Self, CredentialsWarnings), SettingsError> {
+        let permissions = match restrict_permissions(path).await {
+            Ok(None) => None,
+            Ok(Some(mode)) => Some(format!(
+                "The settings file was accessible by other users (mode {mode:o}) and was restricted \
+                 to mode {PRIVATE_MODE:o}. The credentials in it may have been exposed."
+            )),
+            Err(err) => Some(format!(
+                "The settings file may be accessible by other users, failed to restrict it: {err}"
+            )),
+        };
+        let mut warnings = CredentialsWarnings {
+            permissions,
+            store: None,
+        };
+        let mut value: Value = serde_json::from_str(&read_to_string(path).await?)?;
+        let original_version = config_version(&value)?;
+        let mut version = original_version;
+        while version < Self::SUPPORTED_VERSION {
+            value = migrate_step(version, value)?;
+            let next = config_version(&value)?;
+            info!("migrated settings from version {version} to {next}.");
+            version = next;
+        }
+        if version != Self::SUPPORTED_VERSION {
             return Err(SettingsError::UnsupportedVersion(
-                slf.config_version,
+                version,
                 Self::SUPPORTED_VERSION,
             ));
         }
+        let store = CredentialsStore::of(&overrides.apply(value.clone()));
+        let (full, moved) = match with_credentials(path, store, &mut value).await {
+            Ok(result) => result,
+            Err(err) => {
+                warnings.store = Some(store_unavailable(store, &err));
+                (value.clone(), false)
+            }
+        };
+        let slf = Self::validated(overrides.apply(full))?;
+        if original_version != Self::SUPPORTED_VERSION {
+            Self::save_migrated(path, original_version, &value).await?;
+        } else if moved {
+            write_atomically(path, serde_json::to_string(&value)?.as_bytes()).await?;
+        }
+        for warning in warnings.iter() {
+            warn!("{warning}");
+        }
+        Ok((slf, warnings))
+    }
+
+    /// Backs up the settings file as `<name>.v<version>.bak` and replaces it with the migrated
+    /// settings. The new file is written next to it first and then renamed, so the settings file
+    /// is never incomplete.
+    async fn save_migrated(
+        path: &Path,
+        old_version: u32,
+        migrated: &Value,
+    ) -> Result<(), SettingsError> {
+        let backup = path.with_extension(format!("v{old_version}.bak"));
+        copy(path, &backup).await?;
+        info!(
+            "saved settings of version {old_version} to {}.",
+            backup.display()
+        );
+        write_atomically(path, serde_json::to_string(migrated)?.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Validates the settings and deserializes them. Invalid values are only rejected once the
+    /// setup is done, before that the wizard is responsible for them.
+    fn validated(value: Value) -> Result<Self, SettingsError> {
+        let (errors, warnings): (Vec<_>, Vec<_>) = validate(&value)
+            .into_iter()
+            .partition(|issue| issue.severity == Severity::Error);
+        let slf: Self = match serde_json::from_value(value) {
+            Ok(slf) => slf,
+            Err(_) if !errors.is_empty() => return Err(SettingsError::Invalid(errors)),
+            Err(err) => return Err(err.into()),
+        };
+        if slf.is_not_setup() {
+            for issue in errors.iter().chain(&warnings) {
+                debug!("settings: {}: {}", issue.field, issue.message);
+            }
+        } else {
+            if !errors.is_empty() {
+                return Err(SettingsError::Invalid(errors));
+            }
+            for issue in &warnings {
+                warn!("settings: {}: {}", issue.field, issue.message);
+            }
+        }
         Ok(slf)
     }
 
+    pub fn config_version(&self) -> u32 {
+        self.config_version
+    }
+
+    /// Values of the [`SECRET_SETTINGS`] of all instances.
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        [&self.api_key, &self.basic_auth_pass]
+            .into_iter()
+            .chain(
+                self.instances
+                    .iter()
+                    .flat_map(|i| [&i.api_key, &i.basic_auth_pass]),
+            )
+            .map(String::as_str)
+    }
+
+    /// The instance described by the top-level settings.
+    pub fn default_instance(&self) -> Instance<'_> {
+        Instance {
+            name: DEFAULT_INSTANCE,
+            mode: self.mode,
+            service_name: &self.service_name,
+            flatpak_name: &self.flatpak_name,
+            flatpak_binary: &self.flatpak_binary,
+            home: None,
+            autostart: self.autostart,
+            keep_running_on_desktop: self.keep_running_on_desktop,
+            host: self.host.as_deref(),
+            port: self.port,
+            tls: self.tls,
+            remote_url: non_empty(&self.remote_url),
+            tls_cert_sha256: non_empty(&self.tls_cert_sha256),
+            tls_device_id: non_empty(&self.tls_device_id),
+            api_key: &self.api_key,
+            basic_auth_user: &self.basic_auth_user,
+            basic_auth_pass: &self.basic_auth_pass,
+            is_setup: !self.is_not_setup(),
+        }
+    }
+
+    /// All instances, starting with the default one.
+    pub fn instances(&self) -> impl Iterator<Item = Instance<'_>> {
+        let is_setup = !self.is_not_setup();
+        std::iter::once(self.default_instance()).chain(self.instances.iter().map(move |i| {
+            Instance {
+                name: &i.name,
+                mode: i.mode,
+                service_name: &i.service_name,
+                flatpak_name: &i.flatpak_name,
+                flatpak_binary: &i.flatpak_binary,
+                home: i.home.as_deref(),
+                autostart: i.autostart,
+                keep_running_on_desktop: i.keep_running_on_desktop,
+                host: i.host.as_deref(),
+                port: i.port,
+                tls: i.tls,
+                remote_url: non_empty(&i.remote_url),
+                tls_cert_sha256: non_empty(&i.tls_cert_sha256),
+                tls_device_id: non_empty(&i.tls_device_id),
+                api_key: &i.api_key,
+                basic_auth_user: &i.basic_auth_user,
+                basic_auth_pass: &i.basic_auth_pass,
+                is_setup,
+            }
+        }))
+    }
+
+    pub fn instance(&self, name: &str) -> Option<Instance<'_>> {
+        self.instances().find(|i| i.name == name)
+    }
+
     pub fn is_not_setup(&self) -> bool {
         self.is_setup != IsSetup::Bool(true)
     }
 }
 
+/// Problems with the protection of the credentials.
+#[derive(Debug, Default)]
+struct CredentialsWarnings {
+    /// About the permissions of the settings file.
+    permissions: Option<String>,
+    /// About the credentials store.
+    store: Option<String>,
+}
+
+impl CredentialsWarnings {
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.permissions.iter().chain(&self.store)
+    }
+}
+
+fn store_unavailable(store: CredentialsStore, err: &CredentialsError) -> String {
+    format!("The {store} is not available, the credentials are kept in the settings file: {err}")
+}
+
+/// Moves the credentials set in `file` (settings as in the settings file) into the credentials
+/// `store` and returns the settings with the stored credentials filled in, and whether `file`
+/// changed. `file` is only changed once the credentials are stored.
+async fn with_credentials(
+    path: &Path,
+    store: CredentialsStore,
+    file: &mut Value,
+) -> Result<(Value, bool), CredentialsError> {
+    let mut stored = store.load(path).await?;
+    let mut moved = false;
+    if store != CredentialsStore::Settings {
+        let mut without = file.clone();
+        let taken = Credentials::take_from(&mut without);
+        if !taken.is_empty() {
+            stored.extend(taken);
+            store.save(path, &stored).await?;
+            *file = without;
+            moved = true;
+            info!("moved credentials from the settings file to the {store}.");
+        }
+    }
+    let mut full = file.clone();
+    stored.fill_in(&mut full);
+    Ok((full, moved))
+}
+
+/// Writes the file via a temporary file next to it, so that it is never incomplete, not even
+/// after a power loss. The file is only accessible by the user, as it may hold credentials.
+pub async fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("json.watchdog-tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(PRIVATE_MODE)
+        .open(&tmp)
+        .await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    rename(&tmp, path).await?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Returns the `config_version` of settings of any version.
+fn config_version(value: &Value) -> Result<u32, SettingsError> {
+    match value.get("config_version") {
+        Some(Value::Number(n)) => n.as_u64().and_then(|v| v.try_into().ok()),
+        Some(Value::String(s)) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or(SettingsError::MissingVersion)
+}
+
+/// Migrates settings of `version` to the next version. Each step converts between the frozen
+/// structs of the two versions, so steps do not change when [`Settings`] does. Settings of
+/// [`Settings::SUPPORTED_VERSION`] are read as they are.
+fn migrate_step(version: u32, value: Value) -> Result<Value, SettingsError> {
+    Ok(match version {
+        1 => serde_json::to_value(SettingsV2::from(serde_json::from_value::<SettingsV1>(
+            value,
+        )?))?,
+        _ => {
+            return Err(SettingsError::UnsupportedVersion(
+                version,
+                Settings::SUPPORTED_VERSION,
+            ));
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+// v1: Only Flatpak was supported. Syncthing was started by the plugin directly.
+struct SettingsV1 {
+    autostart: bool,
+    flatpak_name: String,
+    #[serde(deserialize_with = "try_deserialize_u32_from_str")]
+    port: u32,
+    api_key: String,
+    basic_auth_user: String,
+    basic_auth_pass: String,
+    keep_running_on_desktop: bool,
+}
+
+#[derive(Debug, Serialize)]
+// v2 as it was introduced: Syncthing is run as systemd service or Flatpak. The settings added to
+// `Settings` since are optional, so these are valid settings of the current version.
+// Do not change.
+struct SettingsV2 {
+    config_version: u32,
+    mode: Mode,
+    service_name: String,
+    flatpak_name: String,
+    flatpak_binary: String,
+    autostart: Autostart,
+    keep_running_on_desktop: bool,
+    port: u32,
+    api_key: String,
+    basic_auth_user: String,
+    basic_auth_pass: String,
+    is_setup: IsSetup,
+}
+
+impl From<SettingsV1> for SettingsV2 {
+    fn from(old: SettingsV1) -> Self {
+        Self {
+            config_version: 2,
+            mode: Mode::Flatpak,
+            service_name: String::new(),
+            flatpak_name: old.flatpak_name,
+            flatpak_binary: "syncthing".to_string(),
+            autostart: match old.autostart {
+                true => Autostart::Gamescope,
+                false => Autostart::No,
+            },
+            keep_running_on_desktop: old.keep_running_on_desktop,
+            port: old.port,
+            api_key: old.api_key,
+            basic_auth_user: old.basic_auth_user,
+            basic_auth_pass: old.basic_auth_pass,
+            // Makes the UI show the wizard, optimized for migrating.
+            is_setup: IsSetup::Other("migratingV2".to_string()),
+        }
+    }
+}
+
 pub struct SettingsProvider {
     settings_path: PathBuf,
+    overrides: Overrides,
     current_settings: RwLock<Settings>,
-    backend_uri_cache: RwLock<Option<(Scheme, String)>>,
+    /// Problems with the protection of the credentials, found when loading the settings.
+    warnings: RwLock<CredentialsWarnings>,
+    backends: BackendCache,
+    /// Held while reloading or updating the settings, so that only one of them runs at a time.
+    /// `current_settings` is only locked to swap in the new settings, loading them (with the
+    /// credentials store) and applying them to the service may take a while.
+    changing: Mutex<()>,
 }
 
 impl SettingsProvider {
-    pub async fn new(settings_path: PathBuf) -> Result<Arc<Self>, SettingsError> {
-        let current_settings = RwLock::new(Settings::new(&settings_path).await?);
+    pub async fn new(
+        settings_path: PathBuf,
+        overrides: Overrides,
+    ) -> Result<Arc<Self>, SettingsError> {
+        let (settings, warnings) = Settings::new(&settings_path, &overrides).await?;
+        set_known_secrets(settings.secrets());
         Ok(Arc::new(Self {
             settings_path,
-            current_settings,
-            backend_uri_cache: RwLock::default(),
+            overrides,
+            current_settings: RwLock::new(settings),
+            warnings: RwLock::new(warnings),
+            backends: BackendCache::default(),
+            changing: Mutex::new(()),
         }))
     }
-    pub async fn backend_uri(&self) -> Result<(Scheme, String), SettingsError> {
-        let backend_uri_cache_read = self.backend_uri_cache.read().await;
-        match &*backend_uri_cache_read {
-            Some((scheme, uri)) => Ok((scheme.clone(), uri.clone())),
-            None => {
-                drop(backend_uri_cache_read);
-                let mut backend_uri_cache_write = self.backend_uri_cache.write().await;
-                let settings_read = self.current_settings.read().await;
-
-                // try https first, if that fails, http, if that also fails, eh
-                let client = make_unsafe_https_client::<Body>();
-
-                let https_uri = Uri::builder()
-                    .scheme(Scheme::HTTPS)
-                    .authority(format!("127.0.0.1:{}", settings_read.port))
-                    .path_and_query("/")
-                    .build()?;
-                let https_req = Request::builder()
-                    .method(Method::HEAD)
-                    .uri(https_uri.clone())
-                    .body(Body::empty())?;
-                match client.request(https_req).await {
-                    Ok(_) => {
-                        *backend_uri_cache_write = Some((Scheme::HTTPS, https_uri.to_string()));
-                        Ok((Scheme::HTTPS, https_uri.to_string()))
-                    }
-                    Err(_) => {
-                        // http time!
-                        let http_uri = Uri::builder()
-                            .scheme(Scheme::HTTP)
-                            .authority(format!("127.0.0.1:{}", settings_read.port))
-                            .path_and_query("/")
-                            .build()?;
-                        let http_req = Request::builder()
-                            .method(Method::HEAD)
-                            .uri(http_uri.clone())
-                            .body(Body::empty())?;
-                        match client.request(http_req).await {
-                            Ok(_) => {
-                                *backend_uri_cache_write =
-                                    Some((Scheme::HTTP, http_uri.to_string()));
-                                Ok((Scheme::HTTP, http_uri.to_string()))
-                            }
-                            Err(_) => Err(SettingsError::BackendOffline),
-                        }
-                    }
-                }
-            }
+    pub fn path(&self) -> &Path {
+        &self.settings_path
+    }
+
+    /// Problems with the protection of the credentials, e.g. a settings file that was readable
+    /// by other users.
+    pub async fn warnings(&self) -> Vec<String> {
+        self.warnings.read().await.iter().cloned().collect()
+    }
+
+    /// Each setting with its current value (without credentials) and where it comes from.
+    pub async fn effective(
+        &self,
+    ) -> Result<BTreeMap<&'static str, EffectiveSetting>, SettingsError> {
+        let file: Value = serde_json::from_str(&read_to_string(&self.settings_path).await?)?;
+        let mut current = serde_json::to_value(&*self.settings().await)?;
+        redact(&mut current);
+        Ok(field_names()
+            .map(|field| {
+                let source = self
+                    .overrides
+                    .source(field)
+                    .unwrap_or(match file.get(field) {
+                        Some(_) => Source::File,
+                        None => Source::Default,
+                    });
+                let value = current.get(field).cloned().unwrap_or(Value::Null);
+                (field, EffectiveSetting { value, source })
+            })
+            .collect())
+    }
+
+    /// Returns how the Syncthing GUI of the instance `name` is reached, probing it if the last
+    /// result expired. See [`crate::backend`].
+    pub async fn backend(&self, name: &str) -> Result<ProbeResult, SettingsError> {
+        if let Some(result) = self.backends.fresh(name).await {
+            return Ok(result);
         }
+        // Not holding the settings while probing.
+        let target = ProbeTarget::new(
+            &self
+                .current_settings
+                .read()
+                .await
+                .instance(name)
+                .ok_or_else(|| SettingsError::UnknownInstance(name.to_string()))?,
+        );
+        Ok(self.backends.probe(target).await)
+    }
+
+    /// Returns the last probe result for the instance `name` without probing.
+    pub async fn last_backend(&self, name: &str) -> Option<ProbeResult> {
+        self.backends.last(name).await
+    }
+
+    /// Returns the URI of the Syncthing GUI of the instance `name`, with path `/`, and how its
+    /// certificate is verified.
+    pub async fn backend_uri(&self, name: &str) -> Result<(Uri, TlsVerification), SettingsError> {
+        let result = self.backend(name).await?;
+        match result.uri {
+            Some(uri) => Ok((uri.parse()?, result.verification)),
+            None => Err(SettingsError::BackendOffline),
+        }
+    }
+
+    /// Probe the instance `name` again on the next request, e.g. after the proxy failed to
+    /// connect to it.
+    pub async fn invalidate_backend(&self, name: &str) {
+        self.backends.invalidate(name).await;
     }
 
     pub async fn reload(&self) -> Result<(), SettingsError> {
-        let (mut cs_lock, mut buc_lock) = join!(
-            self.current_settings.write(),
-            self.backend_uri_cache.write()
-        );
-        *cs_lock = Settings::new(&self.settings_path).await?;
-        *buc_lock = None;
+        let _changing = self.changing.lock().await;
+        let (settings, warnings) = Settings::new(&self.settings_path, &self.overrides).await?;
+        set_known_secrets(settings.secrets());
+        *self.current_settings.write().await = settings;
+        *self.warnings.write().await = warnings;
+        self.backends.clear().await;
+        Ok(())
+    }
+
+    /// Applies a partial update to the settings file as one transaction: The merged settings are
+    /// validated, written to the settings file and the service is re-initialized with them (and
+    /// the overrides). If that fails, the previous settings are restored.
+    /// `null` removes an optional setting, secrets set to [`REDACTED`] are left unchanged.
+    pub async fn update(&self, mut patch: Map<String, Value>) -> Result<(), SettingsError> {
+        let _changing = self.changing.lock().await;
+        let unknown = patch
+            .keys()
+            .filter(|key| !is_known_field(key))
+            .map(|key| ValidationIssue::unknown_field(key))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(SettingsError::Invalid(unknown));
+        }
+        let old_content = read(&self.settings_path).await?;
+        let mut value: Value = serde_json::from_slice(&old_content)?;
+        // Before unredacting, which turns redacted credentials kept in a credentials store into
+        // empty values.
+        let cleared = Credentials::cleared_in(&Value::Object(patch.clone()));
+        unredact(&mut patch, &value);
+        if let Some(object) = value.as_object_mut() {
+            for (key, new) in patch {
+                match new {
+                    Value::Null => object.remove(&key),
+                    new => object.insert(key, new),
+                };
+            }
+        }
+        // Credentials in the patch go into the credentials store, the others are taken from it.
+        let store = CredentialsStore::of(&self.overrides.apply(value.clone()));
+        let (store, old_stored, store_warning) = match store.load(&self.settings_path).await {
+            Ok(stored) => (store, stored, None),
+            Err(err) => {
+                let warning = store_unavailable(store, &err);
+                warn!("{warning}");
+                let store = CredentialsStore::Settings;
+                (store, store.load(&self.settings_path).await?, Some(warning))
+            }
+        };
+        let taken = match store {
+            CredentialsStore::Settings => Credentials::default(),
+            _ => Credentials::take_from(&mut value.clone()),
+        };
+        let mut stored = old_stored.clone();
+        stored.remove(&cleared);
+        stored.fill_in(&mut value);
+        stored.extend(taken);
+        let changed = store != CredentialsStore::Settings && stored != old_stored;
+        let full_settings = Settings::validated(value)?;
+        let new = match self.overrides.is_empty() {
+            true => full_settings.clone(),
+            false => {
+                Settings::validated(self.overrides.apply(serde_json::to_value(&full_settings)?))?
+            }
+        };
+        let mut file_settings = serde_json::to_value(&full_settings)?;
+        if store != CredentialsStore::Settings {
+            Credentials::take_from(&mut file_settings);
+        }
+
+        if changed {
+            store.save(&self.settings_path, &stored).await?;
+        }
+        write_atomically(
+            &self.settings_path,
+            serde_json::to_string(&file_settings)?.as_bytes(),
+        )
+        .await?;
+        set_known_secrets(new.secrets());
+        let old_store_warning = replace(&mut self.warnings.write().await.store, store_warning);
+        let old = replace(&mut *self.current_settings.write().await, new.clone());
+        self.backends.clear().await;
+        if let Err(err) = init_service(&new).await {
+            warn!("failed to apply new settings, restoring the previous ones: {err:?}");
+            if let Err(err) = write_atomically(&self.settings_path, &old_content).await {
+                error!("failed to restore settings file: {err:?}");
+            }
+            if changed && let Err(err) = store.save(&self.settings_path, &old_stored).await {
+                error!("failed to restore credentials in the {store}: {err:?}");
+            }
+            set_known_secrets(old.secrets());
+            self.warnings.write().await.store = old_store_warning;
+            *self.current_settings.write().await = old.clone();
+            self.backends.clear().await;
+            if let Err(err) = init_service(&old).await {
+                warn!("failed to re-init service with the previous settings: {err:?}");
+            }
+            return Err(SettingsError::Apply(err));
+        }
+        info!("settings updated.");
         Ok(())
     }
 
+    /// Whether the current settings are the ones in `file` (settings as in the settings file),
+    /// e.g. because the watchdog wrote them. Credentials that are empty in `file` are not
+    /// compared, they may be kept in a credentials store.
+    pub async fn matches(&self, file: &Value) -> bool {
+        let Ok(mut current) = serde_json::to_value(&*self.settings().await) else {
+            return false;
+        };
+        let mut file = self.overrides.apply(file.clone());
+        let current_credentials = Credentials::take_from(&mut current);
+        let file_credentials = Credentials::take_from(&mut file);
+        let mut merged = current_credentials.clone();
+        merged.extend(file_credentials);
+        current == file && merged == current_credentials
+    }
+
     pub async fn settings(&self) -> impl Deref<Target = Settings> + '_ {
         self.current_settings.read().await
     }
@@ -180,14 +898,14 @@ impl SettingsProvider {
 
 /// Try to deserialize an u32 from a string if it is a string for some reasons. Otherwise
 /// deserialize directly.
-fn try_deserialize_u32_from_str<'de, D>(deserializer: D) -> Result<u32, D::Error>
+pub fn try_deserialize_u32_from_str<'de, D>(deserializer: D) -> Result<u32, D::Error>
 where
     D: Deserializer<'de>,
 {
     #[derive(Deserialize)]
     #[serde(untagged)]
-    enum MaybeU32<'a> {
-        String(&'a str),
+    enum MaybeU32 {
+        String(String),
         U32(u32),
     }
 
@@ -197,10 +915,162 @@ where
                 return Ok(num);
             }
             Err(serde::de::Error::invalid_type(
-                Unexpected::Str(string),
+                Unexpected::Str(&string),
                 &"an u32 integer",
             ))
         }
         MaybeU32::U32(v) => Ok(v),
     }
 }
+
+/// Serializes the log level in lowercase, as the plugin writes it.
+fn serialize_log_level<S>(level: &Option<LevelFilter>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    level
+        .map(|level| level.as_str().to_ascii_lowercase())
+        .serialize(serializer)
+}
+
+/// Helpers for the tests of modules working with settings.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use serde_json::{Value, json};
+    use std::path::{Path, PathBuf};
+    use std::{env, fs, process};
+
+    /// Minimal valid settings of version 2, with `fields` added or replaced.
+    pub fn settings_v2(fields: Value) -> Value {
+        let mut settings = json!({
+            "config_version": 2,
+            "mode": "flatpak",
+            "service_name": "",
+            "flatpak_name": "me.kozec.syncthingtk",
+            "flatpak_binary": "syncthing",
+            "autostart": "no",
+            "keep_running_on_desktop": false,
+            "port": 8384,
+            "api_key": "",
+            "basic_auth_user": "",
+            "basic_auth_pass": "",
+            "is_setup": true,
+        });
+        if let (Some(settings), Value::Object(fields)) = (settings.as_object_mut(), fields) {
+            settings.extend(fields);
+        }
+        settings
+    }
+
+    /// A temporary directory, removed with its content when dropped, also if the test fails.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("decky-watchdog-{name}-{}", process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{TempDir, settings_v2};
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    /// Settings of version 1, as the plugin wrote them.
+    const SETTINGS_V1: &str = include_str!("../tests/fixtures/settings-v1.json");
+
+    fn migrated_v2() -> Value {
+        settings_v2(json!({
+            "autostart": "gamescope",
+            "api_key": "Xk4pT2dFqW9sLmN7vB3cR8yH",
+            "basic_auth_user": "deck",
+            "basic_auth_pass": "pass\"word\\",
+            "is_setup": "migratingV2",
+        }))
+    }
+
+    #[test]
+    fn migrates_v1_to_v2() {
+        let v1 = serde_json::from_str(SETTINGS_V1).unwrap();
+        assert_eq!(migrate_step(1, v1).unwrap(), migrated_v2());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(matches!(
+            migrate_step(0, json!({})),
+            Err(SettingsError::UnsupportedVersion(
+                0,
+                Settings::SUPPORTED_VERSION
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn loads_v1_settings_file() {
+        let dir = TempDir::new("settings");
+        let path = dir.path().join("decky-syncthing.json");
+        fs::write(&path, SETTINGS_V1).unwrap();
+
+        let (settings, _) = Settings::new(&path, &Overrides::default()).await.unwrap();
+        let instance = settings.default_instance();
+        assert_eq!(settings.config_version(), Settings::SUPPORTED_VERSION);
+        assert_eq!(instance.mode, Mode::Flatpak);
+        assert_eq!(instance.flatpak_name, "me.kozec.syncthingtk");
+        assert_eq!(instance.autostart, Autostart::Gamescope);
+        assert_eq!(instance.port, 8384);
+        assert_eq!(instance.api_key, "Xk4pT2dFqW9sLmN7vB3cR8yH");
+        assert_eq!(instance.basic_auth_pass, "pass\"word\\");
+        assert_eq!(settings.is_setup, IsSetup::Other("migratingV2".to_string()));
+        // The old file is kept as backup and replaced by the migrated settings.
+        let backup = fs::read_to_string(path.with_extension("v1.bak")).unwrap();
+        assert_eq!(backup, SETTINGS_V1);
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, migrated_v2());
+    }
+
+    #[tokio::test]
+    async fn reports_effective_sources() {
+        let dir = TempDir::new("effective");
+        let path = dir.path().join("decky-syncthing.json");
+        let file = settings_v2(json!({"host": "::1", "api_key": "Xk4pT2dFqW9sLmN7vB3cR8yH"}));
+        fs::write(&path, file.to_string()).unwrap();
+        let env = [
+            ("DECKY_ST_PORT".into(), "8385".into()),
+            ("DECKY_ST_KEEP_RUNNING_ON_DESKTOP".into(), "false".into()),
+        ];
+        let cli = [("keep_running_on_desktop".to_string(), "true".to_string())];
+        let overrides = Overrides::new(env, &cli).unwrap();
+        let settings = SettingsProvider::new(path, overrides).await.unwrap();
+
+        let effective = settings.effective().await.unwrap();
+        let effective = |field| {
+            let setting = &effective[field];
+            (setting.value.clone(), setting.source)
+        };
+        assert_eq!(effective("port"), (json!(8385), Source::Env));
+        assert_eq!(
+            effective("keep_running_on_desktop"),
+            (json!(true), Source::Cli)
+        );
+        assert_eq!(effective("host"), (json!("::1"), Source::File));
+        assert_eq!(effective("mode"), (json!("flatpak"), Source::File));
+        assert_eq!(effective("api_key"), (json!(REDACTED), Source::File));
+        assert_eq!(effective("listen_address"), (Value::Null, Source::Default));
+    }
+}
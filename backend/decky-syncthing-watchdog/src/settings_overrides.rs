@@ -0,0 +1,243 @@
+//! Overrides of the settings via environment variables and command line arguments, to run the
+//! watchdog outside of Decky (e.g. against a throwaway Syncthing) without editing the settings
+//! file. Overrides are never written to the settings file.
+//! Precedence, highest first: command line (`run --set <setting>=<value>`, `--listen`, ...),
+//! environment (`DECKY_ST_<SETTING>`, e.g. `DECKY_ST_PORT`), settings file, defaults.
+
+use crate::settings_validation::{field_names, is_known_field, parse_field_value};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use thiserror::Error;
+
+/// Prefix of the environment variables overriding settings.
+pub const ENV_PREFIX: &str = "DECKY_ST_";
+
+#[derive(Debug, Error)]
+pub enum OverrideError {
+    #[error("Unknown setting `{0}`.")]
+    UnknownSetting(String),
+    #[error("`{0}` can not be overridden.")]
+    NotOverridable(String),
+    #[error("Environment variable {0} is not valid UTF-8.")]
+    NotUnicode(String),
+    #[error("Invalid value for `{0}`: {1}")]
+    Serialize(String, serde_json::Error),
+}
+
+/// Where the value of a setting comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Cli,
+    Env,
+    File,
+    Default,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Source::Cli => "command line",
+            Source::Env => "environment",
+            Source::File => "settings file",
+            Source::Default => "default",
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EffectiveSetting {
+    /// `null` if not set.
+    pub value: Value,
+    pub source: Source,
+}
+
+#[derive(Debug, Default)]
+pub struct Overrides {
+    values: BTreeMap<String, (Value, Source)>,
+}
+
+impl Overrides {
+    /// Collects the overrides from the environment variables `vars` (usually
+    /// [`std::env::vars_os`]) and from `--set` arguments given as `(setting, value)`.
+    pub fn new(
+        vars: impl IntoIterator<Item = (OsString, OsString)>,
+        cli: &[(String, String)],
+    ) -> Result<Self, OverrideError> {
+        let vars: BTreeMap<_, _> = vars.into_iter().collect();
+        let mut slf = Self::default();
+        for field in field_names().filter(|field| is_overridable(field)) {
+            let var = env_var(field);
+            if let Some(raw) = vars.get(OsStr::new(&var)) {
+                let Some(raw) = raw.to_str() else {
+                    return Err(OverrideError::NotUnicode(var));
+                };
+                slf.insert(field, raw, Source::Env)?;
+            }
+        }
+        for (field, raw) in cli {
+            slf.insert(field, raw, Source::Cli)?;
+        }
+        Ok(slf)
+    }
+
+    /// Overrides `field` with the value of a dedicated command line argument (e.g. `--listen`).
+    pub fn with_cli(
+        mut self,
+        field: &str,
+        value: Option<impl Serialize>,
+    ) -> Result<Self, OverrideError> {
+        if let Some(value) = value {
+            let value = serde_json::to_value(value)
+                .map_err(|e| OverrideError::Serialize(field.to_string(), e))?;
+            self.values.insert(field.to_string(), (value, Source::Cli));
+        }
+        Ok(self)
+    }
+
+    fn insert(&mut self, field: &str, raw: &str, source: Source) -> Result<(), OverrideError> {
+        if !is_overridable(field) {
+            return Err(match is_known_field(field) {
+                true => OverrideError::NotOverridable(field.to_string()),
+                false => OverrideError::UnknownSetting(field.to_string()),
+            });
+        }
+        let value = parse_field_value(field, raw)
+            .ok_or_else(|| OverrideError::UnknownSetting(field.to_string()))?;
+        self.values.insert(field.to_string(), (value, source));
+        Ok(())
+    }
+
+    /// Applies the overrides to the settings (as in the settings file).
+    pub fn apply(&self, mut settings: Value) -> Value {
+        if let Some(settings) = settings.as_object_mut() {
+            for (field, (value, _)) in &self.values {
+                settings.insert(field.clone(), value.clone());
+            }
+        }
+        settings
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Whether `field` is overridden and by what.
+    pub fn source(&self, field: &str) -> Option<Source> {
+        self.values.get(field).map(|(_, source)| *source)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Source)> {
+        self.values
+            .iter()
+            .map(|(field, (_, source))| (field.as_str(), *source))
+    }
+}
+
+/// The version describes the settings file, overriding it makes no sense.
+fn is_overridable(field: &str) -> bool {
+    is_known_field(field) && field != "config_version"
+}
+
+/// Name of the environment variable overriding `field`, e.g. `DECKY_ST_PORT`.
+fn env_var(field: &str) -> String {
+    format!(
+        "{ENV_PREFIX}{}",
+        field.trim_start_matches('_').to_ascii_uppercase()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn vars<const N: usize>(vars: [(&str, &str); N]) -> Vec<(OsString, OsString)> {
+        vars.into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect()
+    }
+
+    fn set(field: &str, value: &str) -> (String, String) {
+        (field.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let env = vars([
+            ("DECKY_ST_PORT", "8385"),
+            ("DECKY_ST_MODE", "systemd"),
+            (
+                "DECKY_ST_WIZARD_FORCE_FLATPAK_CONFIG_FOR",
+                "me.kozec.syncthingtk",
+            ),
+            ("DECKY_ST_UNKNOWN", "ignored"),
+            ("PORT", "1"),
+        ]);
+        let overrides = Overrides::new(env, &[set("port", "8386")])
+            .unwrap()
+            .with_cli("log_format", Some("json"))
+            .unwrap()
+            .with_cli("listen_address", None::<&str>)
+            .unwrap();
+
+        assert_eq!(overrides.source("port"), Some(Source::Cli));
+        assert_eq!(overrides.source("mode"), Some(Source::Env));
+        assert_eq!(
+            overrides.source("_wizard_force_flatpak_config_for"),
+            Some(Source::Env)
+        );
+        assert_eq!(overrides.source("log_format"), Some(Source::Cli));
+        assert_eq!(overrides.source("listen_address"), None);
+        assert_eq!(overrides.source("flatpak_name"), None);
+
+        let file = json!({"port": 8384, "mode": "flatpak", "flatpak_name": "syncthing"});
+        assert_eq!(
+            overrides.apply(file),
+            json!({
+                "port": 8386,
+                "mode": "systemd",
+                "flatpak_name": "syncthing",
+                "_wizard_force_flatpak_config_for": "me.kozec.syncthingtk",
+                "log_format": "json",
+            })
+        );
+    }
+
+    #[test]
+    fn no_overrides() {
+        let overrides = Overrides::new(vars([("HOME", "/home/deck")]), &[]).unwrap();
+        assert!(overrides.is_empty());
+        assert_eq!(
+            overrides.apply(json!({"port": 8384})),
+            json!({"port": 8384})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        assert!(matches!(
+            Overrides::new(vars([]), &[set("no_such_setting", "1")]),
+            Err(OverrideError::UnknownSetting(field)) if field == "no_such_setting"
+        ));
+        assert!(matches!(
+            Overrides::new(vars([]), &[set("config_version", "1")]),
+            Err(OverrideError::NotOverridable(field)) if field == "config_version"
+        ));
+        // Not overridable via the environment either, but not an error.
+        assert!(
+            Overrides::new(vars([("DECKY_ST_CONFIG_VERSION", "1")]), &[])
+                .unwrap()
+                .is_empty()
+        );
+        let not_unicode = [("DECKY_ST_PORT".into(), OsString::from_vec(vec![0xff]))];
+        assert!(matches!(
+            Overrides::new(not_unicode, &[]),
+            Err(OverrideError::NotUnicode(var)) if var == "DECKY_ST_PORT"
+        ));
+    }
+}
@@ -0,0 +1,618 @@
+//! Validation of the settings.
+//! Collects all problems of a settings JSON with the field they belong to, so that the wizard can
+//! point out the exact field. Used when loading the settings and via the API.
+
+use crate::cors::is_valid_origin;
+use crate::credentials::{CredentialsStore, scrub};
+use crate::gui_address::is_valid_host;
+use crate::host_header::is_valid_allowed_host;
+use crate::logging::LogFormat;
+use crate::proxy::ProxyPolicy;
+use crate::settings::{
+    Autostart, Instance, InstanceSettings, IsSetup, Mode, Settings, try_deserialize_u32_from_str,
+};
+use crate::tls::{is_loopback, parse_device_id, parse_fingerprint};
+use log::LevelFilter;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The watchdog can not work with these settings.
+    Error,
+    /// The settings work, but probably not as intended.
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationIssue {
+    /// Name of the setting.
+    pub field: String,
+    pub severity: Severity,
+    pub message: String,
+    /// What to do about it, to show to the user.
+    pub hint: String,
+}
+
+impl ValidationIssue {
+    pub fn unknown_field(field: &str) -> Self {
+        Self::new(
+            field,
+            Severity::Error,
+            "Unknown setting.",
+            "Check the spelling of the setting.",
+        )
+    }
+
+    /// Known secrets are removed from the message, it may quote the invalid value.
+    fn new(field: &str, severity: Severity, message: impl Into<String>, hint: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            severity,
+            message: scrub(&message.into()).into_owned(),
+            hint: hint.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    /// Whether there are no errors. There may still be warnings.
+    pub valid: bool,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl From<Vec<ValidationIssue>> for ValidationReport {
+    fn from(issues: Vec<ValidationIssue>) -> Self {
+        Self {
+            valid: !issues.iter().any(|i| i.severity == Severity::Error),
+            issues,
+        }
+    }
+}
+
+type TypeCheck = fn(&Value) -> Result<(), String>;
+
+fn is<T: DeserializeOwned>(value: &Value) -> Result<(), String> {
+    T::deserialize(value).map(drop).map_err(|e| e.to_string())
+}
+
+fn is_u32(value: &Value) -> Result<(), String> {
+    try_deserialize_u32_from_str(value)
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+/// Name, type check, whether it is required and the hint for each setting.
+const FIELDS: &[(&str, TypeCheck, bool, &str)] = &[
+    ("config_version", is_u32, true, "Must be a number."),
+    (
+        "mode",
+        is::<Mode>,
+        true,
+        "Must be `systemd`, `systemd_system`, `flatpak` or `remote`.",
+    ),
+    ("service_name", is::<String>, true, "Must be a string."),
+    ("flatpak_name", is::<String>, true, "Must be a string."),
+    ("flatpak_binary", is::<String>, true, "Must be a string."),
+    (
+        "autostart",
+        is::<Autostart>,
+        true,
+        "Must be `no`, `boot` or `gamescope`.",
+    ),
+    (
+        "keep_running_on_desktop",
+        is::<bool>,
+        true,
+        "Must be `true` or `false`.",
+    ),
+    ("port", is_u32, true, "Must be a port number, e.g. 8384."),
+    ("api_key", is::<String>, true, "Must be a string."),
+    ("basic_auth_user", is::<String>, true, "Must be a string."),
+    ("basic_auth_pass", is::<String>, true, "Must be a string."),
+    (
+        "is_setup",
+        is::<IsSetup>,
+        true,
+        "Must be `true`, `false` or `migratingV2`.",
+    ),
+    (
+        "_wizard_force_flatpak_config_for",
+        is::<Option<String>>,
+        false,
+        "Must be a string.",
+    ),
+    (
+        "listen_address",
+        is::<Option<SocketAddr>>,
+        false,
+        "Must be an address with port, e.g. `127.0.0.1:58384`.",
+    ),
+    (
+        "control_socket",
+        is::<Option<PathBuf>>,
+        false,
+        "Must be a path.",
+    ),
+    (
+        "log_level",
+        is::<Option<LevelFilter>>,
+        false,
+        "Must be `error`, `warn`, `info`, `debug`, `trace` or `off`.",
+    ),
+    (
+        "log_format",
+        is::<Option<LogFormat>>,
+        false,
+        "Must be `text` or `json`.",
+    ),
+    (
+        "instances",
+        is::<Vec<InstanceSettings>>,
+        false,
+        "Must be a list of instances, each with at least `name`, `mode`, `autostart` and `port`.",
+    ),
+    (
+        "credentials_store",
+        is::<Option<CredentialsStore>>,
+        false,
+        "Must be `settings`, `file` or `secret_service`.",
+    ),
+    (
+        "host",
+        is::<Option<String>>,
+        false,
+        "Must be a host name or IP address, e.g. `127.0.0.1` or `::1`.",
+    ),
+    (
+        "tls",
+        is::<Option<bool>>,
+        false,
+        "Must be `true` or `false`.",
+    ),
+    (
+        "remote_url",
+        is::<Option<String>>,
+        false,
+        "Must be a URL, e.g. `https://nas.local:8384/`.",
+    ),
+    (
+        "tls_cert_sha256",
+        is::<Option<String>>,
+        false,
+        "Must be a SHA-256 fingerprint (64 hex digits).",
+    ),
+    (
+        "tls_device_id",
+        is::<Option<String>>,
+        false,
+        "Must be a Syncthing device ID.",
+    ),
+    (
+        "strip_client_api_key",
+        is::<Option<bool>>,
+        false,
+        "Must be `true` or `false`.",
+    ),
+    (
+        "proxy_policy",
+        is::<Option<ProxyPolicy>>,
+        false,
+        "Must be an object with `read_only` (`true` or `false`) and/or `allow`, a list of rules like `GET /rest/*`.",
+    ),
+    (
+        "cors_origins",
+        is::<Option<Vec<String>>>,
+        false,
+        "Must be a list of origins, e.g. `[\"https://steamloopback.host\"]`.",
+    ),
+    (
+        "allowed_hosts",
+        is::<Option<Vec<String>>>,
+        false,
+        "Must be a list of hosts, e.g. `[\"steamdeck.local:58384\"]`.",
+    ),
+];
+
+pub fn is_known_field(field: &str) -> bool {
+    FIELDS.iter().any(|(name, ..)| *name == field)
+}
+
+/// Names of all settings.
+pub fn field_names() -> impl Iterator<Item = &'static str> {
+    FIELDS.iter().map(|(name, ..)| *name)
+}
+
+/// Parses a value of `field` given as text, e.g. in an environment variable: As JSON if that
+/// has the right type (`8384`, `true`, `[...]`), otherwise as string (`flatpak`).
+/// Returns `None` for unknown settings.
+pub fn parse_field_value(field: &str, raw: &str) -> Option<Value> {
+    let (_, check, ..) = FIELDS.iter().find(|(name, ..)| *name == field)?;
+    Some(match serde_json::from_str(raw) {
+        Ok(value) if check(&value).is_ok() => value,
+        _ => Value::String(raw.to_string()),
+    })
+}
+
+/// Validates settings of the current version, given as raw JSON.
+pub fn validate_json(json: &[u8]) -> Vec<ValidationIssue> {
+    match serde_json::from_slice(json) {
+        Ok(value) => validate(&value),
+        Err(err) => vec![ValidationIssue::new(
+            "",
+            Severity::Error,
+            err.to_string(),
+            "The settings are not valid JSON.",
+        )],
+    }
+}
+
+/// Validates settings of the current version. Returns all problems found. Problems with the
+/// values are only checked if all settings have the right type.
+pub fn validate(value: &Value) -> Vec<ValidationIssue> {
+    let Some(object) = value.as_object() else {
+        return vec![ValidationIssue::new(
+            "",
+            Severity::Error,
+            "Settings are not a JSON object.",
+            "The settings file is broken. Reset the settings.",
+        )];
+    };
+
+    let mut issues = Vec::new();
+    for (field, check, required, hint) in FIELDS {
+        match object.get(*field) {
+            Some(value) => {
+                if let Err(err) = check(value) {
+                    issues.push(ValidationIssue::new(field, Severity::Error, err, hint));
+                }
+            }
+            None if *required => {
+                issues.push(ValidationIssue::new(
+                    field,
+                    Severity::Error,
+                    "Missing.",
+                    hint,
+                ));
+            }
+            None => {}
+        }
+    }
+    if !issues.is_empty() {
+        return issues;
+    }
+    match serde_json::from_value::<Settings>(value.clone()) {
+        Ok(settings) => validate_values(&settings, &mut issues),
+        Err(err) => issues.push(ValidationIssue::new(
+            "",
+            Severity::Error,
+            err.to_string(),
+            "The settings file is broken. Reset the settings.",
+        )),
+    }
+    issues
+}
+
+fn validate_values(settings: &Settings, issues: &mut Vec<ValidationIssue>) {
+    use Severity::*;
+
+    if settings.config_version() != Settings::SUPPORTED_VERSION {
+        issues.push(ValidationIssue::new(
+            "config_version",
+            Error,
+            format!(
+                "Unsupported version {}, need {}.",
+                settings.config_version(),
+                Settings::SUPPORTED_VERSION
+            ),
+            "Update the plugin.",
+        ));
+    }
+    let instances = settings.instances().collect::<Vec<_>>();
+    for (i, instance) in instances.iter().enumerate() {
+        validate_instance(instance, &instances[..i], &instance_field(i), issues);
+    }
+    if let Some(name) = &settings._wizard_force_flatpak_config_for
+        && !is_valid_app_id(name)
+    {
+        issues.push(ValidationIssue::new(
+            "_wizard_force_flatpak_config_for",
+            Warning,
+            format!("`{name}` is not a valid Flatpak app ID."),
+            "Enter the app ID of the Flatpak, e.g. `me.kozec.syncthingtk`.",
+        ));
+    }
+    if !settings.basic_auth_user.is_empty() && settings.basic_auth_pass.is_empty() {
+        issues.push(ValidationIssue::new(
+            "basic_auth_pass",
+            Warning,
+            "A user but no password is set for the Syncthing GUI.",
+            "Enter the password of the Syncthing GUI user.",
+        ));
+    }
+    if let Some(addr) = settings.listen_address
+        && !addr.ip().is_loopback()
+    {
+        issues.push(ValidationIssue::new(
+            "listen_address",
+            Warning,
+            format!("{addr} is not a loopback address and will be ignored."),
+            "Use an address like `127.0.0.1:58384`.",
+        ));
+    }
+    if let Some(path) = &settings.control_socket
+        && path.is_relative()
+    {
+        issues.push(ValidationIssue::new(
+            "control_socket",
+            Warning,
+            format!("{} is a relative path.", path.display()),
+            "Use an absolute path.",
+        ));
+    }
+    if let Some(ProxyPolicy {
+        allow: Some(rules), ..
+    }) = &settings.proxy_policy
+        && rules.is_empty()
+    {
+        issues.push(ValidationIssue::new(
+            "proxy_policy",
+            Warning,
+            "The allowlist is empty, the proxy forwards no requests to Syncthing.",
+            "Add rules like `GET *` to `allow`, or remove it.",
+        ));
+    }
+    for origin in settings.cors_origins.iter().flatten() {
+        if !is_valid_origin(origin) {
+            issues.push(ValidationIssue::new(
+                "cors_origins",
+                Error,
+                format!("`{origin}` is not an origin."),
+                "Use the scheme, host and port only, e.g. `https://steamloopback.host`.",
+            ));
+        }
+    }
+    for host in settings.allowed_hosts.iter().flatten() {
+        if !is_valid_allowed_host(host) {
+            issues.push(ValidationIssue::new(
+                "allowed_hosts",
+                Error,
+                format!("`{host}` is not a host."),
+                "Use a host name or IP address with optional port, e.g. `steamdeck.local:58384`.",
+            ));
+        }
+    }
+}
+
+/// Returns a function prefixing the names of the settings of the `i`th instance. The first
+/// instance is the default one, its settings are the top-level settings.
+fn instance_field(i: usize) -> impl Fn(&str) -> String {
+    move |name| match i {
+        0 => name.to_string(),
+        i => format!("instances[{}].{name}", i - 1),
+    }
+}
+
+/// Validates the settings of an instance. `previous` are the instances before it, to check
+/// for conflicts.
+fn validate_instance(
+    instance: &Instance,
+    previous: &[Instance],
+    field: &impl Fn(&str) -> String,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    use Severity::*;
+
+    // The first one is the default instance, which has no name of its own.
+    if !previous.is_empty() {
+        if !is_valid_instance_name(instance.name) {
+            issues.push(ValidationIssue::new(
+                &field("name"),
+                Error,
+                format!("`{}` is not a valid instance name.", instance.name),
+                "Use up to 64 letters, digits, `-` and `_`, e.g. `family`.",
+            ));
+        } else if previous.iter().any(|other| other.name == instance.name) {
+            issues.push(ValidationIssue::new(
+                &field("name"),
+                Error,
+                format!("There is more than one instance named `{}`.", instance.name),
+                "Give each instance its own name.",
+            ));
+        }
+    }
+    if let Some(host) = instance.host
+        && !is_valid_host(host)
+    {
+        issues.push(ValidationIssue::new(
+            &field("host"),
+            Error,
+            format!("`{host}` is not a valid host name or IP address."),
+            "Use the host of the Syncthing GUI address without port, e.g. `127.0.0.1` or `::1`.",
+        ));
+    }
+    if !(1..=65535).contains(&instance.port) {
+        issues.push(ValidationIssue::new(
+            &field("port"),
+            Error,
+            format!("{} is not a valid port.", instance.port),
+            "Use the port of the Syncthing GUI, 8384 by default.",
+        ));
+    } else if let Some(other) = previous.iter().find(|other| {
+        instance.mode != Mode::Remote
+            && other.mode != Mode::Remote
+            && other.gui_address().authority() == instance.gui_address().authority()
+    }) {
+        issues.push(ValidationIssue::new(
+            &field("port"),
+            Error,
+            format!(
+                "Port {} is already used by instance `{}`.",
+                instance.port, other.name
+            ),
+            "Give each instance its own GUI port.",
+        ));
+    }
+    if let Some(fingerprint) = instance.tls_cert_sha256
+        && parse_fingerprint(fingerprint).is_none()
+    {
+        issues.push(ValidationIssue::new(
+            &field("tls_cert_sha256"),
+            Error,
+            format!("`{fingerprint}` is not a SHA-256 fingerprint."),
+            "Enter the 64 hex digits of the fingerprint, e.g. from `openssl x509 -fingerprint -sha256`.",
+        ));
+    }
+    if let Some(id) = instance.tls_device_id
+        && parse_device_id(id).is_none()
+    {
+        issues.push(ValidationIssue::new(
+            &field("tls_device_id"),
+            Error,
+            format!("`{id}` is not a valid Syncthing device ID."),
+            "Copy the device ID from Actions > Show ID in the Syncthing GUI.",
+        ));
+    }
+    if instance.tls_cert_sha256.is_some() && instance.tls_device_id.is_some() {
+        issues.push(ValidationIssue::new(
+            &field("tls_device_id"),
+            Error,
+            "Both `tls_cert_sha256` and `tls_device_id` are set.",
+            "Pin the certificate with only one of them.",
+        ));
+    }
+    match instance.mode {
+        Mode::Systemd | Mode::SystemdSystem => {
+            if instance.service_name.is_empty() {
+                issues.push(ValidationIssue::new(
+                    &field("service_name"),
+                    Error,
+                    "No service set.",
+                    "Enter the name of the systemd service running Syncthing, e.g. `syncthing`.",
+                ));
+            } else if !is_valid_unit_name(instance.service_name) {
+                issues.push(ValidationIssue::new(
+                    &field("service_name"),
+                    Error,
+                    format!("`{}` is not a valid service name.", instance.service_name),
+                    "Enter the name of the systemd service running Syncthing, e.g. `syncthing`.",
+                ));
+            }
+        }
+        Mode::Flatpak => {
+            if instance.flatpak_name.is_empty() {
+                issues.push(ValidationIssue::new(
+                    &field("flatpak_name"),
+                    Error,
+                    "No Flatpak set.",
+                    "Enter the app ID of the Syncthing Flatpak, e.g. `me.kozec.syncthingtk`.",
+                ));
+            } else if !is_valid_app_id(instance.flatpak_name) {
+                issues.push(ValidationIssue::new(
+                    &field("flatpak_name"),
+                    Error,
+                    format!("`{}` is not a valid Flatpak app ID.", instance.flatpak_name),
+                    "Enter the app ID of the Syncthing Flatpak, e.g. `me.kozec.syncthingtk`.",
+                ));
+            } else if let Some(other) = previous.iter().find(|other| {
+                other.mode == Mode::Flatpak
+                    && other.flatpak_name == instance.flatpak_name
+                    && other.home == instance.home
+            }) {
+                issues.push(ValidationIssue::new(
+                    &field("home"),
+                    Warning,
+                    format!(
+                        "Instance `{}` runs the same Flatpak with the same configuration.",
+                        other.name
+                    ),
+                    "Set `home` to a separate configuration directory for this instance.",
+                ));
+            }
+            if instance.flatpak_binary.trim().is_empty() {
+                issues.push(ValidationIssue::new(
+                    &field("flatpak_binary"),
+                    Error,
+                    "No binary set.",
+                    "Enter the command that runs Syncthing inside the Flatpak, usually `syncthing`.",
+                ));
+            }
+        }
+        Mode::Remote => match instance.remote_uri() {
+            None => issues.push(ValidationIssue::new(
+                &field("remote_url"),
+                Error,
+                match instance.remote_url {
+                    Some(url) => format!("`{url}` is not a valid URL."),
+                    None => "No remote URL set.".to_string(),
+                },
+                "Enter the URL of the Syncthing GUI, e.g. `https://nas.local:8384/`.",
+            )),
+            Some(uri) => {
+                if uri.scheme_str() == Some("http") && !is_loopback(uri.host().unwrap_or_default())
+                {
+                    issues.push(ValidationIssue::new(
+                        &field("remote_url"),
+                        Warning,
+                        "The connection to Syncthing is not encrypted.",
+                        "Enable HTTPS in the GUI settings of the remote Syncthing and use `https://`.",
+                    ));
+                }
+                if instance.api_key.is_empty() {
+                    issues.push(ValidationIssue::new(
+                        &field("api_key"),
+                        Warning,
+                        "No API key set, the state of the remote Syncthing can not be read.",
+                        "Copy the API key from the GUI settings of the remote Syncthing.",
+                    ));
+                }
+                if instance.autostart != Autostart::No {
+                    issues.push(ValidationIssue::new(
+                        &field("autostart"),
+                        Warning,
+                        "A remote Syncthing is not started from here.",
+                        "Set `autostart` to `no`.",
+                    ));
+                }
+            }
+        },
+    }
+}
+
+/// Whether `name` can be used as instance name. It is part of URLs and unit names.
+fn is_valid_instance_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Whether `name` is a valid systemd unit name, with or without `.service`.
+fn is_valid_unit_name(name: &str) -> bool {
+    name.len() <= 255
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b":-_.\\@".contains(&b))
+}
+
+/// Whether `id` is a valid Flatpak app ID (e.g. `me.kozec.syncthingtk`). See
+/// <https://docs.flatpak.org/en/latest/conventions.html#application-ids>.
+fn is_valid_app_id(id: &str) -> bool {
+    let elements = id.split('.').collect::<Vec<_>>();
+    id.len() <= 255
+        && elements.len() >= 3
+        && elements.iter().enumerate().all(|(i, element)| {
+            let last = i == elements.len() - 1;
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_' || (last && b == b'-'))
+        })
+}
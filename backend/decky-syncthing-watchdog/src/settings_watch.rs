@@ -0,0 +1,146 @@
+//! Watches the settings file and reloads the settings when it changes, so that the watchdog
+//! picks up changes even if nobody calls the reload route.
+//! The directory of the file is watched instead of the file itself, to also notice the file
+//! being replaced by a rename.
+
+use crate::logging;
+use crate::service::init_service;
+use crate::settings::{SECRET_SETTINGS, SettingsProvider, redact};
+use crate::supervisor::{ExitCause, SubsystemError};
+use futures_util::StreamExt;
+use inotify::{Inotify, WatchMask};
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::read_to_string;
+use tokio::time::timeout;
+
+/// Time to wait for further changes before reloading, as writes may come in several events.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reloads the settings and applies them. Keeps the current settings if the new ones can not
+/// be loaded.
+pub async fn reload(settings: &SettingsProvider) -> Result<(), anyhow::Error> {
+    settings.reload().await?;
+    // Not holding the settings while initializing the service.
+    let settings = settings.settings().await.clone();
+    if let Err(err) = logging::apply_settings(&settings) {
+        warn!("failed to set log level: {err:?}");
+    }
+    init_service(&settings).await
+}
+
+/// Watches the settings file and reloads the settings on changes. Only returns on errors.
+pub async fn watch(settings: Arc<SettingsProvider>) -> Result<(), SubsystemError> {
+    let path = settings.path().to_path_buf();
+    let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(SubsystemError::new(
+            ExitCause::SettingsWatcher,
+            anyhow::anyhow!("invalid settings path {}", path.display()),
+        ));
+    };
+    let dir = match dir.as_os_str().is_empty() {
+        true => ".".as_ref(),
+        false => dir,
+    };
+    let err = |e: io::Error| SubsystemError::new(ExitCause::SettingsWatcher, e);
+    let inotify = Inotify::init().map_err(err)?;
+    inotify
+        .watches()
+        .add(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .map_err(err)?;
+    let mut events = inotify.into_event_stream([0; 4096]).map_err(err)?;
+    debug!("watching {} for changes.", path.display());
+
+    let mut last = read_json(&settings).await.ok();
+    loop {
+        let event = match events.next().await {
+            Some(event) => event.map_err(err)?,
+            None => {
+                return Err(SubsystemError::new(
+                    ExitCause::SettingsWatcher,
+                    anyhow::anyhow!("inotify event stream ended"),
+                ));
+            }
+        };
+        if event.name.as_deref() != Some(file_name) {
+            continue;
+        }
+        // Wait until the file was quiet for a while.
+        while let Ok(Some(event)) = timeout(DEBOUNCE, events.next()).await {
+            event.map_err(err)?;
+        }
+
+        let current = match read_json(&settings).await {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("settings file changed, but can not be read, keeping current settings: {e}");
+                continue;
+            }
+        };
+        if last.as_ref() == Some(&current) {
+            debug!("settings file written, but unchanged.");
+            continue;
+        }
+        if settings.matches(&current).await {
+            // Written by the watchdog itself, see `SettingsProvider::update`.
+            debug!("settings file matches the current settings.");
+            last = Some(current);
+            continue;
+        }
+        info!(
+            "settings file changed ({}), reloading.",
+            describe_changes(last.as_ref(), &current)
+        );
+        match reload(&settings).await {
+            Ok(()) => last = Some(current),
+            Err(e) => warn!("failed to apply changed settings, keeping current settings: {e:?}"),
+        }
+    }
+}
+
+async fn read_json(settings: &SettingsProvider) -> Result<Value, anyhow::Error> {
+    Ok(serde_json::from_str(
+        &read_to_string(settings.path()).await?,
+    )?)
+}
+
+/// Lists the changed keys with their old and new values. Values of credentials are omitted.
+fn describe_changes(old: Option<&Value>, new: &Value) -> String {
+    let redacted = |value: &Value| {
+        let mut value = value.clone();
+        redact(&mut value);
+        value
+    };
+    let (old_redacted, new_redacted) = (old.map(redacted), redacted(new));
+    let empty = serde_json::Map::new();
+    let old = old.and_then(Value::as_object).unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let old_redacted = old_redacted
+        .as_ref()
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    let new_redacted = new_redacted.as_object().unwrap_or(&empty);
+    let changes = old
+        .keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| {
+            let fmt = |v: Option<&Value>| v.map_or("<unset>".to_string(), Value::to_string);
+            match SECRET_SETTINGS.contains(&key.as_str()) {
+                true => key.clone(),
+                false => format!(
+                    "{key}: {} -> {}",
+                    fmt(old_redacted.get(key)),
+                    fmt(new_redacted.get(key))
+                ),
+            }
+        })
+        .collect::<Vec<_>>();
+    changes.join(", ")
+}
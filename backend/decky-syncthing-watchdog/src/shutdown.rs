@@ -0,0 +1,54 @@
+//! Handles the signals sent to the watchdog.
+//! SIGTERM and SIGINT shut the watchdog down gracefully, SIGHUP reloads the settings.
+//! A graceful shutdown can also be requested via the API, see [`request_shutdown`].
+
+use crate::settings::SettingsProvider;
+use crate::settings_watch::reload;
+use log::{info, warn};
+use std::io;
+use std::sync::LazyLock;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::Notify;
+
+static SHUTDOWN_REQUEST: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Asks the watchdog to shut down gracefully.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUEST.notify_one();
+}
+
+pub struct Signals {
+    sigterm: Signal,
+    sigint: Signal,
+    sighup: Signal,
+}
+
+impl Signals {
+    /// Installs the signal handlers.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+            sighup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Waits until the watchdog is asked to shut down (by signal or by [`request_shutdown`])
+    /// and returns the reason. Reloads the settings and re-inits the service on every SIGHUP
+    /// received in the meantime.
+    pub async fn wait_for_shutdown(&mut self, settings: &SettingsProvider) -> &'static str {
+        loop {
+            tokio::select! {
+                _ = self.sigterm.recv() => return "SIGTERM",
+                _ = self.sigint.recv() => return "SIGINT",
+                _ = SHUTDOWN_REQUEST.notified() => return "shutdown request",
+                _ = self.sighup.recv() => {
+                    info!("received SIGHUP, reloading config.");
+                    if let Err(err) = reload(settings).await {
+                        warn!("failed to reload config on SIGHUP: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+}
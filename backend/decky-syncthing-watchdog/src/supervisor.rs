@@ -0,0 +1,243 @@
+//! Supervises the subsystems of the watchdog (HTTP server, control socket and watchers).
+//! Failed subsystems are restarted with an exponential backoff. The health of each subsystem is
+//! recorded and can be queried via the API. If a subsystem keeps failing the watchdog gives up
+//! and exits with an exit code specific to the cause.
+
+use crate::panic_util::panic_to_string;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::process::ExitCode;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, future::Future};
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+use tokio::time::{Instant, sleep};
+
+static HEALTH: LazyLock<Mutex<BTreeMap<&'static str, SubsystemHealth>>> =
+    LazyLock::new(Default::default);
+
+const BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// After this many failures in a row, a subsystem is considered unrecoverable.
+const MAX_CONSECUTIVE_FAILURES: u32 = 8;
+/// Like [`MAX_CONSECUTIVE_FAILURES`], for failures to bind the HTTP server's address. It is
+/// usually taken by another process, so the plugin should learn about it quickly.
+const MAX_BIND_FAILURES: u32 = 3;
+/// If a subsystem ran for at least this long before failing, its failure count is reset.
+const HEALTHY_AFTER: Duration = Duration::from_secs(120);
+
+/// Reasons for the watchdog to exit with a failure. Each has its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCause {
+    /// The PID file could not be locked.
+    PidFile,
+    /// The settings could not be loaded on startup.
+    Settings,
+    /// The signal handlers could not be installed.
+    Signals,
+    /// The HTTP server could not bind its address.
+    ServerBind,
+    /// The HTTP server kept failing.
+    Server,
+    /// The Gamescope watcher kept failing.
+    Watcher,
+    /// The control API on the Unix domain socket kept failing.
+    ControlSocket,
+    /// Watching the settings file kept failing.
+    SettingsWatcher,
+    /// The token for the control API could not be created.
+    Token,
+}
+
+impl ExitCause {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ExitCause::PidFile => 2,
+            ExitCause::Settings => 3,
+            ExitCause::Signals => 4,
+            ExitCause::ServerBind => 5,
+            ExitCause::Server => 6,
+            ExitCause::Watcher => 7,
+            ExitCause::ControlSocket => 8,
+            ExitCause::SettingsWatcher => 9,
+            ExitCause::Token => 10,
+        }
+    }
+
+    /// After how many failures in a row with this cause a subsystem is given up.
+    fn max_consecutive_failures(self) -> u32 {
+        match self {
+            ExitCause::ServerBind => MAX_BIND_FAILURES,
+            _ => MAX_CONSECUTIVE_FAILURES,
+        }
+    }
+}
+
+impl From<ExitCause> for ExitCode {
+    fn from(value: ExitCause) -> Self {
+        ExitCode::from(value.exit_code())
+    }
+}
+
+/// A failure of a subsystem, with the cause to exit with if it can not be recovered from.
+#[derive(Debug)]
+pub struct SubsystemError {
+    pub cause: ExitCause,
+    pub error: anyhow::Error,
+}
+
+impl SubsystemError {
+    pub fn new(cause: ExitCause, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            cause,
+            error: error.into(),
+        }
+    }
+}
+
+impl Display for SubsystemError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.cause, self.error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsystemStatus {
+    Running,
+    Restarting,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemHealth {
+    pub status: SubsystemStatus,
+    /// Number of restarts since the watchdog started.
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    /// Unix timestamp of the last status change.
+    pub since: u64,
+}
+
+/// Returns the health of all subsystems, keyed by name.
+pub async fn health() -> BTreeMap<&'static str, SubsystemHealth> {
+    HEALTH.lock().await.clone()
+}
+
+async fn set_health(name: &'static str, status: SubsystemStatus, last_error: Option<String>) {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut health = HEALTH.lock().await;
+    let restarts = health.get(name).map(|h| h.restarts).unwrap_or_default();
+    let restarts = match status {
+        SubsystemStatus::Restarting => restarts + 1,
+        _ => restarts,
+    };
+    let last_error = last_error.or_else(|| health.get(name).and_then(|h| h.last_error.clone()));
+    health.insert(
+        name,
+        SubsystemHealth {
+            status,
+            restarts,
+            last_error,
+            since,
+        },
+    );
+}
+
+/// Aborts the task when dropped, so that the subsystem stops if its supervisor is dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the subsystem created by `start` and restarts it if it fails or panics.
+/// Returns `Ok` once the subsystem finished on its own (e.g. on shutdown) and the cause of
+/// the last failure if it failed too often to be recovered.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    panic_cause: ExitCause,
+    mut start: F,
+) -> Result<(), ExitCause>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), SubsystemError>> + Send + 'static,
+{
+    let mut failures = 0;
+    loop {
+        set_health(name, SubsystemStatus::Running, None).await;
+        let started = Instant::now();
+        let task = tokio::spawn(start());
+        let _guard = AbortOnDrop(task.abort_handle());
+        let (cause, err) = match task.await {
+            Ok(Ok(())) => {
+                info!("{name}: finished.");
+                return Ok(());
+            }
+            Ok(Err(err)) => (err.cause, err.to_string()),
+            Err(err) if err.is_panic() => (
+                panic_cause,
+                format!("panicked: {}", panic_to_string(&*err.into_panic())),
+            ),
+            Err(err) => (panic_cause, err.to_string()),
+        };
+
+        if started.elapsed() >= HEALTHY_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        if failures >= cause.max_consecutive_failures() {
+            error!("{name}: failed {failures} times in a row, giving up: {err}");
+            set_health(name, SubsystemStatus::Failed, Some(err)).await;
+            return Err(cause);
+        }
+        let backoff = BACKOFF_INITIAL
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(BACKOFF_MAX);
+        warn!("{name}: failed, restarting in {backoff:?}: {err}");
+        set_health(name, SubsystemStatus::Restarting, Some(err)).await;
+        sleep(backoff).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn gives_up_binding_quickly() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let result = supervise("test_bind", ExitCause::Server, || {
+            let attempts = attempts.clone();
+            async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(SubsystemError::new(
+                    ExitCause::ServerBind,
+                    anyhow::anyhow!("address in use"),
+                ))
+            }
+        })
+        .await;
+        assert_eq!(result, Err(ExitCause::ServerBind));
+        assert_eq!(attempts.load(Ordering::SeqCst), MAX_BIND_FAILURES);
+        let health = &health().await["test_bind"];
+        assert_eq!(health.status, SubsystemStatus::Failed);
+        assert_eq!(health.restarts, MAX_BIND_FAILURES - 1);
+    }
+
+    #[tokio::test]
+    async fn returns_when_finished() {
+        let result = supervise("test_finish", ExitCause::Server, || async { Ok(()) }).await;
+        assert_eq!(result, Ok(()));
+    }
+}
@@ -0,0 +1,108 @@
+//! Reading Syncthing's own configuration (`config.xml`) of an instance, e.g. for the wizard to
+//! find the port and API key, or to know whether the GUI uses TLS.
+
+use crate::gui_address::GuiAddress;
+use crate::settings::{Instance, Mode};
+use homedir::my_home;
+use log::debug;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use sxd_document::Package;
+use sxd_xpath::evaluate_xpath;
+
+pub struct SyncthingConfig(Package);
+
+impl SyncthingConfig {
+    /// Finds and reads the configuration of `instance`. `force_flatpak` looks into the
+    /// configuration directory of that Flatpak, even if the instance is not in Flatpak mode.
+    /// Syncthing's default locations are only searched for the default instance.
+    pub fn find(instance: &Instance<'_>, force_flatpak: Option<&str>) -> Option<Self> {
+        let home = match my_home() {
+            Ok(Some(home)) => home,
+            _ => {
+                return None;
+            }
+        };
+
+        let mut possible_paths = Vec::with_capacity(7);
+        if let Some(dir) = instance.home {
+            possible_paths.push(dir.join("config.xml"));
+        }
+        if instance.mode == Mode::Flatpak || force_flatpak.is_some() {
+            let flatpak_name = force_flatpak.unwrap_or(instance.flatpak_name);
+            possible_paths.push(
+                home.join(".var")
+                    .join("app")
+                    .join(flatpak_name)
+                    .join(".local")
+                    .join("state")
+                    .join("syncthing")
+                    .join("config.xml"),
+            );
+            possible_paths.push(
+                home.join(".var")
+                    .join("app")
+                    .join(flatpak_name)
+                    .join("config")
+                    .join("syncthing")
+                    .join("config.xml"),
+            );
+        }
+
+        if instance.is_default() {
+            if let Ok(var) = env::var("XDG_STATE_HOME") {
+                possible_paths.push(PathBuf::from(var).join("syncthing").join("config.xml"));
+            }
+            if let Ok(var) = env::var("XDG_CONFIG_HOME") {
+                possible_paths.push(PathBuf::from(var).join("syncthing").join("config.xml"));
+            }
+            possible_paths.push(
+                home.join(".local")
+                    .join("state")
+                    .join("syncthing")
+                    .join("config.xml"),
+            );
+            possible_paths.push(home.join(".config").join("syncthing").join("config.xml"));
+        }
+
+        for path in possible_paths {
+            if let Some(config) = Self::read(&path) {
+                debug!("detected config path: {}", path.display());
+                return Some(config);
+            }
+        }
+        None
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        Some(Self(
+            sxd_document::parser::parse(&read_to_string(path).ok()?).ok()?,
+        ))
+    }
+
+    fn value(&self, xpath: &str) -> Option<String> {
+        let doc = self.0.as_document();
+        Some(evaluate_xpath(&doc, xpath).ok()?.string())
+    }
+
+    /// Address of the GUI, with whether it uses TLS (the `tls` attribute).
+    pub fn gui_address(&self) -> Option<GuiAddress> {
+        let tls = match self.value("/configuration/gui/@tls")?.trim() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        };
+        GuiAddress::parse(&self.value("/configuration/gui/address")?, tls)
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        self.value("/configuration/gui/apikey")
+    }
+
+    /// User for Basic auth, `None` if not set.
+    pub fn gui_user(&self) -> Option<String> {
+        self.value("/configuration/gui/user")
+            .filter(|user| !user.trim().is_empty())
+    }
+}
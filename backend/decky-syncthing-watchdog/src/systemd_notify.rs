@@ -0,0 +1,206 @@
+//! Support for running the watchdog as a systemd service (`Type=notify`).
+//! Implements readiness and status notifications, the systemd watchdog keep-alive and socket
+//! activation. All of this is a no-op if the watchdog was not started by systemd.
+
+use crate::service::get_state;
+use crate::settings::SettingsProvider;
+use log::{debug, warn};
+use sd_notify::NotifyState;
+use std::convert::Infallible;
+use std::env;
+use std::future::pending;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{interval, timeout};
+
+/// How often the status is updated, if the systemd watchdog is not enabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Where to send notifications to systemd and how often it expects keep-alive pings. Read from
+/// the environment once on startup.
+#[derive(Debug, Clone, Default)]
+pub struct Systemd {
+    /// `NOTIFY_SOCKET`, `None` if not started by systemd.
+    pub notify_socket: Option<PathBuf>,
+    /// Timeout of the systemd watchdog (`WATCHDOG_USEC`), if it is enabled for this process.
+    pub watchdog_timeout: Option<Duration>,
+}
+
+impl Systemd {
+    pub fn from_env() -> Self {
+        let mut usec = 0;
+        Self {
+            notify_socket: env::var_os("NOTIFY_SOCKET").map(PathBuf::from),
+            watchdog_timeout: sd_notify::watchdog_enabled(false, &mut usec)
+                .then(|| Duration::from_micros(usec)),
+        }
+    }
+}
+
+fn notify(systemd: &Systemd, state: &[NotifyState]) {
+    let Some(socket_path) = &systemd.notify_socket else {
+        return;
+    };
+    let message: String = state.iter().map(|state| format!("{state}\n")).collect();
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        socket.connect(socket_path)?;
+        socket.send(message.as_bytes())
+    });
+    if let Err(err) = result {
+        warn!("failed to notify systemd: {err:?}");
+    }
+}
+
+/// Tells systemd that the watchdog is ready.
+pub fn ready(systemd: &Systemd, status: &str) {
+    notify(systemd, &[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tells systemd that the watchdog is shutting down.
+pub fn stopping(systemd: &Systemd) {
+    notify(systemd, &[NotifyState::Stopping]);
+}
+
+/// Returns the listening socket passed by systemd socket activation with its address, if any.
+/// Like `listen_address`, it must be a loopback address, since the proxy adds the Syncthing
+/// credentials to requests. Other sockets are ignored.
+pub fn take_activated_listener() -> Option<(TcpListener, SocketAddr)> {
+    let mut fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(err) => {
+            warn!("invalid socket activation environment: {err:?}");
+            return None;
+        }
+    };
+    let fd = fds.next()?;
+    if fds.next().is_some() {
+        warn!("got more than one socket via socket activation, only using the first one.");
+    }
+    debug!("using socket passed by systemd (fd {fd}).");
+    // SAFETY: systemd passes us ownership of this listening socket. The iterator yields each
+    // descriptor only once and we only call this once on startup.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    let addr = match listener.local_addr() {
+        Ok(addr) if !addr.ip().is_loopback() => {
+            warn!("activated socket {addr} is not on a loopback address, ignoring it.");
+            return None;
+        }
+        Ok(addr) => addr,
+        Err(err) => {
+            warn!("failed to get address of activated socket, ignoring it: {err:?}");
+            return None;
+        }
+    };
+    if let Err(err) = listener.set_nonblocking(true) {
+        warn!("failed to make activated socket non-blocking: {err:?}");
+        return None;
+    }
+    Some((listener, addr))
+}
+
+/// Sends the systemd watchdog keep-alive pings and updates the status with the state of
+/// Syncthing. Never returns.
+pub async fn keep_alive(systemd: &Systemd, settings: &SettingsProvider) -> Infallible {
+    if systemd.notify_socket.is_none() {
+        return pending().await;
+    }
+    let period = match systemd.watchdog_timeout {
+        // systemd recommends pinging at half of the timeout.
+        Some(timeout) => timeout / 2,
+        None => STATUS_INTERVAL,
+    };
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        if systemd.watchdog_timeout.is_some() {
+            notify(systemd, &[NotifyState::Watchdog]);
+        }
+        let status = match timeout(period, instance_states(settings)).await {
+            Ok(status) => status,
+            Err(_) => "Syncthing: unknown (timeout)".to_string(),
+        };
+        notify(systemd, &[NotifyState::Status(&status)]);
+    }
+}
+
+/// The state of each instance, e.g. `Syncthing: running, family: stopped`.
+async fn instance_states(settings: &SettingsProvider) -> String {
+    let settings = settings.settings().await;
+    let mut states = Vec::new();
+    for instance in settings.instances() {
+        let name = match instance.is_default() {
+            true => "Syncthing",
+            false => instance.name,
+        };
+        match get_state(&instance).await {
+            Ok(state) => states.push(format!("{name}: {}", state.as_static_str())),
+            Err(err) => states.push(format!("{name}: unknown ({err})")),
+        }
+    }
+    states.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::test_support::{TempDir, settings_v2};
+    use crate::settings_overrides::Overrides;
+    use serde_json::json;
+    use std::fs::write;
+
+    /// Waits for the next notification.
+    async fn receive(socket: &tokio::net::UnixDatagram) -> String {
+        let mut buf = [0; 1024];
+        let len = timeout(Duration::from_secs(30), socket.recv(&mut buf))
+            .await
+            .expect("no notification")
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    /// Runs the notifications against a fake notify socket, as systemd would see them.
+    #[tokio::test]
+    async fn notifies_systemd() {
+        let dir = TempDir::new("notify");
+        let socket_path = dir.path().join("notify.sock");
+        let socket = tokio::net::UnixDatagram::bind(&socket_path).unwrap();
+        // A remote Syncthing that is not reachable, so its state is known without systemd.
+        let settings_path = dir.path().join("settings.json");
+        let settings = settings_v2(json!({
+            "mode": "remote",
+            "remote_url": "http://127.0.0.1:1/",
+        }));
+        write(&settings_path, settings.to_string()).unwrap();
+        let settings = SettingsProvider::new(settings_path, Overrides::default())
+            .await
+            .unwrap();
+        let systemd = Systemd {
+            notify_socket: Some(socket_path),
+            watchdog_timeout: Some(Duration::from_millis(400)),
+        };
+
+        ready(&systemd, "Watchdog ready.");
+        assert_eq!(receive(&socket).await, "READY=1\nSTATUS=Watchdog ready.\n");
+
+        let keep_alive = tokio::spawn({
+            let systemd = systemd.clone();
+            async move { keep_alive(&systemd, &settings).await }
+        });
+        // Pings repeatedly, each time followed by the status.
+        let (mut pings, mut statuses) = (0, 0);
+        while pings < 2 || statuses < 2 {
+            match receive(&socket).await.as_str() {
+                "WATCHDOG=1\n" => pings += 1,
+                "STATUS=Syncthing: stopped\n" => statuses += 1,
+                other => panic!("unexpected notification {other:?}"),
+            }
+        }
+        keep_alive.abort();
+
+        stopping(&systemd);
+        while receive(&socket).await != "STOPPING=1\n" {}
+    }
+}
@@ -0,0 +1,378 @@
+//! Verification of the certificates of Syncthing GUIs.
+//! Syncthing on this machine (a loopback host) uses a self-signed certificate, so any
+//! certificate is accepted for it. Other hosts must present a pinned certificate (given by its
+//! SHA-256 fingerprint or the Syncthing device ID derived from it) or one signed by one of the
+//! system's root CAs.
+
+use crate::util::make_unsafe_https_client;
+use hyper::Client;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use log::{debug, warn};
+use ring::digest::{SHA256, digest};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, Error, RootCertStore, ServerName};
+use serde::{Serialize, Serializer};
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// Length of a device ID without check characters and dashes.
+const DEVICE_ID_LEN: usize = 52;
+/// Characters of a device ID per check character.
+const LUHN_GROUP_LEN: usize = 13;
+
+static SYSTEM_ROOTS: LazyLock<Arc<RootCertStore>> = LazyLock::new(|| {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let (added, ignored) = roots.add_parsable_certificates(
+                &certs.into_iter().map(|cert| cert.0).collect::<Vec<_>>(),
+            );
+            debug!("loaded {added} system root certificates ({ignored} ignored).");
+        }
+        Err(err) => warn!("failed to load the system root certificates: {err}"),
+    }
+    Arc::new(roots)
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TlsVerification {
+    /// Any certificate, only for Syncthing on this machine.
+    AcceptAny,
+    /// A certificate for the host, signed by one of the system's root CAs.
+    SystemRoots,
+    /// Only the certificate with this SHA-256 hash.
+    Pinned([u8; 32]),
+}
+
+impl TlsVerification {
+    /// How to verify the certificate of `host`, with the certificate `pin` if set.
+    pub fn for_host(host: &str, pin: Option<[u8; 32]>) -> Self {
+        match pin {
+            Some(pin) => Self::Pinned(pin),
+            None if is_loopback(host) => Self::AcceptAny,
+            None => Self::SystemRoots,
+        }
+    }
+}
+
+impl Serialize for TlsVerification {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            Self::AcceptAny => "accept_any",
+            Self::SystemRoots => "system_roots",
+            Self::Pinned(_) => "pinned",
+        })
+    }
+}
+
+/// Whether `host` is this machine, e.g. `127.0.0.1`, `::1` or `localhost`.
+pub fn is_loopback(host: &str) -> bool {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// An HTTP(S) client verifying certificates as given by `verification`.
+pub fn make_https_client<B>(
+    verification: TlsVerification,
+) -> Client<HttpsConnector<HttpConnector>, B>
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+{
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match verification {
+        TlsVerification::AcceptAny => return make_unsafe_https_client(),
+        TlsVerification::SystemRoots => builder
+            .with_root_certificates(SYSTEM_ROOTS.clone())
+            .with_no_client_auth(),
+        TlsVerification::Pinned(pin) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCert(pin)))
+            .with_no_client_auth(),
+    };
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build::<_, B>(https)
+}
+
+/// Accepts only the certificate with the SHA-256 hash, whatever host it is for. Syncthing's
+/// certificates are self-signed and not for the host name.
+struct PinnedCert([u8; 32]);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let hash = cert_sha256(end_entity);
+        match hash == self.0 {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(Error::General(format!(
+                "certificate is not the pinned one, it has the SHA-256 fingerprint {} (device ID {})",
+                hex(&hash),
+                device_id(&hash)
+            ))),
+        }
+    }
+}
+
+fn cert_sha256(cert: &Certificate) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest(&SHA256, &cert.0).as_ref());
+    hash
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parses a SHA-256 fingerprint, 64 hex digits, optionally separated by `:` or spaces.
+pub fn parse_fingerprint(fingerprint: &str) -> Option<[u8; 32]> {
+    let digits = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>();
+    if digits.len() != 64 || !digits.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Parses a Syncthing device ID (e.g. `MFZWI3D-BONSGYC-...`) into the SHA-256 hash of the
+/// certificate it stands for. Accepts IDs with or without check characters and dashes.
+pub fn parse_device_id(id: &str) -> Option<[u8; 32]> {
+    // Like Syncthing, undo common typos.
+    let id = id
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| match c.to_ascii_uppercase() {
+            '0' => 'O',
+            '1' => 'I',
+            '8' => 'B',
+            c => c,
+        })
+        .collect::<String>();
+    let id = match id.len() {
+        DEVICE_ID_LEN => id,
+        len if len == DEVICE_ID_LEN + DEVICE_ID_LEN / LUHN_GROUP_LEN => {
+            let mut stripped = String::with_capacity(DEVICE_ID_LEN);
+            for group in id.as_bytes().chunks(LUHN_GROUP_LEN + 1) {
+                let (data, check) = group.split_at(LUHN_GROUP_LEN);
+                let data = std::str::from_utf8(data).ok()?;
+                if luhn_base32(data)? != check[0] {
+                    return None;
+                }
+                stripped.push_str(data);
+            }
+            stripped
+        }
+        _ => return None,
+    };
+    let mut hash = [0; 32];
+    let (mut bits, mut acc, mut i) = (0, 0u32, 0);
+    for c in id.bytes() {
+        acc = (acc << 5) | BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+        bits += 5;
+        if bits >= 8 && i < hash.len() {
+            bits -= 8;
+            hash[i] = (acc >> bits) as u8;
+            acc &= (1 << bits) - 1;
+            i += 1;
+        }
+    }
+    Some(hash)
+}
+
+/// The Syncthing device ID for the certificate with the SHA-256 `hash`, with check characters
+/// and dashes as shown by Syncthing.
+pub fn device_id(hash: &[u8; 32]) -> String {
+    let mut base32 = String::with_capacity(DEVICE_ID_LEN);
+    let (mut bits, mut acc) = (0, 0u32);
+    for byte in hash {
+        acc = (acc << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            base32.push(BASE32_ALPHABET[(acc >> bits) as usize & 31] as char);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    base32.push(BASE32_ALPHABET[(acc << (5 - bits)) as usize & 31] as char);
+    let mut with_check = String::with_capacity(DEVICE_ID_LEN + 4);
+    for group in base32.as_bytes().chunks(LUHN_GROUP_LEN) {
+        let group = std::str::from_utf8(group).unwrap();
+        with_check.push_str(group);
+        with_check.push(luhn_base32(group).unwrap() as char);
+    }
+    with_check
+        .as_bytes()
+        .chunks(7)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Check character of a group of a device ID, see Syncthing's `luhnBase32`.
+fn luhn_base32(group: &str) -> Option<u8> {
+    let mut factor = 1;
+    let mut sum = 0;
+    for c in group.bytes() {
+        let codepoint = BASE32_ALPHABET.iter().position(|a| *a == c)?;
+        let addend = factor * codepoint;
+        factor = if factor == 2 { 1 } else { 2 };
+        sum += addend / 32 + addend % 32;
+    }
+    Some(BASE32_ALPHABET[(32 - sum % 32) % 32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device ID from Syncthing's tests (`lib/protocol/deviceid_test.go`).
+    const DEVICE_ID: &str = "P56IOI7-MZJNU2Y-IQGDREY-DM2MGTI-MGL3BXN-PQ6W5BM-TBBZ4TJ-XZWICQ2";
+    /// SHA-256 hash of the certificate `DEVICE_ID` stands for.
+    const HASH: &str = "7F7C8723ECCA5B4D22061C4981B34C34D865EC376BE1EB74330873C9A6F9B205";
+
+    fn hash() -> [u8; 32] {
+        parse_fingerprint(HASH).unwrap()
+    }
+
+    #[test]
+    fn parses_device_id() {
+        assert_eq!(parse_device_id(DEVICE_ID), Some(hash()));
+    }
+
+    #[test]
+    fn formats_device_id() {
+        assert_eq!(device_id(&hash()), DEVICE_ID);
+    }
+
+    #[test]
+    fn device_id_round_trip() {
+        assert_eq!(device_id(&parse_device_id(DEVICE_ID).unwrap()), DEVICE_ID);
+        for seed in 0..=255u8 {
+            let hash: [u8; 32] =
+                std::array::from_fn(|i| seed.wrapping_mul(31).wrapping_add(i as u8 * 7));
+            assert_eq!(parse_device_id(&device_id(&hash)), Some(hash));
+        }
+    }
+
+    #[test]
+    fn parses_device_id_variants() {
+        let without_dashes = DEVICE_ID.replace('-', "");
+        let without_check = without_dashes
+            .as_bytes()
+            .chunks(LUHN_GROUP_LEN + 1)
+            .map(|group| std::str::from_utf8(&group[..LUHN_GROUP_LEN]).unwrap())
+            .collect::<String>();
+        for id in [
+            without_dashes.as_str(),
+            without_check.as_str(),
+            &DEVICE_ID.to_lowercase(),
+            &DEVICE_ID.replace('-', " "),
+            // Typos Syncthing corrects: 0 for O, 1 for I.
+            &DEVICE_ID.replace('O', "0").replace('I', "1"),
+        ] {
+            assert_eq!(parse_device_id(id), Some(hash()), "{id}");
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_check_character() {
+        // The first group's check character is `Y`.
+        let wrong = DEVICE_ID.replacen("U2Y-", "U2Z-", 1);
+        assert_eq!(parse_device_id(&wrong), None);
+        // A changed data character does not match the check character anymore.
+        let wrong = DEVICE_ID.replacen("P56", "P57", 1);
+        assert_eq!(parse_device_id(&wrong), None);
+    }
+
+    #[test]
+    fn rejects_invalid_device_ids() {
+        assert_eq!(parse_device_id(""), None);
+        assert_eq!(parse_device_id(&DEVICE_ID[..DEVICE_ID.len() - 1]), None);
+        assert_eq!(parse_device_id(&DEVICE_ID.replacen('P', "!", 1)), None);
+    }
+
+    #[test]
+    fn parses_fingerprints() {
+        let with_colons = hex(&hash());
+        assert_eq!(parse_fingerprint(&with_colons), Some(hash()));
+        assert_eq!(parse_fingerprint(&HASH.to_lowercase()), Some(hash()));
+        assert_eq!(
+            parse_fingerprint(&with_colons.replace(':', " ")),
+            Some(hash())
+        );
+        assert_eq!(parse_fingerprint(&HASH[2..]), None);
+        assert_eq!(parse_fingerprint(&HASH.replacen('7', "G", 1)), None);
+        assert_eq!(parse_fingerprint(&HASH.replacen("7F", "ä", 1)), None);
+    }
+
+    #[test]
+    fn pinned_cert_accepts_only_the_pinned_certificate() {
+        let cert = Certificate(b"certificate".to_vec());
+        let other = Certificate(b"other certificate".to_vec());
+        let verifier = PinnedCert(cert_sha256(&cert));
+        let verify = |cert: &Certificate| {
+            verifier.verify_server_cert(
+                cert,
+                &[],
+                &ServerName::try_from("nas.local").unwrap(),
+                &mut [].into_iter(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify(&cert).is_ok());
+        match verify(&other) {
+            Err(Error::General(message)) => {
+                assert!(message.contains(&device_id(&cert_sha256(&other))))
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn verification_for_hosts() {
+        assert_eq!(
+            TlsVerification::for_host("127.0.0.1", None),
+            TlsVerification::AcceptAny
+        );
+        assert_eq!(
+            TlsVerification::for_host("[::1]", None),
+            TlsVerification::AcceptAny
+        );
+        assert_eq!(
+            TlsVerification::for_host("nas.local", None),
+            TlsVerification::SystemRoots
+        );
+        assert_eq!(
+            TlsVerification::for_host("localhost", Some(hash())),
+            TlsVerification::Pinned(hash())
+        );
+    }
+}
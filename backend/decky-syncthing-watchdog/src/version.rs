@@ -0,0 +1,39 @@
+//! Version and build information of the watchdog.
+
+use crate::settings::Settings;
+use serde::Serialize;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+/// Revision of the watchdog HTTP API. Increased whenever routes are added or changed.
+pub const API_REVISION: u32 = 14;
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_revision: &'static str,
+    pub config_version: u32,
+    pub api_revision: u32,
+    pub uptime_secs: u64,
+}
+
+/// Records the start time of the watchdog, for the uptime.
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn uptime_secs() -> u64 {
+    STARTED.elapsed().as_secs()
+}
+
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_revision: env!("DECKY_WATCHDOG_GIT_REVISION"),
+        config_version: Settings::SUPPORTED_VERSION,
+        api_revision: API_REVISION,
+        uptime_secs: uptime_secs(),
+    }
+}
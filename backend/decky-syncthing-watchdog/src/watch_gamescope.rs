@@ -1,5 +1,5 @@
 use crate::service::{start_service, stop_service};
-use crate::settings::{Autostart, SettingsProvider};
+use crate::settings::{Autostart, Instance, Mode, SettingsProvider};
 use log::{debug, info};
 use std::convert::Infallible;
 use std::sync::Arc;
@@ -32,43 +32,52 @@ impl GamescopeWatchdog {
     pub async fn background_watch(&mut self) -> Infallible {
         //      - When autostart is enabled & when gamescope (or this watchdog) was not running but now is: Start
         //      - when gamescope is gone: Stop
+        // Both for each instance with autostart set to Gamescope.
         self.refresh_gamescope_state();
         let settings_arc = self.settings.clone();
-        let autostart = matches!(
-            settings_arc.settings().await.autostart,
-            Autostart::Gamescope
-        );
-        if settings_arc.settings().await.is_not_setup()
-            && autostart
-            && self.gamescope_process_is_running()
-        {
-            debug!("Initial autostart.");
-            start_service(&*settings_arc.settings().await).await.ok();
+        let is_autostart = |instance: &Instance| {
+            instance.autostart == Autostart::Gamescope && instance.mode != Mode::Remote
+        };
+        let settings = settings_arc.settings().await;
+        let autostart = settings.instances().any(|i| is_autostart(&i));
+        if settings.is_not_setup() && autostart && self.gamescope_process_is_running() {
+            for instance in settings.instances().filter(is_autostart) {
+                debug!(instance = instance.name; "Initial autostart.");
+                start_service(&instance).await.ok();
+            }
         }
+        drop(settings);
         loop {
             debug!("background loop");
-            if settings_arc.settings().await.is_not_setup() {
+            let settings = settings_arc.settings().await;
+            if settings.is_not_setup() {
                 info!("Skipping Gamescope check: Configuration not setup.");
             } else {
                 match self.gamescope_pid {
                     None => {
                         debug!("Gamescope was not running");
                         if autostart && self.gamescope_process_is_running() {
-                            debug!("Gamescope is now running, starting");
-                            start_service(&*settings_arc.settings().await).await.ok();
+                            for instance in settings.instances().filter(is_autostart) {
+                                debug!(instance = instance.name; "Gamescope is now running, starting");
+                                start_service(&instance).await.ok();
+                            }
                         }
                     }
                     Some(_) => {
                         debug!("Gamescope was running");
-                        if !self.gamescope_process_is_running()
-                            && !settings_arc.settings().await.keep_running_on_desktop
-                        {
-                            debug!("Gamescope is no longer running, stopping");
-                            stop_service(&*settings_arc.settings().await).await.ok();
+                        if !self.gamescope_process_is_running() {
+                            for instance in settings
+                                .instances()
+                                .filter(|i| is_autostart(i) && !i.keep_running_on_desktop)
+                            {
+                                debug!(instance = instance.name; "Gamescope is no longer running, stopping");
+                                stop_service(&instance).await.ok();
+                            }
                         }
                     }
                 }
             }
+            drop(settings);
             sleep(Duration::from_secs(BACKGROUND_WATCH_INTERVAL_SECS)).await;
         }
     }
@@ -123,12 +132,12 @@ impl GamescopeWatchdog {
     where
         F: Fn(&Process) -> Result<bool, E>,
     {
-        if sys.refresh_process_specifics(pid, ProcessRefreshKind::default()) {
-            if let Some(proc) = sys.process(pid) {
-                if proc.status() != ProcessStatus::Dead && cond(proc)? {
-                    return Ok(Some(proc));
-                }
-            }
+        if sys.refresh_process_specifics(pid, ProcessRefreshKind::default())
+            && let Some(proc) = sys.process(pid)
+            && proc.status() != ProcessStatus::Dead
+            && cond(proc)?
+        {
+            return Ok(Some(proc));
         }
         Ok(None)
     }
@@ -21,24 +21,25 @@ WATCHDOG_BIN_PATH = Path(DECKY_PLUGIN_DIR) / "bin" / "decky-syncthing-watchdog"
 # Path to the watchdog PID file.
 # File that should contain settings.
 WATCHDOG_PID_PATH = Path(DECKY_PLUGIN_RUNTIME_DIR) / "watchdog.pid"
+# File the watchdog writes the endpoints it listens on into.
+WATCHDOG_ENDPOINT_PATH = Path(DECKY_PLUGIN_RUNTIME_DIR) / "watchdog.endpoint.json"
+# File the watchdog writes the token for its control API into, new on every start.
+WATCHDOG_TOKEN_PATH = Path(DECKY_PLUGIN_RUNTIME_DIR) / "watchdog.token"
+# Default address of the watchdog, if it didn't write an endpoint file (yet).
+WATCHDOG_DEFAULT_ADDRESS = "127.0.0.1:58384"
 
 
-# Old configs:
-class SettingsV1(TypedDict):
-    config_version: Literal[1]
-    autostart: bool
-    flatpak_name: str
-    port: int
-    api_key: str
-    basic_auth_user: str
-    basic_auth_pass: str
-    keep_running_on_desktop: bool
+# Version of the settings the plugin works with. The watchdog migrates older settings files to it on start, see
+# `backend/decky-syncthing-watchdog/src/settings.rs`.
+SETTINGS_VERSION = 2
+# How long to wait for the watchdog to migrate old settings.
+SETTINGS_MIGRATION_TIMEOUT = 10
 
 
 # See `backend/decky-syncthing-watchdog/src/settings.rs` for details.
 class SettingsV2(TypedDict):
     config_version: Literal[2]
-    mode: Union[Literal["systemd"], Literal["systemd_system"], Literal["flatpak"]]
+    mode: Union[Literal["systemd"], Literal["systemd_system"], Literal["flatpak"], Literal["remote"]]
     # Mode: systemd OR systemd_system
     service_name: str
     # Mode: flatpak
@@ -58,6 +59,53 @@ class SettingsV2(TypedDict):
     # in a Flatpak settings directory, even if the `flatpak` mode is not enabled. The value
     # is the name of the Flatpak
     _wizard_force_flatpak_config_for: NotRequired[Optional[str]]
+    # Address the watchdog listens on (loopback only). Default: 127.0.0.1:58384
+    listen_address: NotRequired[Optional[str]]
+    # Unix domain socket the watchdog additionally serves its control API on.
+    control_socket: NotRequired[Optional[str]]
+    # Log level of the watchdog: error, warn, info, debug, trace or off. Default: info
+    log_level: NotRequired[Optional[str]]
+    # Format of the watchdog log files: text or json (one JSON object per line). Default: text
+    log_format: NotRequired[Optional[str]]
+    # Additional Syncthing instances, each a dict with `name`, `mode`, `port`, `autostart` and the other settings
+    # of an instance above (plus `home` in Flatpak mode). Managed by the watchdog only, the wizard sets up the
+    # default instance.
+    instances: NotRequired[list[dict]]
+    # Where the watchdog keeps `api_key`, `basic_auth_user` and `basic_auth_pass`: settings (this file), file (a
+    # credentials file next to it) or secret_service. Default: settings. Credentials written to this file are moved
+    # into the store by the watchdog, the file then only contains empty values for them.
+    credentials_store: NotRequired[Optional[str]]
+    # Host (name or IP address) the Syncthing GUI listens on, detected by the wizard. Default: 127.0.0.1
+    host: NotRequired[Optional[str]]
+    # Whether the Syncthing GUI uses HTTPS, detected by the wizard. Syncthing's config.xml takes precedence.
+    tls: NotRequired[Optional[bool]]
+    # Mode: remote - URL of the GUI of Syncthing on another machine, e.g. https://nas.local:8384/
+    remote_url: NotRequired[Optional[str]]
+    # Pin the certificate of a Syncthing that is not on this machine by its SHA-256 fingerprint or by the Syncthing
+    # device ID. Otherwise it must be signed by a system root CA.
+    tls_cert_sha256: NotRequired[Optional[str]]
+    tls_device_id: NotRequired[Optional[str]]
+    # Whether the watchdog's proxy removes API keys sent by clients, so only `api_key` is used. Default: False
+    strip_client_api_key: NotRequired[Optional[bool]]
+    # Which requests the watchdog's proxy forwards to Syncthing: {"read_only": bool, "allow": ["GET /rest/*", ...]}.
+    # Default: all
+    proxy_policy: NotRequired[Optional[dict]]
+    # Origins allowed to make cross-origin requests to the watchdog. Default: the Steam client and the Decky loader
+    cors_origins: NotRequired[Optional[list[str]]]
+    # Hosts the watchdog may be requested with besides 127.0.0.1, localhost and [::1]. Default: none
+    allowed_hosts: NotRequired[Optional[list[str]]]
+
+
+# Settings that may be missing from the settings file.
+OPTIONAL_SETTINGS = (
+    "_wizard_force_flatpak_config_for", "listen_address", "control_socket", "log_level", "log_format", "instances",
+    "credentials_store", "host", "tls", "remote_url", "tls_cert_sha256", "tls_device_id",
+    "strip_client_api_key", "proxy_policy", "cors_origins", "allowed_hosts"
+)
+# Settings the frontend does not get to see: The watchdog's proxy adds the API key to requests to Syncthing.
+# Shown as `REDACTED` (if set), setting them to it keeps their value.
+HIDDEN_SETTINGS = ("api_key",)
+REDACTED = "<redacted>"
 
 
 # noinspection PyAttributeOutsideInit
@@ -65,17 +113,38 @@ class Plugin:
     settings: SettingsV2
 
     async def get_settings_json(self) -> str:
-        return json.dumps(self.settings)
+        self._reread_settings()
+        return json.dumps(redact(await with_stored_credentials(self.settings)))
+
+    async def get_watchdog_url(self) -> str:
+        address = self.settings.get("listen_address") or WATCHDOG_DEFAULT_ADDRESS
+        try:
+            with open(WATCHDOG_ENDPOINT_PATH, "rb") as f:
+                address = json.load(f)["http"]
+        except Exception as ex:
+            logger.warning(f"Failed reading watchdog endpoint file, using {address}. Exception: {ex}")
+        return f"http://{address}/"
+
+    async def get_watchdog_token(self) -> str:
+        try:
+            with open(WATCHDOG_TOKEN_PATH, "r") as f:
+                return f.read().strip()
+        except Exception as ex:
+            logger.warning(f"Failed reading watchdog token file. Exception: {ex}")
+            return ""
 
     async def restart_watchdog(self):
         await reset_all_processes()
         start_watchdog()
 
     async def set_setting(self, setting: str, value: any):
+        self._reread_settings()
         # TODO: Could do this nicer with some typing magic.
-        if setting not in self.settings and setting != "_wizard_force_flatpak_config_for":
+        if setting not in self.settings and setting not in OPTIONAL_SETTINGS:
             logger.error(f"Unknown setting: {setting}")
             raise KeyError(f"Unknown setting: {setting}")
+        if setting in HIDDEN_SETTINGS and value == REDACTED:
+            return
         # Sometimes the frontend lib doesn't properly convert the data types, make sure it's correct
         if setting == "port":
             value = int(value)
@@ -84,10 +153,23 @@ class Plugin:
         if value == "false":
             value = False
         self.settings[setting] = value  # type: ignore
-        with open(SETTINGS_PATH, "w") as f:
-            json.dump(self.settings, f)
+        save_settings(self.settings)
         logger.info("Updated settings.")
 
+    def _reread_settings(self):
+        """
+        The watchdog can change the settings file as well (via its settings API), so it is re-read before the settings
+        are used. Keeps the loaded settings if the file can not be read.
+        """
+        try:
+            with open(SETTINGS_PATH, "rb") as f:
+                settings = json.load(f)
+        except Exception as ex:
+            logger.warning(f"Failed re-reading config, keeping loaded settings. Exception: {ex}")
+            return
+        if settings.get("config_version") == self.settings["config_version"]:
+            self.settings = settings
+
     async def _main(self):
         logger.info("Loaded.")
         self.log_file = None
@@ -112,27 +194,75 @@ def start_watchdog():
     and acts as a HTTP proxy server for the web UI
     This watchdog itself is NOT stopped by this plugin, as it's lightweight enough to just keep running.
     Also note, we are always trying to start the watchdog when loading the plugin. The watcher will self-terminate
-    if it finds out it's already running (it checks the PID file and sees if that process is alive).
+    if it finds out it's already running (the running watchdog holds a lock on the PID file).
     """
     logger.info("Watchdog starting...")
-    env = dict(os.environ)
-    patch_env(env)
-    # On the real Deck the session bus variable may not be set. This is not bulletproof, but what can you do:
-    if "DBUS_SESSION_BUS_ADDRESS" not in env:
-        env["DBUS_SESSION_BUS_ADDRESS"] = f"unix:path=/run/user/{os.getuid()}/bus"
     subprocess.Popen(
         [
             WATCHDOG_BIN_PATH,
+            "run",
+            "--settings",
             SETTINGS_PATH,
+            "--pid-file",
             WATCHDOG_PID_PATH,
+            "--log-dir",
             DECKY_PLUGIN_LOG_DIR,
         ],
-        env=env,
+        env=watchdog_env(),
         # do not attach to this process.
         start_new_session=True,
     )
 
 
+def watchdog_env() -> dict:
+    env = dict(os.environ)
+    patch_env(env)
+    # On the real Deck the session bus variable may not be set. This is not bulletproof, but what can you do:
+    if "DBUS_SESSION_BUS_ADDRESS" not in env:
+        env["DBUS_SESSION_BUS_ADDRESS"] = f"unix:path=/run/user/{os.getuid()}/bus"
+    return env
+
+
+async def with_stored_credentials(settings: SettingsV2) -> SettingsV2:
+    """
+    Returns the settings with the credentials filled in that the watchdog keeps in a credentials store instead of the
+    settings file (see `credentials_store`). Credentials set in the settings file take precedence, they were not moved
+    into the store yet.
+    """
+    if not settings.get("credentials_store"):
+        return settings
+    try:
+        process = await asyncio.create_subprocess_exec(
+            WATCHDOG_BIN_PATH, "credentials", "--settings", SETTINGS_PATH,
+            env=watchdog_env(), stdout=subprocess.PIPE, stderr=subprocess.PIPE,
+        )
+        stdout, stderr = await process.communicate()
+        if process.returncode != 0:
+            raise RuntimeError(stderr.decode().strip())
+        credentials = json.loads(stdout).get("default", {})
+    except Exception as ex:
+        logger.warning(f"Failed reading stored credentials. Exception: {ex}")
+        return settings
+    settings = dict(settings)
+    for key, value in credentials.items():
+        if not settings.get(key):
+            settings[key] = value
+    return settings  # type: ignore
+
+
+def redact(settings: dict) -> dict:
+    """
+    Returns the settings with the values of `HIDDEN_SETTINGS` replaced by `REDACTED`, also for each of the `instances`.
+    """
+    settings = dict(settings)
+    for key in HIDDEN_SETTINGS:
+        if settings.get(key):
+            settings[key] = REDACTED
+    if "instances" in settings:
+        settings["instances"] = [redact(instance) for instance in settings["instances"]]
+    return settings
+
+
 async def load_settings() -> SettingsV2:
     if os.path.exists(SETTINGS_PATH):
         try:
@@ -146,16 +276,32 @@ async def load_settings() -> SettingsV2:
         if "config_version" not in settings:
             logger.error(f"Unsupported settings version, using default.")
             return default_settings(save=True)
-        if settings["config_version"] == 1:
-            logger.error(f"Running settings migration to V2.")
-            return await migrate_settings_v2(settings)
+        if settings["config_version"] < SETTINGS_VERSION:
+            logger.info(f"Settings of version {settings['config_version']}, letting the watchdog migrate them.")
+            # The processes started by older versions need to be stopped first.
+            await reset_all_processes()
+            start_watchdog()
+            return await read_migrated_settings()
         return settings
     return default_settings(save=True)
 
 
+def save_settings(settings: SettingsV2):
+    """
+    Writes the settings to a temporary file first and then moves it into place, so that the watchdog (which reloads
+    the settings whenever the file changes) never reads a partially written file. The file is only readable by the
+    user, as it may contain credentials.
+    """
+    tmp_path = SETTINGS_PATH.with_suffix(".json.tmp")
+    with open(os.open(tmp_path, os.O_WRONLY | os.O_CREAT | os.O_TRUNC, 0o600), "w") as f:
+        os.fchmod(f.fileno(), 0o600)
+        json.dump(settings, f)
+    os.replace(tmp_path, SETTINGS_PATH)
+
+
 def default_settings(*, save=False) -> SettingsV2:
     defaults = SettingsV2(
-        config_version=2,
+        config_version=SETTINGS_VERSION,
         mode="systemd",
         service_name="",
         flatpak_name="",
@@ -169,31 +315,28 @@ def default_settings(*, save=False) -> SettingsV2:
         is_setup=False,
     )
     if save:
-        with open(SETTINGS_PATH, "w") as f:
-            json.dump(defaults, f)
+        save_settings(defaults)
     return defaults
 
 
-async def migrate_settings_v2(old: SettingsV1) -> SettingsV2:
-    settings = SettingsV2(
-        config_version=2,
-        mode="flatpak",
-        service_name="",
-        flatpak_name=old["flatpak_name"],
-        flatpak_binary="syncthing",
-        autostart="gamescope" if old["autostart"] else "no",
-        keep_running_on_desktop=old["keep_running_on_desktop"],
-        port=old["port"],
-        api_key=old["api_key"],
-        basic_auth_user=old["basic_auth_user"],
-        basic_auth_pass=old["basic_auth_pass"],
-        is_setup="migratingV2"
-    )
-
-    await reset_all_processes()
-    with open(SETTINGS_PATH, "w") as f:
-        json.dump(settings, f)
-    return settings
+async def read_migrated_settings() -> SettingsV2:
+    """
+    Waits for the watchdog to migrate the settings file and reads it. Falls back to the (unsaved) defaults if it does
+    not, the settings are then re-read once they are migrated.
+    """
+    for _ in range(SETTINGS_MIGRATION_TIMEOUT * 2):
+        await asyncio.sleep(0.5)
+        try:
+            with open(SETTINGS_PATH, "rb") as f:
+                settings = json.load(f)
+        except Exception as ex:
+            logger.warning(f"Failed reading config while waiting for its migration. Exception: {ex}")
+            continue
+        if settings.get("config_version") == SETTINGS_VERSION:
+            logger.info("Watchdog migrated the settings.")
+            return settings
+    logger.error("Watchdog did not migrate the settings, using default.")
+    return default_settings()
 
 
 async def reset_all_processes():
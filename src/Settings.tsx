@@ -1,5 +1,5 @@
 export interface Settings {
-    mode: "systemd" | "systemd_system" | "flatpak";
+    mode: "systemd" | "systemd_system" | "flatpak" | "remote";
     // Mode: systemd OR systemd_service
     service_name: string;
     // Mode: flatpak
@@ -9,8 +9,24 @@ export interface Settings {
     autostart: "no" | "boot" | "gamescope";
     keep_running_on_desktop: boolean;
     port: number;
+    // "<redacted>" if set, the watchdog adds it to requests to Syncthing.
     api_key: string;
     basic_auth_user: string;
     basic_auth_pass: string;
     is_setup: boolean | "migratingV2";
+    listen_address?: string | null;
+    control_socket?: string | null;
+    log_level?: "error" | "warn" | "info" | "debug" | "trace" | "off" | null;
+    log_format?: "text" | "json" | null;
+    credentials_store?: "settings" | "file" | "secret_service" | null;
+    host?: string | null;
+    tls?: boolean | null;
+    // Mode: remote
+    remote_url?: string | null;
+    tls_cert_sha256?: string | null;
+    tls_device_id?: string | null;
+    strip_client_api_key?: boolean | null;
+    proxy_policy?: {read_only?: boolean; allow?: string[] | null} | null;
+    cors_origins?: string[] | null;
+    allowed_hosts?: string[] | null;
 }
@@ -293,10 +293,11 @@ export interface StVersion {
 
 export class SyncthingApi {
     private readonly baseUrl: string;
-    private readonly apiKey: string;
 
-    constructor(apiKey: string, baseUrl: string = WATCHDOG_PROXY_URL) {
-        this.apiKey = apiKey;
+    /**
+     * The watchdog's proxy at `baseUrl` adds the API key to the requests.
+     */
+    constructor(baseUrl: string = WATCHDOG_PROXY_URL) {
         this.baseUrl = baseUrl;
     }
 
@@ -377,9 +378,6 @@ export class SyncthingApi {
             method: 'GET',
             headers: {
                 'Accept': 'application/json',
-                'Authorization': `Bearer ${this.apiKey}`,
-                // who knows why we have to set both...
-                'X-API-Key': `${this.apiKey}`
             },
             ...(init ?? {})
         });
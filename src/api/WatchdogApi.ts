@@ -4,11 +4,19 @@ import {
     WATCHDOG_CHECK_SCAN_BASIC_AUTH_ROUTE,
     WATCHDOG_CHECK_SCAN_PORT_ROUTE,
     WATCHDOG_CHECK_START_ROUTE,
+    WATCHDOG_CRASHES_ROUTE,
+    WATCHDOG_HEALTH_ROUTE,
+    WATCHDOG_INSTANCES_ROUTE,
     WATCHDOG_PROXY_URL,
     WATCHDOG_RELOAD_CONFIG_ROUTE,
+    WATCHDOG_SETTINGS_ROUTE,
+    WATCHDOG_SETTINGS_VALIDATE_ROUTE,
     WATCHDOG_START_ROUTE,
     WATCHDOG_STATE_ROUTE,
-    WATCHDOG_STOP_ROUTE
+    WATCHDOG_STOP_ROUTE,
+    WATCHDOG_TOKEN,
+    WATCHDOG_TOKEN_HEADER,
+    reloadWatchdogToken
 } from "../consts";
 import {sleep} from "decky-frontend-lib";
 
@@ -24,31 +32,104 @@ export interface CheckStart {
 
 export interface CheckScanPort {
     port?: number;
+    host?: string;
+    tls?: boolean;
 }
 
 export interface CheckScanApikey {
-    api_key?: string;
+    found: boolean;
+    valid: boolean;
 }
 
 export interface CheckScanBasicAuth {
     basic_auth_user?: string;
 }
 
+export interface CrashSummary {
+    id: string;
+    // Unix timestamp
+    timestamp: number;
+    version: string;
+    message: string;
+}
+
+export interface CrashReport extends CrashSummary {
+    git_revision: string;
+    uptime_secs: number;
+    location: string | null;
+    thread: string | null;
+    backtrace: string;
+    // The settings at the time of the crash, without credentials.
+    settings: any;
+    log: string[];
+}
+
+export interface ValidationIssue {
+    // Name of the setting, empty if the settings as a whole are broken.
+    field: string;
+    severity: "error" | "warning";
+    message: string;
+    hint: string;
+}
+
+export interface ValidationReport {
+    valid: boolean;
+    issues: ValidationIssue[];
+}
+
+export interface InstanceInfo {
+    name: string;
+    mode: "systemd" | "systemd_system" | "flatpak" | "remote";
+    port: number;
+    autostart: "no" | "boot" | "gamescope";
+    // null if the state could not be determined.
+    state: SyncthingProcessState | null;
+}
+
 export class WatchdogApi {
     private readonly baseUrl: string;
+    private readonly instance?: string;
 
-    constructor(baseUrl: string = WATCHDOG_PROXY_URL) {
+    /**
+     * @param instance Name of the Syncthing instance to control with `getState`, `start` and `stop`. Defaults to
+     *                 the default instance.
+     */
+    constructor(baseUrl: string = WATCHDOG_PROXY_URL, instance?: string) {
         this.baseUrl = baseUrl;
+        this.instance = instance;
+    }
+
+    /**
+     * Sends a request to the watchdog with its token. If the token is rejected, the watchdog may have been restarted
+     * with a new one: It is reloaded and the request is sent again.
+     */
+    private async fetch(url: string, init?: RequestInit): Promise<Response> {
+        const send = () => fetch(url, {
+            ...(init ?? {}),
+            headers: {...(init?.headers ?? {}), [WATCHDOG_TOKEN_HEADER]: WATCHDOG_TOKEN},
+        });
+        const result = await send();
+        if (result.status == 401 && await reloadWatchdogToken()) {
+            return await send();
+        }
+        return result;
+    }
+
+    private instanceRoute(route: string): string {
+        if (this.instance === undefined) {
+            return route;
+        }
+        return route.replace("__decky-watchdog/", `${WATCHDOG_INSTANCES_ROUTE}/${encodeURIComponent(this.instance)}/`);
     }
 
     async getState(): Promise<SyncthingProcessState> {
         let result;
         try {
-             result = await fetch(`${this.baseUrl}${WATCHDOG_STATE_ROUTE}`);
+             result = await this.fetch(`${this.baseUrl}${this.instanceRoute(WATCHDOG_STATE_ROUTE)}`);
         } catch (_) {
             // we retry fetching the state once, because the watchdog may still be starting.
             await sleep(1000);
-            result = await fetch(`${this.baseUrl}${WATCHDOG_STATE_ROUTE}`);
+            result = await this.fetch(`${this.baseUrl}${this.instanceRoute(WATCHDOG_STATE_ROUTE)}`);
         }
         if (result.ok) {
             let text = (await result.text()).trim();
@@ -67,28 +148,68 @@ export class WatchdogApi {
     }
 
     async reloadSettings(): Promise<void> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_RELOAD_CONFIG_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_RELOAD_CONFIG_ROUTE}`,  {method: "POST"});
         if (!result.ok) {
             throw new Error(`Settings reload request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
     }
 
+    /**
+     * Returns the settings the watchdog is using. Credentials are replaced by `<redacted>`.
+     */
+    async getSettings(): Promise<any> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_SETTINGS_ROUTE}`);
+        if (!result.ok) {
+            throw new Error(`Settings request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
+    /**
+     * Changes the given settings, saves and applies them. `null` unsets a setting. Returns the new settings, or the
+     * validation report if the settings are invalid.
+     */
+    async patchSettings(patch: object): Promise<any | ValidationReport> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_SETTINGS_ROUTE}`, {
+            method: "PATCH",
+            body: JSON.stringify(patch),
+        });
+        if (!result.ok && result.status != 422) {
+            throw new Error(`Settings update request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
+    /**
+     * Validates the given settings, or the saved settings if none are given.
+     */
+    async validateSettings(settings?: object): Promise<ValidationReport> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_SETTINGS_VALIDATE_ROUTE}`, {
+            method: "POST",
+            body: settings === undefined ? undefined : JSON.stringify(settings),
+        });
+        if (!result.ok) {
+            throw new Error(`Validate request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
     async start(): Promise<void> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_START_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${this.instanceRoute(WATCHDOG_START_ROUTE)}`,  {method: "POST"});
         if (!result.ok) {
             throw new Error(`Start request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
     }
 
     async stop(): Promise<void> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_STOP_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${this.instanceRoute(WATCHDOG_STOP_ROUTE)}`,  {method: "POST"});
         if (!result.ok) {
             throw new Error(`Stop request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
     }
 
     async checkStart(): Promise<CheckStart | CheckError> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_CHECK_START_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CHECK_START_ROUTE}`,  {method: "POST"});
          if (!result.ok && result.status != 500) {
             throw new Error(`Request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
@@ -96,7 +217,7 @@ export class WatchdogApi {
     }
 
     async checkScanPort(): Promise<CheckScanPort | CheckError> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_PORT_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_PORT_ROUTE}`,  {method: "POST"});
         if (!result.ok && result.status != 500) {
             throw new Error(`Request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
@@ -104,7 +225,7 @@ export class WatchdogApi {
     }
 
     async checkScanApikey(): Promise<CheckScanApikey | CheckError> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_API_KEY_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_API_KEY_ROUTE}`,  {method: "POST"});
         if (!result.ok && result.status != 500) {
             throw new Error(`Request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
@@ -112,20 +233,63 @@ export class WatchdogApi {
     }
 
     async checkScanBasicAuth(): Promise<CheckScanBasicAuth | CheckError> {
-        let result = await fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_BASIC_AUTH_ROUTE}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CHECK_SCAN_BASIC_AUTH_ROUTE}`,  {method: "POST"});
          if (!result.ok && result.status != 500) {
             throw new Error(`Request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         }
         return await result.json()
     }
 
+    /**
+     * Lists the Syncthing instances, starting with the default one.
+     */
+    async getInstances(): Promise<InstanceInfo[]> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_INSTANCES_ROUTE}`);
+        if (!result.ok) {
+            throw new Error(`Instances request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
+    /**
+     * Returns the problems with the protection of the Syncthing credentials, e.g. a settings file that was readable
+     * by other users or a credentials store that is not available.
+     */
+    async getWarnings(): Promise<string[]> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_HEALTH_ROUTE}`);
+        // 503 if a subsystem of the watchdog is not running, the response is the same.
+        if (!result.ok && result.status != 503) {
+            throw new Error(`Health request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return (await result.json()).warnings ?? [];
+    }
+
+    /**
+     * Lists the crash reports of the watchdog, newest first.
+     */
+    async getCrashes(): Promise<CrashSummary[]> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CRASHES_ROUTE}`);
+        if (!result.ok) {
+            throw new Error(`Crashes request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
+    async getCrash(id: string): Promise<CrashReport> {
+        let result = await this.fetch(`${this.baseUrl}${WATCHDOG_CRASHES_ROUTE}/${encodeURIComponent(id)}`);
+        if (!result.ok) {
+            throw new Error(`Crash request failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
+        }
+        return await result.json()
+    }
+
     /**
      * Checks if Syncthing is up, by calling the / of the watchdog proxy. This will return a HTTP `425 Too Early` if
      * the Syncthing API is not up yet. Then it will return false. If a status code >= 500 is returned or the request
      * fails, an error is thrown. Otherwise, true is returned.
      */
     async checkIfUp(): Promise<boolean> {
-        let result = await fetch(`${this.baseUrl}`,  {method: "POST"});
+        let result = await this.fetch(`${this.baseUrl}`,  {method: "POST"});
         if (result.status >= 500) {
             throw new Error(`Check failed. Status: ${result.status} ${result.statusText}. Response: ${await result.text()}`);
         } else if (result.status == 425) {
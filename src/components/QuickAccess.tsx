@@ -29,6 +29,7 @@ export const QuickAccess: FC<{ serverApi: ServerAPI }> = ({serverApi}) => {
     const [settings, setSettings] = useState<Settings | null>(null);
     const [loading, setLoading] = useState(false);
     const [error, setError] = useState<string | null>(null);
+    const [warnings, setWarnings] = useState<string[]>([]);
 
     const reloadState = async (updateLoading = true) => {
         console.info(`Decky Syncthing: loading context.`);
@@ -42,6 +43,10 @@ export const QuickAccess: FC<{ serverApi: ServerAPI }> = ({serverApi}) => {
             const newState = await watchdogApi.getState();
             console.info(`Decky Syncthing: loaded state: ${newState}.`);
             setState(newState);
+            setWarnings(await watchdogApi.getWarnings().catch((err) => {
+                console.error(`Decky Syncthing: failed loading warnings: ${err}`);
+                return [];
+            }));
             const settingsResult = await serverApi.callPluginMethod<{}, string>(PLUGIN_API_GET_SETTINGS_JSON, {});
             if (settingsResult.success) {
                 console.info(`Decky Syncthing: loaded settings.`);
@@ -119,7 +124,7 @@ export const QuickAccess: FC<{ serverApi: ServerAPI }> = ({serverApi}) => {
 
     let api: SyncthingApi | null = null;
     if (settings != null) {
-        api = new SyncthingApi(settings.api_key);
+        api = new SyncthingApi();
 
         if (settings.is_setup !== true) {
             return (<>
@@ -190,6 +195,17 @@ export const QuickAccess: FC<{ serverApi: ServerAPI }> = ({serverApi}) => {
                     </PanelSection>
                 </>
             )}
+            {warnings.length > 0 && (
+                <>
+                    <PanelSection title="Warning">
+                        {warnings.map((warning) => (
+                            <PanelSectionRow key={warning}>
+                                {warning}
+                            </PanelSectionRow>
+                        ))}
+                    </PanelSection>
+                </>
+            )}
             {state == SyncthingProcessState.Running && api != null && (
                 <>
                     <PanelSection title="Folders">
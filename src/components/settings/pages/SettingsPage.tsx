@@ -94,6 +94,18 @@ export const SettingsPage: FC<SettingsPageProps> = ({serverApi}) => {
                              onChange={onChange}/>
                 </DialogControlsSection>
             );
+        } else if (settings?.mode === "remote") {
+            modeSettings = (
+                <DialogControlsSection>
+                    <DialogControlsSectionHeader>Remote Settings</DialogControlsSectionHeader>
+                    <Setting type="str" label="Syncthing URL" setting="remote_url" value={settings?.remote_url ?? ""}
+                             onChange={onChange}
+                             description="URL of the web UI of Syncthing on another machine, e.g. https://nas.local:8384/"/>
+                    <Setting type="str" label="Device ID" setting="tls_device_id" value={settings?.tls_device_id ?? ""}
+                             onChange={onChange}
+                             description="Only needed if the remote Syncthing uses its own (self-signed) certificate."/>
+                </DialogControlsSection>
+            );
         } else {
             modeSettings = (
                 <DialogControlsSection>
@@ -110,7 +122,7 @@ export const SettingsPage: FC<SettingsPageProps> = ({serverApi}) => {
             <DialogBody>
                 <DialogControlsSection>
                     <DialogControlsSectionHeader>Mode</DialogControlsSectionHeader>
-                    <SettingDropdown label="Launch Mode" options={{"flatpak": "Flatpak", "systemd": "Existing Systemd user service", "systemd_system": "Existing Systemd system service"}}
+                    <SettingDropdown label="Launch Mode" options={{"flatpak": "Flatpak", "systemd": "Existing Systemd user service", "systemd_system": "Existing Systemd system service", "remote": "Syncthing on another machine"}}
                                      setting="mode" value={settings?.mode} onChange={onChange}/>
                 </DialogControlsSection>
                 {modeSettings}
@@ -119,7 +131,8 @@ export const SettingsPage: FC<SettingsPageProps> = ({serverApi}) => {
                     <Setting type="int" label="Syncthing Port" setting="port" value={settings?.port}
                              onChange={onChange}/>
                     <Setting type="str" label="API Key" setting="api_key" value={settings?.api_key}
-                             onChange={onChange}/>
+                             onChange={onChange}
+                             description="Once set, the key is not shown here anymore. Enter a new key to replace it."/>
                     <Setting type="str" label="Basic Auth Username" setting="basic_auth_user" value={settings?.basic_auth_user}
                              onChange={onChange}
                              description="This and the password are needed if you want to enter the web UI and have it password-protected."/>
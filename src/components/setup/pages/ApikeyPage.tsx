@@ -39,8 +39,9 @@ const ApikeyPage: FunctionComponent<SetupPageProps> = ({nextPage, backPage, serv
                 let response = await watchdogApi.checkScanApikey();
                 if ("error" in response) {
                     setError({"error": response.error});
-                } else if (response.api_key != null) {
-                    setAndContinue(response.api_key);
+                } else if (response.found && response.valid) {
+                    // The watchdog saved the API key.
+                    nextPage({});
                 } else {
                     setError(true);
                 }
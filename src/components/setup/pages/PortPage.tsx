@@ -16,11 +16,23 @@ const PortPage: FunctionComponent<SetupPageProps> = ({nextPage, backPage, server
     const [settings, setSettings] = useState<Settings | null>(null);
     setBackNavAllowed(false);
 
-    const setAndContinue = async (port: number) => {
+    const setAndContinue = async (port: number, host?: string, tls?: boolean) => {
         await serverApi.callPluginMethod<SetSettingParams, void>(PLUGIN_API_SET_SETTING, {
             setting: "port",
             value: port,
         })
+        if (host != null) {
+            await serverApi.callPluginMethod<SetSettingParams, void>(PLUGIN_API_SET_SETTING, {
+                setting: "host",
+                value: host,
+            })
+        }
+        if (tls != null) {
+            await serverApi.callPluginMethod<SetSettingParams, void>(PLUGIN_API_SET_SETTING, {
+                setting: "tls",
+                value: tls,
+            })
+        }
         // Progress to next page
         nextPage({});
     };
@@ -40,7 +52,7 @@ const PortPage: FunctionComponent<SetupPageProps> = ({nextPage, backPage, server
                 if ("error" in response) {
                     setError({"error": response.error});
                 } else if (response.port != null) {
-                    setAndContinue(response.port);
+                    setAndContinue(response.port, response.host, response.tls);
                 } else {
                     setError(true);
                 }
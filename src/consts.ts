@@ -1,4 +1,5 @@
-export const WATCHDOG_PROXY_URL = "http://127.0.0.1:58384/";
+// Updated with the address the watchdog actually listens on, see `setWatchdogProxyUrl`.
+export let WATCHDOG_PROXY_URL = "http://127.0.0.1:58384/";
 export const WATCHDOG_STATE_ROUTE = "__decky-watchdog/state";
 export const WATCHDOG_RELOAD_CONFIG_ROUTE = "__decky-watchdog/reload-config";
 export const WATCHDOG_START_ROUTE = "__decky-watchdog/start";
@@ -7,7 +8,18 @@ export const WATCHDOG_CHECK_START_ROUTE = "__decky-watchdog/check/start";
 export const WATCHDOG_CHECK_SCAN_PORT_ROUTE = "__decky-watchdog/check/scan_port";
 export const WATCHDOG_CHECK_SCAN_API_KEY_ROUTE = "__decky-watchdog/check/scan_api_key";
 export const WATCHDOG_CHECK_SCAN_BASIC_AUTH_ROUTE = "__decky-watchdog/check/scan_basic_auth";
+export const WATCHDOG_CRASHES_ROUTE = "__decky-watchdog/crashes";
+export const WATCHDOG_SETTINGS_ROUTE = "__decky-watchdog/settings";
+export const WATCHDOG_SETTINGS_VALIDATE_ROUTE = "__decky-watchdog/settings/validate";
+export const WATCHDOG_INSTANCES_ROUTE = "__decky-watchdog/instances";
+export const WATCHDOG_HEALTH_ROUTE = "__decky-watchdog/health";
+// The web UI of an instance other than the default one is proxied at `<WATCHDOG_PROXY_URL>__decky-instance/<name>/`.
+export const WATCHDOG_INSTANCE_PROXY_PREFIX = "__decky-instance/";
 export const PLUGIN_API_RESTART_WATCHDOG = "restart_watchdog";
+export const PLUGIN_API_GET_WATCHDOG_URL = "get_watchdog_url";
+export const PLUGIN_API_GET_WATCHDOG_TOKEN = "get_watchdog_token";
+// Header with the token for the watchdog's `__decky-watchdog/` routes.
+export const WATCHDOG_TOKEN_HEADER = "X-Decky-Watchdog-Token";
 export const PLUGIN_API_GET_SETTINGS_JSON = "get_settings_json";
 export const PLUGIN_API_SET_SETTING = "set_setting";
 
@@ -18,3 +30,28 @@ export enum SyncthingProcessState {
     Failed = "failed",
     Unknown = "unknown"
 }
+
+export function setWatchdogProxyUrl(url: string) {
+    WATCHDOG_PROXY_URL = url;
+}
+
+// Token for the watchdog's `__decky-watchdog/` routes. It changes whenever the watchdog restarts, see
+// `reloadWatchdogToken`.
+export let WATCHDOG_TOKEN = "";
+let watchdogTokenLoader: (() => Promise<string | null>) | null = null;
+
+export function setWatchdogTokenLoader(loader: () => Promise<string | null>) {
+    watchdogTokenLoader = loader;
+}
+
+/**
+ * Loads the current token of the watchdog. Returns whether it changed.
+ */
+export async function reloadWatchdogToken(): Promise<boolean> {
+    const token = watchdogTokenLoader != null ? await watchdogTokenLoader() : null;
+    if (token == null || token == WATCHDOG_TOKEN) {
+        return false;
+    }
+    WATCHDOG_TOKEN = token;
+    return true;
+}
@@ -7,9 +7,33 @@ import {SettingsRouter} from "./components/settings/SettingsRouter";
 import {QuickAccess} from "./components/QuickAccess";
 import {SyncthingIcon} from "./components/SyncthingIcon";
 import {SetupRouter} from "./components/setup/SetupRouter";
+import {
+    PLUGIN_API_GET_WATCHDOG_TOKEN,
+    PLUGIN_API_GET_WATCHDOG_URL,
+    reloadWatchdogToken,
+    setWatchdogProxyUrl,
+    setWatchdogTokenLoader
+} from "./consts";
 
 export default definePlugin((serverApi: ServerAPI) => {
     console.info(`Decky Syncthing: loading`);
+    serverApi.callPluginMethod<{}, string>(PLUGIN_API_GET_WATCHDOG_URL, {}).then((result) => {
+        if (result.success) {
+            console.info(`Decky Syncthing: watchdog at ${result.result}`);
+            setWatchdogProxyUrl(result.result);
+        } else {
+            console.error(`Decky Syncthing: failed getting watchdog URL: ${result.result}`);
+        }
+    });
+    setWatchdogTokenLoader(async () => {
+        const result = await serverApi.callPluginMethod<{}, string>(PLUGIN_API_GET_WATCHDOG_TOKEN, {});
+        if (!result.success) {
+            console.error(`Decky Syncthing: failed getting watchdog token: ${result.result}`);
+            return null;
+        }
+        return result.result;
+    });
+    reloadWatchdogToken();
     serverApi.routerHook.addRoute(
         "/decky-syncthing/settings",
         () => (